@@ -0,0 +1,237 @@
+use crate::checkpoint;
+use crate::vec3::Vec3;
+use std::io::{self, Read, Write};
+
+/// Samples every pixel takes before its noise estimate is trusted.
+pub const MIN_SAMPLES: usize = 16;
+
+/// Samples taken between two convergence checks of a pixel.
+pub const BATCH: usize = 8;
+
+/// Most samples a noisy pixel can be given from the budget other pixels
+/// saved, as a multiple of the samples per pixel.
+pub const MAX_FACTOR: usize = 4;
+
+/// Running mean and variance of the luminance of a pixel's samples, using
+/// Welford's update, and the samples the pixel was granted beyond the
+/// samples per pixel.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PixelStats {
+    count: usize,
+    mean: f64,
+    m2: f64,
+    extra: usize,
+}
+
+impl PixelStats {
+    pub fn add(&mut self, value: f32) {
+        self.count += 1;
+        let delta = value as f64 - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (value as f64 - self.mean);
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Lets the pixel take `samples` more than it could so far.
+    pub fn grant(&mut self, samples: usize) {
+        self.extra += samples;
+    }
+
+    pub fn mean(&self) -> f32 {
+        self.mean as f32
+    }
+
+    /// Unbiased sample variance.
+    pub fn variance(&self) -> f32 {
+        if self.count < 2 {
+            return 0.0;
+        }
+        (self.m2 / (self.count - 1) as f64) as f32
+    }
+
+    /// Standard error of the mean relative to the square root of the mean.
+    /// Display encodings roughly take square roots, so this tracks how visible
+    /// the remaining noise is in both dark and bright pixels.
+    pub fn error(&self) -> f32 {
+        let standard_error = (self.variance() / self.count.max(1) as f32).sqrt();
+        standard_error / self.mean().max(1e-4).sqrt()
+    }
+
+    pub fn save(&self, out: &mut impl Write) -> io::Result<()> {
+        checkpoint::write_u64(out, self.count as u64)?;
+        checkpoint::write_f64(out, self.mean)?;
+        checkpoint::write_f64(out, self.m2)?;
+        checkpoint::write_u64(out, self.extra as u64)
+    }
+
+    pub fn load(input: &mut impl Read) -> io::Result<PixelStats> {
+        Ok(PixelStats {
+            count: checkpoint::read_u64(input)? as usize,
+            mean: checkpoint::read_f64(input)?,
+            m2: checkpoint::read_f64(input)?,
+            extra: checkpoint::read_u64(input)? as usize,
+        })
+    }
+
+    /// Whether a pixel that may take up to `max_samples`, and whatever it was
+    /// granted beyond them, should stop sampling for an error `threshold`.
+    /// Only checked at the end of each batch.
+    pub fn converged(&self, threshold: f32, max_samples: usize) -> bool {
+        self.count >= max_samples + self.extra
+            || (self.count >= MIN_SAMPLES
+                && self.count.is_multiple_of(BATCH)
+                && self.error() <= threshold)
+    }
+}
+
+/// Hands the `budget` of samples that finished pixels left unused to those
+/// that ran out of samples while still above the error `threshold`.
+///
+/// Each noisy pixel is estimated to need its count times (error / threshold)²
+/// samples in all, since the error falls with the square root of the count.
+/// When the budget does not cover every need it is split in proportion to
+/// them, in whole batches; if that rounds every share away, the noisiest
+/// pixel gets one batch. No pixel goes beyond `MAX_FACTOR` times
+/// `max_samples`. Returns the grants as indices into `pixels` and samples,
+/// none once the budget or the noisy pixels have run out.
+pub fn allocate(
+    pixels: &[PixelStats],
+    budget: usize,
+    threshold: f32,
+    max_samples: usize,
+) -> Vec<(usize, usize)> {
+    let ceiling = MAX_FACTOR * max_samples;
+    let needs: Vec<(usize, usize)> = pixels
+        .iter()
+        .enumerate()
+        .filter(|(_, stats)| stats.count >= max_samples + stats.extra && stats.error() > threshold)
+        .filter_map(|(i, stats)| {
+            let room = ceiling.saturating_sub(stats.count);
+            let ratio = (stats.error() / threshold).powi(2) as f64;
+            let need = (stats.count as f64 * (ratio - 1.0)).ceil() as usize;
+            let need = need.next_multiple_of(BATCH).min(room);
+            (need > 0).then_some((i, need))
+        })
+        .collect();
+    let total: usize = needs.iter().map(|(_, need)| need).sum();
+    if budget < BATCH || total == 0 {
+        return Vec::new();
+    }
+    if total <= budget {
+        return needs;
+    }
+
+    let grants: Vec<(usize, usize)> = needs
+        .iter()
+        .map(|&(i, need)| {
+            let share = (need as f64 * budget as f64 / total as f64) as usize;
+            (i, (share - share % BATCH).min(need))
+        })
+        .filter(|(_, share)| *share > 0)
+        .collect();
+    if !grants.is_empty() {
+        return grants;
+    }
+    let noisiest =
+        needs
+            .iter()
+            .copied()
+            .reduce(|a, b| match pixels[b.0].error() > pixels[a.0].error() {
+                true => b,
+                false => a,
+            });
+    noisiest
+        .map(|(i, need)| (i, need.min(BATCH)))
+        .into_iter()
+        .collect()
+}
+
+/// Colours sample counts from black (none) through purple, orange and yellow
+/// to white (`max`), as linear display values.
+pub fn heatmap(counts: &[usize], max: usize) -> Vec<Vec3> {
+    const STOPS: [(f32, f32, f32); 5] = [
+        (0.0, 0.0, 0.0),
+        (0.3, 0.0, 0.4),
+        (0.9, 0.2, 0.0),
+        (1.0, 0.8, 0.0),
+        (1.0, 1.0, 1.0),
+    ];
+
+    counts
+        .iter()
+        .map(|&count| {
+            let t = (count as f32 / max.max(1) as f32).clamp(0.0, 1.0) * 4.0;
+            let i = (t as usize).min(3);
+            let f = t - i as f32;
+            let (a, b) = (STOPS[i], STOPS[i + 1]);
+            Vec3::new(
+                a.0 + (b.0 - a.0) * f,
+                a.1 + (b.1 - a.1) * f,
+                a.2 + (b.2 - a.2) * f,
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats() {
+        let mut stats = PixelStats::default();
+        for v in [2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0] {
+            stats.add(v);
+        }
+        assert_eq!(stats.mean(), 5.0);
+        assert!((stats.variance() - 32.0 / 7.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_convergence() {
+        let mut flat = PixelStats::default();
+        let mut noisy = PixelStats::default();
+        for i in 0..MIN_SAMPLES {
+            flat.add(0.5);
+            noisy.add(if i % 2 == 0 { 0.0 } else { 1.0 });
+        }
+        assert!(flat.converged(0.01, 64));
+        assert!(!noisy.converged(0.01, 64));
+        assert!(noisy.converged(0.01, MIN_SAMPLES));
+    }
+
+    #[test]
+    fn test_allocate() {
+        let stats = |values: &[f32]| {
+            let mut stats = PixelStats::default();
+            values.iter().for_each(|&v| stats.add(v));
+            stats
+        };
+        let flat = stats(&[0.5; 16]);
+        let noisy = stats(&[0.0, 1.0].repeat(16));
+        let pixels = [flat, noisy, noisy];
+
+        // The flat pixel saved 16 of its 32 samples.
+        let grants = allocate(&pixels, 16, 0.05, 32);
+        assert_eq!(grants, [(1, 8), (2, 8)]);
+        // Plenty of budget: each gets what it needs, up to the ceiling.
+        assert_eq!(allocate(&pixels, 10000, 0.05, 32), [(1, 96), (2, 96)]);
+        assert!(allocate(&pixels, 4, 0.05, 32).is_empty());
+        assert!(allocate(&[flat], 100, 0.05, 32).is_empty());
+
+        let mut granted = noisy;
+        granted.grant(8);
+        assert!(!granted.converged(0.05, 32));
+        assert!(granted.converged(0.05, 24));
+    }
+
+    #[test]
+    fn test_heatmap_ends() {
+        let colours = heatmap(&[0, 100], 100);
+        assert_eq!(colours[0], Vec3::default());
+        assert_eq!(colours[1], Vec3::new(1.0, 1.0, 1.0));
+    }
+}
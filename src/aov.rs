@@ -0,0 +1,237 @@
+use crate::hittable::HitRecord;
+use crate::material::Material;
+use crate::ray::Ray;
+use crate::sampler;
+use crate::vec3::Vec3;
+use std::str::FromStr;
+
+/// An arbitrary output variable: a buffer recorded from the first surface
+/// each camera ray hits. Pixels whose rays hit nothing are zero.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aov {
+    /// Distance from the ray origin to the hit.
+    Depth,
+    /// Shading normal in world space.
+    Normal,
+    /// Reflectance of the material; white for glass and lights.
+    Albedo,
+    /// Hit point in world space.
+    Position,
+    /// A hash of the material's parameters, equal for equal materials.
+    MaterialId,
+    /// One plus the index of the object in the scene.
+    ObjectId,
+    /// Screen-space motion in pixels. Scenes and cameras do not move yet, so
+    /// it is always zero.
+    Motion,
+}
+
+impl FromStr for Aov {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Aov::ALL
+            .into_iter()
+            .find(|aov| aov.name() == s)
+            .ok_or_else(|| format!("unknown AOV '{}'", s))
+    }
+}
+
+/// Parses a comma-separated list of AOV names, or `all`.
+pub fn parse_list(s: &str) -> Result<Vec<Aov>, String> {
+    if s == "all" {
+        return Ok(Aov::ALL.to_vec());
+    }
+    s.split(',').map(|name| name.trim().parse()).collect()
+}
+
+impl Aov {
+    pub const ALL: [Aov; 7] = [
+        Aov::Depth,
+        Aov::Normal,
+        Aov::Albedo,
+        Aov::Position,
+        Aov::MaterialId,
+        Aov::ObjectId,
+        Aov::Motion,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aov::Depth => "depth",
+            Aov::Normal => "normal",
+            Aov::Albedo => "albedo",
+            Aov::Position => "position",
+            Aov::MaterialId => "material-id",
+            Aov::ObjectId => "object-id",
+            Aov::Motion => "motion",
+        }
+    }
+
+    /// EXR channel names for the x, y and z components that are used.
+    pub fn channels(&self) -> &'static [&'static str] {
+        match self {
+            Aov::Depth => &["Z"],
+            Aov::Normal => &["N.X", "N.Y", "N.Z"],
+            Aov::Albedo => &["albedo.R", "albedo.G", "albedo.B"],
+            Aov::Position => &["P.X", "P.Y", "P.Z"],
+            Aov::MaterialId => &["materialID"],
+            Aov::ObjectId => &["objectID"],
+            Aov::Motion => &["motion.X", "motion.Y"],
+        }
+    }
+
+    /// Whether the values are identifiers. Averaging two identifiers gives a
+    /// third, so these keep the first sample of each pixel instead.
+    pub fn is_id(&self) -> bool {
+        matches!(self, Aov::MaterialId | Aov::ObjectId)
+    }
+
+    /// The value for camera ray `r` hitting `rec`.
+    pub fn value(&self, r: &Ray, rec: &HitRecord) -> Vec3 {
+        match self {
+            Aov::Depth => {
+                let depth = rec.t * r.direction().length();
+                Vec3::new(depth, depth, depth)
+            }
+            Aov::Normal => rec.normal,
+            Aov::Albedo => match rec.material {
+                Material::Lambertian { albedo } | Material::Metal { albedo, .. } => albedo,
+                Material::Dielectric { .. } | Material::DiffuseLight { .. } => {
+                    Vec3::new(1.0, 1.0, 1.0)
+                }
+            },
+            Aov::Position => rec.p,
+            Aov::MaterialId => {
+                let id = material_id(&rec.material) as f32;
+                Vec3::new(id, id, id)
+            }
+            Aov::ObjectId => {
+                let id = (rec.object + 1) as f32;
+                Vec3::new(id, id, id)
+            }
+            Aov::Motion => Vec3::default(),
+        }
+    }
+
+    /// Maps raw values to linear display colours for viewing as an ordinary
+    /// image: depth and position are normalised to their range, directions
+    /// moved from `[-1, 1]` into `[0, 1]` and identifiers given random
+    /// colours.
+    pub fn display(&self, values: &[Vec3]) -> Vec<Vec3> {
+        match self {
+            Aov::Depth => {
+                let max = values.iter().map(|v| v.x()).fold(0.0, f32::max);
+                let scale = if max > 0.0 { 1.0 / max } else { 0.0 };
+                values.iter().map(|&v| v * scale).collect()
+            }
+            Aov::Normal | Aov::Motion => values
+                .iter()
+                .map(|&v| v * 0.5 + Vec3::new(0.5, 0.5, 0.5))
+                .collect(),
+            Aov::Albedo => values.to_vec(),
+            Aov::Position => {
+                let (mut min, mut max) = ([f32::MAX; 3], [f32::MIN; 3]);
+                for v in values {
+                    for (i, c) in [v.x(), v.y(), v.z()].into_iter().enumerate() {
+                        min[i] = min[i].min(c);
+                        max[i] = max[i].max(c);
+                    }
+                }
+                let normalise = |c: f32, i: usize| (c - min[i]) / (max[i] - min[i]).max(1e-6);
+                values
+                    .iter()
+                    .map(|v| {
+                        Vec3::new(
+                            normalise(v.x(), 0),
+                            normalise(v.y(), 1),
+                            normalise(v.z(), 2),
+                        )
+                    })
+                    .collect()
+            }
+            Aov::MaterialId | Aov::ObjectId => values
+                .iter()
+                .map(|v| {
+                    if v.x() == 0.0 {
+                        return Vec3::default();
+                    }
+                    let h = sampler::hash(&[v.x().to_bits() as u64]);
+                    let channel = |shift: u32| ((h >> shift) & 0xff) as f32 / 255.0;
+                    Vec3::new(channel(0), channel(8), channel(16))
+                })
+                .collect(),
+        }
+    }
+}
+
+// 24 bits so the id survives as an f32, and never zero, which means a miss.
+fn material_id(material: &Material) -> u32 {
+    let values: Vec<u64> = match *material {
+        Material::Lambertian { albedo } => {
+            vec![
+                0,
+                albedo.x().to_bits() as u64,
+                albedo.y().to_bits() as u64,
+                albedo.z().to_bits() as u64,
+            ]
+        }
+        Material::Metal { albedo, fuzz } => vec![
+            1,
+            albedo.x().to_bits() as u64,
+            albedo.y().to_bits() as u64,
+            albedo.z().to_bits() as u64,
+            fuzz.to_bits() as u64,
+        ],
+        Material::Dielectric { ref_idx } => vec![2, ref_idx.to_bits() as u64],
+        Material::DiffuseLight { emit, group } => vec![
+            3,
+            emit.x().to_bits() as u64,
+            emit.y().to_bits() as u64,
+            emit.z().to_bits() as u64,
+            group as u64,
+        ],
+    };
+    (sampler::hash(&values) as u32 & 0xff_ffff).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse_list("depth, object-id").unwrap(),
+            [Aov::Depth, Aov::ObjectId]
+        );
+        assert_eq!(parse_list("all").unwrap().len(), 7);
+        assert!(parse_list("depth,colour").is_err());
+    }
+
+    #[test]
+    fn test_values() {
+        let r = Ray::new(Vec3::default(), Vec3::new(0.0, 0.0, -2.0));
+        let rec = HitRecord {
+            t: 1.5,
+            p: Vec3::new(0.0, 0.0, -3.0),
+            normal: Vec3::new(0.0, 0.0, 1.0),
+            material: Material::Metal {
+                albedo: Vec3::new(0.8, 0.6, 0.2),
+                fuzz: 0.1,
+            },
+            object: 4,
+        };
+        assert_eq!(Aov::Depth.value(&r, &rec), Vec3::new(3.0, 3.0, 3.0));
+        assert_eq!(Aov::Albedo.value(&r, &rec), Vec3::new(0.8, 0.6, 0.2));
+        assert_eq!(Aov::ObjectId.value(&r, &rec).x(), 5.0);
+
+        let id = Aov::MaterialId.value(&r, &rec).x();
+        assert!(id >= 1.0 && id == id.round());
+        let glass = HitRecord {
+            material: Material::Dielectric { ref_idx: 1.5 },
+            ..rec
+        };
+        assert_ne!(Aov::MaterialId.value(&r, &glass).x(), id);
+    }
+}
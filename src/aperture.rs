@@ -0,0 +1,217 @@
+use crate::ppm;
+use crate::sampler;
+use crate::vec3::Vec3;
+use std::f32::consts::PI;
+use std::io;
+use std::sync::Arc;
+
+/// The shape of the lens opening, which is also the shape of out-of-focus
+/// highlights.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ApertureShape {
+    Circle,
+    /// Regular polygon formed by `blades` straight blades, `rotation` in degrees.
+    Polygon {
+        blades: u32,
+        rotation: f32,
+    },
+    /// Opening painted in an image: brighter pixels let more light through.
+    Mask(Arc<ApertureMask>),
+}
+
+impl ApertureShape {
+    /// Parses `circle`, `polygon:BLADES[:ROTATION]` or `mask:FILE`, loading the
+    /// mask image in the last case.
+    pub fn from_spec(spec: &str) -> io::Result<ApertureShape> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+        let (name, rest) = spec.split_once(':').unwrap_or((spec, ""));
+
+        match name {
+            "circle" => Ok(ApertureShape::Circle),
+            "polygon" => {
+                let (blades, rotation) = rest.split_once(':').unwrap_or((rest, "0"));
+                let blades = blades
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|&b| b >= 3)
+                    .ok_or_else(|| invalid(format!("invalid blade count in '{}'", spec)))?;
+                let rotation = rotation
+                    .parse::<f32>()
+                    .map_err(|_| invalid(format!("invalid blade rotation in '{}'", spec)))?;
+                Ok(ApertureShape::Polygon { blades, rotation })
+            }
+            "mask" => Ok(ApertureShape::Mask(Arc::new(ApertureMask::load(rest)?))),
+            _ => Err(invalid(format!("unknown aperture shape '{}'", name))),
+        }
+    }
+}
+
+/// Aperture shape plus the anamorphic squeeze of the lens.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Aperture {
+    pub shape: ApertureShape,
+    /// Horizontal squeeze factor, above zero; 2.0 turns round bokeh into tall
+    /// ovals.
+    pub squeeze: f32,
+}
+
+impl Default for Aperture {
+    fn default() -> Self {
+        Aperture {
+            shape: ApertureShape::Circle,
+            squeeze: 1.0,
+        }
+    }
+}
+
+impl Aperture {
+    /// Maps `u` in the unit square to a point on the aperture, scaled to fit
+    /// inside the unit disk.
+    pub fn sample(&self, u: (f32, f32)) -> Vec3 {
+        let p = match &self.shape {
+            ApertureShape::Circle => sampler::concentric_disk(u),
+            ApertureShape::Polygon { blades, rotation } => {
+                // Pick one of the triangles fanning out from the centre, then a
+                // uniform point inside it.
+                let step = 2.0 * PI / *blades as f32;
+                let blade = ((u.0 * *blades as f32) as u32).min(blades - 1);
+                let start = rotation * PI / 180.0 + step * blade as f32;
+                let (mut a, mut b) = (u.0 * *blades as f32 - blade as f32, u.1);
+                if a + b > 1.0 {
+                    a = 1.0 - a;
+                    b = 1.0 - b;
+                }
+                let v0 = Vec3::new(start.cos(), start.sin(), 0.0);
+                let v1 = Vec3::new((start + step).cos(), (start + step).sin(), 0.0);
+                a * v0 + b * v1
+            }
+            ApertureShape::Mask(mask) => mask.sample(u),
+        };
+
+        Vec3::new(p.x() / self.squeeze, p.y(), 0.0)
+    }
+}
+
+/// An aperture image turned into a distribution over its pixels.
+#[derive(Debug, PartialEq)]
+pub struct ApertureMask {
+    width: usize,
+    height: usize,
+    // Running sum of pixel luminance in row-major order, normalised to end at 1.
+    cdf: Vec<f32>,
+}
+
+impl ApertureMask {
+    pub fn load(path: &str) -> io::Result<ApertureMask> {
+        let image = ppm::read(path)?;
+        ApertureMask::from_image(&image).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("aperture mask '{}' is completely black", path),
+            )
+        })
+    }
+
+    pub fn from_image(image: &ppm::Image) -> Option<ApertureMask> {
+        let mut total = 0.0;
+        let mut cdf: Vec<f32> = image
+            .pixels
+            .iter()
+            .map(|p| {
+                total += 0.2126 * p.r() + 0.7152 * p.g() + 0.0722 * p.b();
+                total
+            })
+            .collect();
+
+        if total <= 0.0 {
+            return None;
+        }
+        for c in cdf.iter_mut() {
+            *c /= total;
+        }
+
+        Some(ApertureMask {
+            width: image.width,
+            height: image.height,
+            cdf,
+        })
+    }
+
+    /// Picks a pixel with probability proportional to its brightness and a
+    /// jittered point inside it. The image is inscribed in the unit disk, its
+    /// longer side centred in a square whose corners touch the circle.
+    fn sample(&self, u: (f32, f32)) -> Vec3 {
+        let index = self
+            .cdf
+            .partition_point(|&c| c <= u.0)
+            .min(self.cdf.len() - 1);
+        // Where `u.0` fell inside the pixel's share of the distribution.
+        let below = if index > 0 { self.cdf[index - 1] } else { 0.0 };
+        let jx = ((u.0 - below) / (self.cdf[index] - below)).clamp(0.0, 1.0);
+        let jy = u.1;
+        let (x, y) = (index % self.width, index / self.width);
+
+        let size = self.width.max(self.height) as f32;
+        let px = (x as f32 + jx - self.width as f32 / 2.0) / size;
+        // Image rows run top to bottom.
+        let py = (self.height as f32 / 2.0 - y as f32 - jy) / size;
+
+        // Scale the square so its corners lie on the unit circle.
+        std::f32::consts::SQRT_2 * Vec3::new(px, py, 0.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn uniform_square() -> impl Iterator<Item = (f32, f32)> {
+        (0..1024).map(|i| ((i % 32) as f32 / 32.0, (i / 32) as f32 / 32.0))
+    }
+
+    #[test]
+    fn test_polygon_inside_blades() {
+        let aperture = Aperture {
+            shape: ApertureShape::Polygon {
+                blades: 4,
+                rotation: 45.0,
+            },
+            squeeze: 1.0,
+        };
+        // A square with corners at 45 degrees has edges at |x|, |y| <= sqrt(1/2).
+        for u in uniform_square() {
+            let p = aperture.sample(u);
+            assert!(p.x().abs() <= 0.7072 && p.y().abs() <= 0.7072, "{:?}", p);
+        }
+    }
+
+    #[test]
+    fn test_anamorphic_squeeze() {
+        let aperture = Aperture {
+            shape: ApertureShape::Circle,
+            squeeze: 2.0,
+        };
+        for u in uniform_square() {
+            assert!(aperture.sample(u).x().abs() <= 0.5);
+        }
+    }
+
+    #[test]
+    fn test_mask_only_bright_pixels() {
+        let image = ppm::decode(b"P2 2 2 255 0 255 0 0").unwrap();
+        let mask = ApertureMask::from_image(&image).unwrap();
+        for u in uniform_square() {
+            let p = mask.sample(u);
+            // Only the top-right quadrant is open.
+            assert!(p.x() >= 0.0 && p.y() >= 0.0, "{:?}", p);
+        }
+        assert!(ApertureMask::from_image(&ppm::decode(b"P2 1 1 255 0").unwrap()).is_none());
+
+        // Even the corners of a fully open mask stay inside the unit disk.
+        let open = ApertureMask::from_image(&ppm::decode(b"P2 2 1 255 255 255").unwrap());
+        let open = open.unwrap();
+        for u in uniform_square().chain([(0.0, 0.0), (0.999_999, 0.999_999)]) {
+            assert!(open.sample(u).length() <= 1.0 + 1e-6);
+        }
+    }
+}
@@ -0,0 +1,593 @@
+use crate::hittable::Hittable;
+use crate::integrator::{Integrator, MAX_DEPTH};
+use crate::lpe::Event;
+use crate::material::{bsdf, bsdf_pdf, is_delta, scatter, Material, Transport};
+use crate::ray::Ray;
+use crate::sampler::{self, Sampler};
+use crate::scene::Scene;
+use crate::vec3::Vec3;
+use std::f32::consts::PI;
+
+/// Bidirectional path tracing, after Veach and PBRT.
+///
+/// Every sample traces a path from the camera and one from a light, then
+/// joins every prefix of the one to every prefix of the other, weighting each
+/// way of forming a path with the balance heuristic.
+///
+/// Surfaces scatter as in the path tracer: diffuse ones around the normal
+/// offset by a point in the unit ball, whose directions have density
+/// `2 cos³ θ / π`, and metal within a ball of radius `fuzz` around the mirror
+/// direction. Glass and sharp metal
+/// cannot be connected through. Paths are never traced out of the sky, which
+/// is only reached from the camera and by sampling directions towards it; and
+/// light paths are never joined to the camera itself, which would add light
+/// to other pixels than the sample's.
+pub struct Bidirectional;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Camera,
+    Surface,
+    /// A point on the scene's light with this index.
+    Light(usize),
+    /// The sky, seen in the direction of the normal.
+    Sky,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Vertex {
+    kind: Kind,
+    p: Vec3,
+    n: Vec3,
+    material: Material,
+    /// Throughput of the path up to here, divided by its density.
+    beta: Vec3,
+    /// Whether the surface scatters into a single direction.
+    delta: bool,
+    /// Density per area of reaching this vertex from the previous one, and
+    /// of reaching it from the next one going the other way.
+    pdf_fwd: f32,
+    pdf_rev: f32,
+}
+
+impl Vertex {
+    fn new(kind: Kind, p: Vec3, n: Vec3, beta: Vec3) -> Vertex {
+        Vertex {
+            kind,
+            p,
+            n,
+            material: Material::default(),
+            beta,
+            delta: false,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+        }
+    }
+
+    /// Unit direction from here to `to`.
+    fn direction(&self, to: &Vertex) -> Vec3 {
+        match to.kind {
+            Kind::Sky => to.n,
+            _ => Vec3::unit_vector(&(to.p - self.p)),
+        }
+    }
+
+    /// Converts a density over directions leaving this vertex into one over
+    /// the area around `next`.
+    fn area_pdf(&self, pdf: f32, next: &Vertex) -> f32 {
+        match next.kind {
+            Kind::Sky => pdf,
+            _ => {
+                let d = next.p - self.p;
+                let w = Vec3::unit_vector(&d);
+                pdf * Vec3::dot(&next.n, &w).abs() / d.squared_length()
+            }
+        }
+    }
+
+    /// Whether paths can be joined here.
+    fn is_connectable(&self) -> bool {
+        match self.kind {
+            Kind::Surface => !self.delta && !matches!(self.material, Material::DiffuseLight { .. }),
+            Kind::Light(_) => true,
+            Kind::Camera | Kind::Sky => false,
+        }
+    }
+
+    /// Density per area at `to` of this vertex sampling it, having been
+    /// reached from `from` by a path traced in `mode`.
+    fn pdf(&self, from: Option<&Vertex>, to: &Vertex, mode: Transport) -> f32 {
+        let w = self.direction(to);
+        let pdf = match (self.kind, from) {
+            (Kind::Light(_), _) => Vec3::dot(&self.n, &w).max(0.0) / PI,
+            (Kind::Surface, Some(from)) => {
+                bsdf_pdf(&self.material, self.n, self.direction(from), w, mode)
+            }
+            _ => 0.0,
+        };
+        self.area_pdf(pdf, to)
+    }
+}
+
+/// The lights a path can start from: every emitting sphere, and the sky
+/// unless it is black, each picked with the same probability.
+struct Lights<'a> {
+    scene: &'a Scene,
+    count: usize,
+}
+
+/// A point sampled on a light.
+enum LightSample {
+    Sphere(Vertex),
+    /// A direction towards the sky, and its density.
+    Sky(Vec3, f32),
+}
+
+impl<'a> Lights<'a> {
+    fn new(scene: &'a Scene) -> Lights<'a> {
+        let sky = scene.sky.horizon.squared_length() + scene.sky.zenith.squared_length() > 0.0;
+        Lights {
+            scene,
+            count: scene.lights.len() + sky as usize,
+        }
+    }
+
+    /// Probability of picking any one light.
+    fn choice_pdf(&self) -> f32 {
+        1.0 / self.count as f32
+    }
+
+    /// Density per area of a path starting at `v`, a point on a light.
+    fn origin_pdf(&self, v: &Vertex) -> f32 {
+        match v.kind {
+            Kind::Light(i) => {
+                let r = self.scene.lights[i].radius;
+                self.choice_pdf() / (4.0 * PI * r * r)
+            }
+            _ => 0.0,
+        }
+    }
+
+    fn sample(&self, choice: f32, u: (f32, f32)) -> Option<LightSample> {
+        if self.count == 0 {
+            return None;
+        }
+        let i = ((choice * self.count as f32) as usize).min(self.count - 1);
+        let Some(light) = self.scene.lights.get(i) else {
+            let w = sampler::uniform_ball(u, 1.0);
+            return Some(LightSample::Sky(w, self.choice_pdf() / (4.0 * PI)));
+        };
+        let n = sampler::uniform_ball(u, 1.0);
+        let mut v = Vertex::new(
+            Kind::Light(i),
+            light.center + n * light.radius,
+            n,
+            light.emit,
+        );
+        v.material = Material::DiffuseLight {
+            emit: light.emit,
+            group: light.group,
+        };
+        v.pdf_fwd = self.origin_pdf(&v);
+        v.beta = light.emit / v.pdf_fwd;
+        Some(LightSample::Sphere(v))
+    }
+}
+
+/// Extends `path` by following `r` until it leaves the scene, is absorbed or
+/// is as long as a path may be. `pdf` is the density of the direction of `r`.
+fn walk(
+    scene: &Scene,
+    mut r: Ray,
+    mut beta: Vec3,
+    mut pdf: f32,
+    mode: Transport,
+    sampler: &mut dyn Sampler,
+    path: &mut Vec<Vertex>,
+) {
+    // The camera path may also end on a light, the light path not on the
+    // camera.
+    let max = match mode {
+        Transport::Radiance => MAX_DEPTH + 2,
+        Transport::Importance => MAX_DEPTH + 1,
+    };
+    while path.len() < max {
+        let prev = *path.last().unwrap();
+        let Some(rec) = scene.world.hit(&r, 0.001, f32::MAX) else {
+            if mode == Transport::Radiance {
+                let mut sky = Vertex::new(
+                    Kind::Sky,
+                    r.origin(),
+                    Vec3::unit_vector(&r.direction()),
+                    beta,
+                );
+                sky.pdf_fwd = pdf;
+                path.push(sky);
+            }
+            return;
+        };
+
+        let mut v = Vertex::new(Kind::Surface, rec.p, rec.normal, beta);
+        v.material = rec.material;
+        v.pdf_fwd = prev.area_pdf(pdf, &v);
+        if let Material::DiffuseLight { .. } = rec.material {
+            // Paths from lights end here too, as lights do not scatter.
+            let light = scene.lights.iter().position(|l| l.object == rec.object);
+            if let (Transport::Radiance, Some(i)) = (mode, light) {
+                v.kind = Kind::Light(i);
+                path.push(v);
+            }
+            return;
+        }
+        v.delta = is_delta(&rec.material);
+        path.push(v);
+        if path.len() == max {
+            return;
+        }
+
+        let (u0, u1) = sampler.next_2d();
+        let u = [u0, u1, sampler.next_1d()];
+        let from = -Vec3::unit_vector(&r.direction());
+        let w = match (mode, rec.material) {
+            (Transport::Importance, Material::Lambertian { .. }) => {
+                sampler::cosine_direction(rec.normal, (u0, u1))
+            }
+            _ => {
+                let mut scattered = Ray::new(Vec3::default(), Vec3::default());
+                let mut attenuation = Vec3::default();
+                if !scatter(&rec.material, &r, &rec, u, &mut attenuation, &mut scattered) {
+                    return;
+                }
+                if v.delta || mode == Transport::Radiance {
+                    // The attenuation is exactly the scattering function
+                    // times the cosine over the density.
+                    beta = beta * attenuation;
+                }
+                Vec3::unit_vector(&scattered.direction())
+            }
+        };
+
+        let (pdf_fwd, pdf_rev) = match v.delta {
+            true => (0.0, 0.0),
+            false => (
+                bsdf_pdf(&rec.material, rec.normal, from, w, mode),
+                bsdf_pdf(&rec.material, rec.normal, w, from, mode.other()),
+            ),
+        };
+        if mode == Transport::Importance && !v.delta {
+            if pdf_fwd <= 0.0 {
+                return;
+            }
+            let f = bsdf(&rec.material, rec.normal, w, from);
+            beta = beta * f * (Vec3::dot(&w, &rec.normal).abs() / pdf_fwd);
+        }
+        if beta.squared_length() == 0.0 {
+            return;
+        }
+
+        let last = path.len() - 1;
+        path[last - 1].pdf_rev = path[last].area_pdf(pdf_rev, &path[last - 1]);
+        r = Ray::new(rec.p, w);
+        pdf = pdf_fwd;
+    }
+}
+
+/// Whether nothing lies between the points of `a` and `b`.
+fn visible(scene: &Scene, a: &Vertex, b: &Vertex) -> bool {
+    let w = a.direction(b);
+    let t_max = match b.kind {
+        Kind::Sky => f32::MAX,
+        _ => (b.p - a.p).length() - 0.001,
+    };
+    scene.world.hit(&Ray::new(a.p, w), 0.001, t_max).is_none()
+}
+
+impl Integrator for Bidirectional {
+    fn radiance(
+        &self,
+        r: &Ray,
+        scene: &Scene,
+        sampler: &mut dyn Sampler,
+        _index: usize,
+        record: &mut dyn FnMut(&[Event], Vec3),
+    ) -> Vec3 {
+        let one = Vec3::new(1.0, 1.0, 1.0);
+        let mut camera = vec![Vertex::new(Kind::Camera, r.origin(), Vec3::default(), one)];
+        walk(
+            scene,
+            *r,
+            one,
+            1.0,
+            Transport::Radiance,
+            sampler,
+            &mut camera,
+        );
+
+        let lights = Lights::new(scene);
+        let choice = sampler.next_1d();
+        let (position, direction) = (sampler.next_2d(), sampler.next_2d());
+        let mut light = Vec::new();
+        if let Some(LightSample::Sphere(origin)) = lights.sample(choice, position) {
+            let w = sampler::cosine_direction(origin.n, direction);
+            let pdf = Vec3::dot(&w, &origin.n) / PI;
+            let beta = origin.beta * (Vec3::dot(&w, &origin.n) / pdf);
+            light.push(origin);
+            let r = Ray::new(origin.p, w);
+            walk(
+                scene,
+                r,
+                beta,
+                pdf,
+                Transport::Importance,
+                sampler,
+                &mut light,
+            );
+        }
+
+        let mut total = Vec3::default();
+        for t in 2..=camera.len() {
+            for s in 0..=light.len().max(1) {
+                if s + t - 2 > MAX_DEPTH {
+                    break;
+                }
+                let sampled = match s {
+                    1 => {
+                        let choice = sampler.next_1d();
+                        lights.sample(choice, sampler.next_2d())
+                    }
+                    _ => None,
+                };
+                let path = Strategy {
+                    scene,
+                    lights: &lights,
+                    camera: &camera[..t],
+                    light: match &sampled {
+                        Some(LightSample::Sphere(v)) => std::slice::from_ref(v),
+                        _ => &light[..s.min(light.len())],
+                    },
+                };
+                let value = match (s, &sampled) {
+                    (0, _) => path.emitted(),
+                    (1, Some(LightSample::Sky(w, pdf))) => path.to_sky(*w, *pdf),
+                    (1, None) => None,
+                    _ => path.connect(),
+                };
+                if let Some((value, events)) = value {
+                    record(&events, value);
+                    total = total + value;
+                }
+            }
+        }
+        total
+    }
+}
+
+/// One way of forming a path: the first vertices of the camera path joined
+/// to the first of the light path.
+struct Strategy<'a> {
+    scene: &'a Scene,
+    lights: &'a Lights<'a>,
+    camera: &'a [Vertex],
+    light: &'a [Vertex],
+}
+
+impl Strategy<'_> {
+    /// The light the camera path ends on, if it ends on one.
+    fn emitted(&self) -> Option<(Vec3, Vec<Event>)> {
+        let (pt, prev) = (self.camera.last()?, &self.camera[self.camera.len() - 2]);
+        let (value, weight) = match pt.kind {
+            Kind::Light(i) => (pt.beta * self.scene.lights[i].emit, self.weight()),
+            Kind::Sky => {
+                let value = pt.beta * self.scene.sky.color(pt.n);
+                match prev.is_connectable() {
+                    // Sampling the sky is the only other way to this path.
+                    true => (value, pt.pdf_fwd / (pt.pdf_fwd + self.sky_pdf())),
+                    false => (value, 1.0),
+                }
+            }
+            _ => return None,
+        };
+        Some((value * weight, self.events(None)))
+    }
+
+    /// The camera path joined to direction `w` towards the sky.
+    fn to_sky(&self, w: Vec3, pdf: f32) -> Option<(Vec3, Vec<Event>)> {
+        let (pt, prev) = (self.camera.last()?, &self.camera[self.camera.len() - 2]);
+        if !pt.is_connectable() {
+            return None;
+        }
+        let to_camera = pt.direction(prev);
+        let f = bsdf(&pt.material, pt.n, to_camera, w);
+        if f.squared_length() == 0.0 {
+            return None;
+        }
+        let sky = Vertex::new(Kind::Sky, pt.p, w, Vec3::default());
+        if !visible(self.scene, pt, &sky) {
+            return None;
+        }
+        let value = pt.beta * f * self.scene.sky.color(w) * (Vec3::dot(&w, &pt.n).abs() / pdf);
+        let bsdf_pdf = bsdf_pdf(&pt.material, pt.n, to_camera, w, Transport::Radiance);
+        Some((value * (pdf / (pdf + bsdf_pdf)), self.events(Some(&sky))))
+    }
+
+    /// The last camera vertex joined to the last light vertex.
+    fn connect(&self) -> Option<(Vec3, Vec<Event>)> {
+        let (pt, qs) = (self.camera.last()?, self.light.last()?);
+        if !pt.is_connectable() || !qs.is_connectable() {
+            return None;
+        }
+        let w = pt.direction(qs);
+        let pt_prev = &self.camera[self.camera.len() - 2];
+        let f_pt = bsdf(&pt.material, pt.n, pt.direction(pt_prev), w);
+        let f_qs = match self.light {
+            [_] => match Vec3::dot(&qs.n, &w) < 0.0 {
+                true => Vec3::new(1.0, 1.0, 1.0),
+                false => Vec3::default(),
+            },
+            [.., qs_prev, _] => bsdf(&qs.material, qs.n, -w, qs.direction(qs_prev)),
+            [] => unreachable!(),
+        };
+        let g = Vec3::dot(&pt.n, &w).abs() * Vec3::dot(&qs.n, &w).abs()
+            / (qs.p - pt.p).squared_length();
+        let value = pt.beta * f_pt * f_qs * qs.beta * g;
+        if value.squared_length() == 0.0 || !visible(self.scene, pt, qs) {
+            return None;
+        }
+        Some((value * self.weight(), self.events(None)))
+    }
+
+    fn sky_pdf(&self) -> f32 {
+        self.lights.choice_pdf() / (4.0 * PI)
+    }
+
+    /// The balance heuristic weight of this strategy among all that could
+    /// have made the same path, ending on a sphere light. Following PBRT,
+    /// each other strategy's density is found relative to this one's by
+    /// moving the join one vertex at a time.
+    fn weight(&self) -> f32 {
+        let (camera, light) = (self.camera, self.light);
+        let (s, t) = (light.len(), camera.len());
+        let (pt, pt_prev) = (&camera[t - 1], &camera[t - 2]);
+        let qs = light.last();
+        let qs_prev = light.len().checked_sub(2).map(|i| &light[i]);
+
+        // The reverse densities of the vertices around the join, which only
+        // this strategy knows.
+        let pt_rev = match qs {
+            Some(qs) => qs.pdf(qs_prev, pt, Transport::Importance),
+            None => self.lights.origin_pdf(pt),
+        };
+        let pt_prev_rev = match qs {
+            Some(qs) => pt.pdf(Some(qs), pt_prev, Transport::Importance),
+            None => pt.pdf(None, pt_prev, Transport::Importance),
+        };
+        let qs_rev = qs.map(|qs| pt.pdf(Some(pt_prev), qs, Transport::Radiance));
+        let qs_prev_rev = qs_prev.map(|v| qs.unwrap().pdf(Some(pt), v, Transport::Radiance));
+
+        let remap = |pdf: f32| if pdf != 0.0 { pdf } else { 1.0 };
+        let mut sum = 0.0;
+
+        // Fewer camera vertices, down to two; one would mean joining light
+        // paths to the camera.
+        let mut ratio = 1.0;
+        for i in (2..t).rev() {
+            let rev = match t - 1 - i {
+                0 => pt_rev,
+                1 => pt_prev_rev,
+                _ => camera[i].pdf_rev,
+            };
+            ratio *= remap(rev) / remap(camera[i].pdf_fwd);
+            let delta = i != t - 1 && camera[i].delta;
+            if !delta && !camera[i - 1].delta {
+                sum += ratio;
+            }
+        }
+
+        // Fewer light vertices, down to none.
+        let mut ratio = 1.0;
+        for i in (0..s).rev() {
+            let rev = match s - 1 - i {
+                0 => qs_rev.unwrap(),
+                1 => qs_prev_rev.unwrap(),
+                _ => light[i].pdf_rev,
+            };
+            ratio *= remap(rev) / remap(light[i].pdf_fwd);
+            let delta = i != s - 1 && light[i].delta;
+            let prev_delta = i > 0 && light[i - 1].delta;
+            if !delta && !prev_delta {
+                sum += ratio;
+            }
+        }
+
+        1.0 / (1.0 + sum)
+    }
+
+    /// The events of the joined path, from the camera to the light; `end`
+    /// replaces the light path.
+    fn events(&self, end: Option<&Vertex>) -> Vec<Event> {
+        let path: Vec<&Vertex> = match end {
+            Some(end) => self.camera.iter().chain([end]).collect(),
+            None => self.camera.iter().chain(self.light.iter().rev()).collect(),
+        };
+        (0..path.len())
+            .map(|i| match path[i].kind {
+                Kind::Camera => Event::Camera,
+                Kind::Light(l) => Event::Light(self.scene.lights[l].group),
+                Kind::Sky => Event::Light(self.scene.sky.group),
+                Kind::Surface => Event::between(
+                    &path[i].material,
+                    path[i].n,
+                    path[i].direction(path[i - 1]),
+                    path[i].direction(path[i + 1]),
+                ),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::Camera;
+    use crate::integrator::{Bounces, PathTracer};
+    use crate::mat3::Mat3;
+    use crate::sampler::SamplerKind;
+
+    #[test]
+    fn test_matches_path_tracer() {
+        let scene = Scene::parse(
+            "sky 0.1 0.1 0.1 0.05 0.07 0.1\n\
+             sphere 0 -1000 0 1000 lambertian 0.5 0.5 0.5\n\
+             sphere 0 1 0 1 dielectric 1.5\n\
+             sphere -4 1 0 1 lambertian 0.4 0.2 0.1\n\
+             sphere 4 1 0 1 metal 0.7 0.6 0.5 0.3\n\
+             sphere 2 3 2 0.5 light 10 10 10 key\n",
+            Mat3::IDENTITY,
+            1.0,
+        )
+        .unwrap();
+        let camera = Camera::new(
+            Vec3::new(13.0, 2.0, 3.0),
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            20.0,
+            1.5,
+            0.0,
+            10.0,
+        );
+        let (size, samples) = (6, 256);
+        let mean = |integrator: &dyn Integrator| {
+            let mut sampler = SamplerKind::Sobol.create(samples, 1);
+            let mut sum = Vec3::default();
+            for y in 0..size {
+                for x in 0..size {
+                    for index in 0..samples {
+                        sampler.start_sample(x, y, index);
+                        let (jx, jy) = sampler.next_2d();
+                        let (s, t) = ((x as f32 + jx) / size as f32, (y as f32 + jy) / size as f32);
+                        let r = camera.get_ray(s, t, sampler.next_2d()).unwrap();
+                        sum = sum
+                            + integrator.radiance(
+                                &r,
+                                &scene,
+                                sampler.as_mut(),
+                                index,
+                                &mut |_, _| {},
+                            );
+                    }
+                }
+            }
+            sum / (size * size * samples) as f32
+        };
+        let (path, bidirectional) = (
+            mean(&PathTracer::new(Bounces::default())),
+            mean(&Bidirectional),
+        );
+        assert!(
+            (path - bidirectional).length() < 0.03 * path.length(),
+            "{:?} {:?}",
+            path,
+            bidirectional
+        );
+    }
+}
@@ -0,0 +1,154 @@
+use crate::sampler::mix_bits;
+use std::sync::OnceLock;
+
+/// Side length of the tiling blue-noise texture.
+pub const SIZE: usize = 64;
+
+/// Value in `(0, 1)` of the blue-noise texture at `(x, y)`, tiling in both
+/// directions. Every value occurs exactly once per tile and neighbouring
+/// pixels have values far apart, so thresholding it gives evenly spread dots.
+pub fn value(x: usize, y: usize) -> f32 {
+    static TEXTURE: OnceLock<Vec<f32>> = OnceLock::new();
+    TEXTURE.get_or_init(|| void_and_cluster(SIZE))[(y % SIZE) * SIZE + x % SIZE]
+}
+
+/// A binary pattern on a torus together with how crowded each cell is: the sum
+/// of a Gaussian centred on every set cell.
+struct Pattern<'a> {
+    size: usize,
+    kernel: &'a [f32],
+    ones: Vec<bool>,
+    energy: Vec<f32>,
+}
+
+impl Pattern<'_> {
+    fn toggle(&mut self, i: usize) {
+        self.ones[i] = !self.ones[i];
+        let sign = if self.ones[i] { 1.0 } else { -1.0 };
+        let (ix, iy) = (i % self.size, i / self.size);
+        for (j, e) in self.energy.iter_mut().enumerate() {
+            let dx = (j % self.size + self.size - ix) % self.size;
+            let dy = (j / self.size + self.size - iy) % self.size;
+            *e += sign * self.kernel[dy * self.size + dx];
+        }
+    }
+
+    /// The set cell with the most set cells around it.
+    fn tightest_cluster(&self) -> usize {
+        self.extreme(true, |a, b| a > b)
+    }
+
+    /// The empty cell with the fewest set cells around it.
+    fn largest_void(&self) -> usize {
+        self.extreme(false, |a, b| a < b)
+    }
+
+    fn extreme(&self, set: bool, better: impl Fn(f32, f32) -> bool) -> usize {
+        let mut best = None;
+        for (i, &e) in self.energy.iter().enumerate() {
+            if self.ones[i] == set && best.is_none_or(|b: usize| better(e, self.energy[b])) {
+                best = Some(i);
+            }
+        }
+        best.expect("pattern has no cell of the requested kind")
+    }
+}
+
+/// Ulichney's void-and-cluster method: ranks every cell so that thresholding
+/// the ranks at any level yields a blue-noise dot pattern.
+fn void_and_cluster(size: usize) -> Vec<f32> {
+    const SIGMA: f32 = 1.5;
+    let n = size * size;
+
+    let kernel: Vec<f32> = (0..n)
+        .map(|i| {
+            let (dx, dy) = (i % size, i / size);
+            let dx = dx.min(size - dx) as f32;
+            let dy = dy.min(size - dy) as f32;
+            (-(dx * dx + dy * dy) / (2.0 * SIGMA * SIGMA)).exp()
+        })
+        .collect();
+    let mut initial = Pattern {
+        size,
+        kernel: &kernel,
+        ones: vec![false; n],
+        energy: vec![0.0; n],
+    };
+
+    // Seed roughly a tenth of the cells, then move the most crowded dot into
+    // the biggest gap until that stops changing anything.
+    for i in 0..n {
+        if mix_bits(i as u64).is_multiple_of(10) {
+            initial.toggle(i);
+        }
+    }
+    loop {
+        let cluster = initial.tightest_cluster();
+        initial.toggle(cluster);
+        let void = initial.largest_void();
+        initial.toggle(void);
+        if void == cluster {
+            break;
+        }
+    }
+    let seeded = initial.ones.iter().filter(|&&one| one).count();
+
+    let mut ranks = vec![0; n];
+
+    // Ranks below the initial pattern: remove the tightest clusters first.
+    let mut pattern = Pattern {
+        ones: initial.ones.clone(),
+        energy: initial.energy.clone(),
+        ..initial
+    };
+    for rank in (0..seeded).rev() {
+        let cluster = pattern.tightest_cluster();
+        pattern.toggle(cluster);
+        ranks[cluster] = rank;
+    }
+
+    // Ranks above it: fill the largest voids. Past half full this is the same
+    // as Ulichney's third phase, since the tightest cluster of empty cells is
+    // where set cells have the least energy.
+    let mut pattern = initial;
+    for rank in seeded..n {
+        let void = pattern.largest_void();
+        pattern.toggle(void);
+        ranks[void] = rank;
+    }
+
+    ranks
+        .into_iter()
+        .map(|rank| (rank as f32 + 0.5) / n as f32)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_every_level_once() {
+        let mut values: Vec<f32> = (0..SIZE * SIZE)
+            .map(|i| value(i % SIZE, i / SIZE))
+            .collect();
+        values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        for (i, v) in values.iter().enumerate() {
+            assert!((v * (SIZE * SIZE) as f32 - 0.5 - i as f32).abs() < 1e-2);
+        }
+    }
+
+    #[test]
+    fn test_neighbours_differ_more_than_white_noise() {
+        // Uniform white noise has a mean neighbour difference of 1/3.
+        let mut total = 0.0;
+        for y in 0..SIZE {
+            for x in 0..SIZE {
+                total += (value(x, y) - value(x + 1, y)).abs();
+                total += (value(x, y) - value(x, y + 1)).abs();
+            }
+        }
+        let mean = total / (2 * SIZE * SIZE) as f32;
+        assert!(mean > 0.38, "{}", mean);
+    }
+}
@@ -1,7 +1,90 @@
+use crate::aperture::Aperture;
+use crate::exposure;
+use crate::lens::LensSystem;
+use crate::stereo::{Eye, StereoMode};
 use crate::{ray::Ray, vec3::Vec3};
-use rand::prelude::*;
+use std::f32::consts::PI;
+use std::str::FromStr;
+use std::sync::Arc;
 
-#[derive(Clone, Copy)]
+/// How a fisheye lens maps the angle from the optical axis onto the image circle.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FisheyeMapping {
+    /// r = f * theta
+    Equidistant,
+    /// r = 2f * sin(theta / 2)
+    Equisolid,
+}
+
+/// The projection used to turn image coordinates into camera rays.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Projection {
+    /// Thin-lens pinhole projection driven by the vertical field of view.
+    Perspective,
+    /// Parallel rays; `height` is the vertical extent of the view in world units.
+    Orthographic { height: f32 },
+    /// Circular fisheye fitted to the shorter image side, `fov` in degrees.
+    Fisheye { fov: f32, mapping: FisheyeMapping },
+    /// Full 360x180 latitude/longitude panorama.
+    Equirectangular,
+    /// Panorama that wraps `hfov` degrees horizontally and is planar vertically.
+    Cylindrical { hfov: f32 },
+    /// Rays traced from the film through a real lens prescription.
+    Lens(Arc<LensSystem>),
+}
+
+impl FromStr for Projection {
+    type Err = String;
+
+    /// Parses `name[:param[:param]]`, e.g. `orthographic:4`, `fisheye:180:equisolid`,
+    /// `equirect` or `cylindrical:360`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(':');
+        let name = parts.next().unwrap_or_default();
+        let mut number = |default: f32| -> Result<f32, String> {
+            match parts.next() {
+                Some(p) => p
+                    .parse::<f32>()
+                    .map_err(|_| format!("invalid number '{}' in projection '{}'", p, s)),
+                None => Ok(default),
+            }
+        };
+
+        let projection = match name {
+            "perspective" => Projection::Perspective,
+            "orthographic" | "ortho" => Projection::Orthographic {
+                height: number(4.0)?,
+            },
+            "fisheye" => {
+                let fov = number(180.0)?;
+                let mapping = match parts.next() {
+                    None | Some("equidistant") => FisheyeMapping::Equidistant,
+                    Some("equisolid") => FisheyeMapping::Equisolid,
+                    Some(other) => return Err(format!("unknown fisheye mapping '{}'", other)),
+                };
+                Projection::Fisheye { fov, mapping }
+            }
+            "equirectangular" | "equirect" => Projection::Equirectangular,
+            "cylindrical" => Projection::Cylindrical {
+                hfov: number(360.0)?,
+            },
+            _ => return Err(format!("unknown projection '{}'", name)),
+        };
+
+        if parts.next().is_some() {
+            return Err(format!("too many parameters in projection '{}'", s));
+        }
+
+        Ok(projection)
+    }
+}
+
+/// Height of the virtual sensor used to relate the field of view to a focal
+/// length: a full-frame 24mm sensor. Scene units are taken to be metres, as
+/// lens prescriptions are, so the aperture and focal length share a unit.
+const SENSOR_HEIGHT: f32 = 0.024;
+
+#[derive(Clone)]
 pub struct Camera {
     origin: Vec3,
     lower_left_corner: Vec3,
@@ -10,7 +93,14 @@ pub struct Camera {
     lens_radius: f32,
     u: Vec3,
     v: Vec3,
-    // w: Vec3,
+    w: Vec3,
+    half_height: f32,
+    aspect: f32,
+    focus_dist: f32,
+    projection: Projection,
+    aperture: Aperture,
+    // Signed distance of the eye from the rig centre for omni-directional stereo.
+    ods_offset: f32,
 }
 
 impl Camera {
@@ -23,57 +113,363 @@ impl Camera {
         aperture: f32,
         focus_dist: f32,
     ) -> Camera {
-        // let mut u = Vec3::default();
-        // let mut v = Vec3::default();
-        // let mut w = Vec3::default();
-
         let lens_radius = aperture / 2.0;
 
-        let theta = fvof * std::f32::consts::PI / 180.0;
+        let theta = fvof * PI / 180.0;
         let half_height = (theta / 2.0).tan();
 
-        let half_width = aspect * half_height;
-
         let origin = look_from;
         let w = Vec3::unit_vector(&(look_from - look_at));
         let u = Vec3::unit_vector(&Vec3::cross(&vup, &w));
         let v = Vec3::cross(&w, &u);
 
-        Camera {
-            lower_left_corner: origin
-                - half_width * focus_dist * u
-                - half_height * focus_dist * v
-                - focus_dist * w,
-            horizontal: 2.0 * half_width * focus_dist * u,
-            vertical: 2.0 * half_height * focus_dist * v,
+        let mut camera = Camera {
+            lower_left_corner: Vec3::default(),
+            horizontal: Vec3::default(),
+            vertical: Vec3::default(),
             origin,
             lens_radius,
             u,
             v,
-            // w,
+            w,
+            half_height,
+            aspect,
+            focus_dist,
+            projection: Projection::Perspective,
+            aperture: Aperture::default(),
+            ods_offset: 0.0,
+        };
+        camera.update_frame();
+        camera
+    }
+
+    // Recomputes the image plane at the focus distance from the origin and basis.
+    fn update_frame(&mut self) {
+        let half_width = self.aspect * self.half_height;
+
+        self.lower_left_corner = self.origin
+            - half_width * self.focus_dist * self.u
+            - self.half_height * self.focus_dist * self.v
+            - self.focus_dist * self.w;
+        self.horizontal = 2.0 * half_width * self.focus_dist * self.u;
+        self.vertical = 2.0 * self.half_height * self.focus_dist * self.v;
+    }
+
+    /// Returns the same camera placement rendered through a different projection.
+    pub fn with_projection(mut self, projection: Projection) -> Camera {
+        self.projection = projection;
+        self
+    }
+
+    /// Returns the same camera with a differently shaped lens opening.
+    pub fn with_aperture(mut self, aperture: Aperture) -> Camera {
+        self.aperture = aperture;
+        self
+    }
+
+    /// Focal length implied by the field of view on a full-frame sensor.
+    pub fn focal_length(&self) -> f32 {
+        SENSOR_HEIGHT / 2.0 / self.half_height
+    }
+
+    /// The f-number: focal length over aperture diameter, both in metres. A
+    /// lens prescription has its own, and a pinhole, with no aperture to go
+    /// by, gets the default one.
+    pub fn f_number(&self) -> f32 {
+        if let Projection::Lens(system) = &self.projection {
+            if let Some(f_number) = system.f_number() {
+                return f_number;
+            }
+        }
+        if self.lens_radius <= 0.0 {
+            return exposure::DEFAULT_F_NUMBER;
         }
+        self.focal_length() / (2.0 * self.lens_radius)
     }
 
-    pub fn get_ray(self, s: f32, t: f32) -> Ray {
-        let rd = self.lens_radius * random_in_unit_disk();
-        let offset = self.u * rd.x() + self.v * rd.y();
+    /// Returns the camera with the aperture diameter set from an f-number.
+    pub fn with_f_number(mut self, f_number: f32) -> Camera {
+        self.lens_radius = self.focal_length() / (2.0 * f_number);
+        self
+    }
 
-        Ray::new(
-            self.origin + offset,
-            self.lower_left_corner + s * self.horizontal + t * self.vertical - self.origin - offset,
-        )
+    /// Returns the camera for one eye of a stereo rig centred on this camera.
+    ///
+    /// `convergence` is the distance of the zero-parallax plane: toed-in eyes
+    /// are rotated to look at it and off-axis eyes shear their frustum so that
+    /// both image windows coincide there.
+    pub fn eye(mut self, mode: StereoMode, interocular: f32, convergence: f32, eye: Eye) -> Camera {
+        let half = match eye {
+            Eye::Left => -interocular / 2.0,
+            Eye::Right => interocular / 2.0,
+        };
+        let centre = self.origin;
+
+        match mode {
+            StereoMode::Parallel => {
+                self.origin = centre + half * self.u;
+                self.update_frame();
+            }
+            StereoMode::ToedIn => {
+                let target = centre - convergence * self.w;
+                self.origin = centre + half * self.u;
+                self.w = Vec3::unit_vector(&(self.origin - target));
+                self.u = Vec3::unit_vector(&Vec3::cross(&self.v, &self.w));
+                self.v = Vec3::cross(&self.w, &self.u);
+                self.update_frame();
+            }
+            StereoMode::OffAxis => {
+                self.origin = centre + half * self.u;
+                self.update_frame();
+                self.lower_left_corner =
+                    self.lower_left_corner - (half * self.focus_dist / convergence) * self.u;
+            }
+            StereoMode::Ods => self.ods_offset = half,
+        }
+
+        self
+    }
+
+    /// Returns the ray through image coordinates `(s, t)` in `[0, 1]`, or `None`
+    /// when the point lies outside the projection's image area (the corners
+    /// around a circular fisheye). `lens` in the unit square picks the point on
+    /// the aperture the ray passes through.
+    pub fn get_ray(&self, s: f32, t: f32, lens: (f32, f32)) -> Option<Ray> {
+        match &self.projection {
+            Projection::Perspective => {
+                let rd = self.lens_radius * self.aperture.sample(lens);
+                let offset = self.u * rd.x() + self.v * rd.y();
+
+                Some(Ray::new(
+                    self.origin + offset,
+                    self.lower_left_corner + s * self.horizontal + t * self.vertical
+                        - self.origin
+                        - offset,
+                ))
+            }
+            Projection::Orthographic { height } => {
+                let half_height = *height / 2.0;
+                let half_width = self.aspect * half_height;
+                let base = self.origin
+                    + (2.0 * s - 1.0) * half_width * self.u
+                    + (2.0 * t - 1.0) * half_height * self.v;
+                let focus = base - self.focus_dist * self.w;
+
+                let rd = self.lens_radius * self.aperture.sample(lens);
+                let start = base + self.u * rd.x() + self.v * rd.y();
+
+                Some(Ray::new(start, focus - start))
+            }
+            Projection::Fisheye { fov, mapping } => {
+                // Fit the image circle to the shorter side of the frame.
+                let (x, y) = if self.aspect >= 1.0 {
+                    ((2.0 * s - 1.0) * self.aspect, 2.0 * t - 1.0)
+                } else {
+                    (2.0 * s - 1.0, (2.0 * t - 1.0) / self.aspect)
+                };
+                let r = (x * x + y * y).sqrt();
+                if r > 1.0 {
+                    return None;
+                }
+
+                let half_fov = *fov * PI / 360.0;
+                let theta = match *mapping {
+                    FisheyeMapping::Equidistant => r * half_fov,
+                    FisheyeMapping::Equisolid => 2.0 * (r * (half_fov / 2.0).sin()).asin(),
+                };
+                let phi = y.atan2(x);
+
+                let direction =
+                    theta.sin() * (phi.cos() * self.u + phi.sin() * self.v) - theta.cos() * self.w;
+                Some(self.focused_ray(direction, lens))
+            }
+            Projection::Equirectangular => {
+                let phi = (s - 0.5) * 2.0 * PI;
+                let theta = (t - 0.5) * PI;
+
+                let direction =
+                    theta.cos() * (phi.sin() * self.u - phi.cos() * self.w) + theta.sin() * self.v;
+                Some(Ray::new(self.ods_origin(phi), direction))
+            }
+            Projection::Cylindrical { hfov } => {
+                let phi = (s - 0.5) * *hfov * PI / 180.0;
+                let y = (2.0 * t - 1.0) * self.half_height;
+
+                let direction = phi.sin() * self.u - phi.cos() * self.w + y * self.v;
+                if self.ods_offset != 0.0 {
+                    return Some(Ray::new(self.ods_origin(phi), direction));
+                }
+                Some(self.focused_ray(direction, lens))
+            }
+            Projection::Lens(system) => {
+                let r = system.sample_ray(system.film_point(s, t), self.aperture.sample(lens))?;
+
+                // Camera space looks down +z, the world camera down -w.
+                let to_world = |p: Vec3| p.x() * self.u + p.y() * self.v - p.z() * self.w;
+                Some(Ray::new(
+                    self.origin + to_world(r.origin()),
+                    to_world(r.direction()),
+                ))
+            }
+        }
+    }
+
+    // Omni-directional stereo moves the eye around a circle so that it always
+    // sits beside the viewing direction at longitude `phi`.
+    fn ods_origin(&self, phi: f32) -> Vec3 {
+        self.origin + self.ods_offset * (phi.cos() * self.u + phi.sin() * self.w)
+    }
+
+    /// Thin-lens ray for non-planar projections: the lens is placed perpendicular
+    /// to `direction` and the ray converges at `focus_dist` along it.
+    fn focused_ray(&self, direction: Vec3, lens: (f32, f32)) -> Ray {
+        let direction = Vec3::unit_vector(&direction);
+        if self.lens_radius <= 0.0 {
+            return Ray::new(self.origin, direction);
+        }
+
+        let helper = if direction.y().abs() < 0.99 {
+            Vec3::new(0.0, 1.0, 0.0)
+        } else {
+            Vec3::new(1.0, 0.0, 0.0)
+        };
+        let a = Vec3::unit_vector(&Vec3::cross(&helper, &direction));
+        let b = Vec3::cross(&direction, &a);
+
+        let rd = self.lens_radius * self.aperture.sample(lens);
+        let start = self.origin + a * rd.x() + b * rd.y();
+        let focus = self.origin + self.focus_dist * direction;
+
+        Ray::new(start, focus - start)
     }
 }
 
-fn random_in_unit_disk() -> Vec3 {
-    let mut rng = rand::thread_rng();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pinhole() -> Camera {
+        Camera::new(
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, -1.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            90.0,
+            2.0,
+            0.0,
+            1.0,
+        )
+    }
 
-    loop {
-        let p = 2.0 * Vec3::new(rng.gen::<f32>(), rng.gen::<f32>(), 0.0) - Vec3::new(1.0, 1.0, 0.0);
+    fn assert_close(a: Vec3, b: Vec3) {
+        assert!((a - b).length() < 1e-4, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn test_parse_projection() {
+        assert_eq!(
+            "fisheye:190:equisolid".parse::<Projection>(),
+            Ok(Projection::Fisheye {
+                fov: 190.0,
+                mapping: FisheyeMapping::Equisolid
+            })
+        );
+        assert_eq!(
+            "ortho".parse::<Projection>(),
+            Ok(Projection::Orthographic { height: 4.0 })
+        );
+        assert!("fisheye:wide".parse::<Projection>().is_err());
+        assert!("spherical".parse::<Projection>().is_err());
+    }
 
-        // The dot product should be less than 1.0 to ensure the point is inside the unit disk
-        if Vec3::dot(&p, &p) < 1.0 {
-            return p;
+    #[test]
+    fn test_centre_rays_look_forward() {
+        let forward = Vec3::new(0.0, 0.0, -1.0);
+        for projection in [
+            Projection::Perspective,
+            Projection::Orthographic { height: 2.0 },
+            Projection::Fisheye {
+                fov: 180.0,
+                mapping: FisheyeMapping::Equidistant,
+            },
+            Projection::Equirectangular,
+            Projection::Cylindrical { hfov: 360.0 },
+        ] {
+            let ray = pinhole()
+                .with_projection(projection)
+                .get_ray(0.5, 0.5, (0.5, 0.5))
+                .unwrap();
+            assert_close(Vec3::unit_vector(&ray.direction()), forward);
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_equirectangular_wraps_behind() {
+        let camera = pinhole().with_projection(Projection::Equirectangular);
+        let ray = camera.get_ray(0.0, 0.5, (0.5, 0.5)).unwrap();
+        assert_close(
+            Vec3::unit_vector(&ray.direction()),
+            Vec3::new(0.0, 0.0, 1.0),
+        );
+    }
+
+    #[test]
+    fn test_f_number() {
+        // A 90 degree field of view on a 24mm sensor is a 12mm lens.
+        let camera = pinhole().with_f_number(4.0);
+        assert!((camera.focal_length() - 0.012).abs() < 1e-6);
+        assert!((camera.lens_radius - 0.0015).abs() < 1e-6);
+        assert!((camera.f_number() - 4.0).abs() < 1e-4);
+        assert_eq!(pinhole().f_number(), exposure::DEFAULT_F_NUMBER);
+    }
+
+    #[test]
+    fn test_stereo_eyes() {
+        let camera = pinhole();
+        let left = camera
+            .clone()
+            .eye(StereoMode::Parallel, 0.1, 5.0, Eye::Left);
+        let ray = left.get_ray(0.5, 0.5, (0.5, 0.5)).unwrap();
+        assert_close(ray.origin(), Vec3::new(-0.05, 0.0, 0.0));
+        assert_close(
+            Vec3::unit_vector(&ray.direction()),
+            Vec3::new(0.0, 0.0, -1.0),
+        );
+
+        // Off-axis and toed-in eyes both see the rig centre line at the convergence distance.
+        for mode in [StereoMode::OffAxis, StereoMode::ToedIn] {
+            let right = camera.clone().eye(mode, 0.1, 5.0, Eye::Right);
+            let ray = right.get_ray(0.5, 0.5, (0.5, 0.5)).unwrap();
+            let t = -5.0 / ray.direction().z();
+            assert_close(ray.point_at_parameter(t), Vec3::new(0.0, 0.0, -5.0));
+        }
+
+        let ods = camera.with_projection(Projection::Equirectangular).eye(
+            StereoMode::Ods,
+            0.1,
+            5.0,
+            Eye::Right,
+        );
+        assert_close(
+            ods.get_ray(0.5, 0.5, (0.5, 0.5)).unwrap().origin(),
+            Vec3::new(0.05, 0.0, 0.0),
+        );
+        assert_close(
+            ods.get_ray(0.0, 0.5, (0.5, 0.5)).unwrap().origin(),
+            Vec3::new(-0.05, 0.0, 0.0),
+        );
+    }
+
+    #[test]
+    fn test_fisheye_outside_circle() {
+        let camera = pinhole().with_projection(Projection::Fisheye {
+            fov: 180.0,
+            mapping: FisheyeMapping::Equidistant,
+        });
+        assert!(camera.get_ray(0.0, 0.0, (0.5, 0.5)).is_none());
+        let edge = camera.get_ray(0.75, 0.5, (0.5, 0.5)).unwrap();
+        assert_close(
+            Vec3::unit_vector(&edge.direction()),
+            Vec3::new(1.0, 0.0, 0.0),
+        );
+    }
+}
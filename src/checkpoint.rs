@@ -0,0 +1,222 @@
+use crate::render::{Render, RenderSettings};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+const MAGIC: &[u8; 8] = b"RTCHECK2";
+
+/// Saves the state of `renders` so a later run can continue them.
+///
+/// Besides the accumulated samples, the file records everything that decides
+/// which sample comes next: the sampler, its seed and each pixel's sample
+/// count. The file is written next to `path` first and then moved over it, so
+/// a crash while saving leaves the previous checkpoint intact.
+pub fn save<P: AsRef<Path>>(
+    path: P,
+    settings: &RenderSettings,
+    frame: &str,
+    renders: &[Render],
+) -> io::Result<()> {
+    let path = path.as_ref();
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+
+    let mut out = BufWriter::new(File::create(&partial)?);
+    out.write_all(MAGIC)?;
+    write_string(&mut out, &fingerprint(settings, frame))?;
+    write_u64(&mut out, renders.len() as u64)?;
+    for render in renders {
+        render.save(&mut out)?;
+    }
+    out.into_inner()?.sync_all()?;
+
+    fs::rename(&partial, path)
+}
+
+/// Loads renders saved by `save`. The checkpoint must come from a render with
+/// the same image size, filter, sampler, seed and AOVs, and of the same
+/// `frame`, which sums up the scene and cameras; the sample count may differ,
+/// so a finished render can be continued to more samples per pixel.
+/// The stratified sampler lays out its strata for the sample count, so
+/// continuing it to a different count loses some of its stratification.
+pub fn load<P: AsRef<Path>>(
+    path: P,
+    settings: &RenderSettings,
+    frame: &str,
+) -> io::Result<Vec<Render>> {
+    let mut input = BufReader::new(File::open(path)?);
+
+    let mut magic = [0; 8];
+    input.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid_data("not a render checkpoint".to_string()));
+    }
+    let saved = read_string(&mut input)?;
+    if saved != fingerprint(settings, frame) {
+        return Err(invalid_data(format!(
+            "checkpoint was made with different settings: {}",
+            saved
+        )));
+    }
+
+    let count = read_u64(&mut input)?;
+    (0..count)
+        .map(|_| {
+            let mut render = Render::new(settings);
+            render.load(&mut input)?;
+            Ok(render)
+        })
+        .collect()
+}
+
+// The settings a checkpoint has to agree with to be continued.
+fn fingerprint(settings: &RenderSettings, frame: &str) -> String {
+    format!(
+        "{}x{} {:?} {:?} {:?} {:?} {:?} seed {} aovs {:?} light paths {:?} {}",
+        settings.width,
+        settings.height,
+        settings.filter,
+        settings.sampler,
+        settings.integrator,
+        settings.bounces,
+        settings.fireflies,
+        settings.seed,
+        settings.aovs,
+        settings
+            .light_paths
+            .iter()
+            .map(|lpe| (lpe.name(), lpe.text()))
+            .collect::<Vec<_>>(),
+        frame
+    )
+}
+
+/// A 64-bit FNV-1a hash of `bytes`, to tell apart inputs too large to
+/// compare in full, such as scene files.
+pub fn digest(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+pub fn write_u64(out: &mut impl Write, v: u64) -> io::Result<()> {
+    out.write_all(&v.to_le_bytes())
+}
+
+pub fn write_f32(out: &mut impl Write, v: f32) -> io::Result<()> {
+    out.write_all(&v.to_le_bytes())
+}
+
+pub fn write_f64(out: &mut impl Write, v: f64) -> io::Result<()> {
+    out.write_all(&v.to_le_bytes())
+}
+
+pub fn write_string(out: &mut impl Write, s: &str) -> io::Result<()> {
+    write_u64(out, s.len() as u64)?;
+    out.write_all(s.as_bytes())
+}
+
+pub fn read_u64(input: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+pub fn read_f32(input: &mut impl Read) -> io::Result<f32> {
+    let mut bytes = [0; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(f32::from_le_bytes(bytes))
+}
+
+pub fn read_f64(input: &mut impl Read) -> io::Result<f64> {
+    let mut bytes = [0; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(f64::from_le_bytes(bytes))
+}
+
+pub fn read_string(input: &mut impl Read) -> io::Result<String> {
+    let len = read_u64(input)?;
+    let mut bytes = Vec::new();
+    input.take(len).read_to_end(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|_| invalid_data("corrupt checkpoint".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aov::Aov;
+    use crate::camera::Camera;
+    use crate::colorspace::ColorSpace;
+    use crate::firefly::Fireflies;
+    use crate::integrator::{Bounces, IntegratorKind};
+    use crate::mat3::Mat3;
+    use crate::sampler::SamplerKind;
+    use crate::scene::Scene;
+    use crate::tiles::{self, TileOrder};
+    use crate::vec3::Vec3;
+    use indicatif::ProgressBar;
+    use std::sync::atomic::AtomicBool;
+
+    #[test]
+    fn test_resume_matches_uninterrupted_render() {
+        let settings = RenderSettings {
+            width: 6,
+            height: 4,
+            samples: 6,
+            filter: "mitchell".parse().unwrap(),
+            sampler: SamplerKind::Halton,
+            integrator: IntegratorKind::Path,
+            bounces: Bounces::default(),
+            fireflies: Fireflies::default(),
+            seed: 3,
+            adaptive: None,
+            tiles: tiles::layout(6, 4, 4, TileOrder::Hilbert, None, None),
+            aovs: vec![Aov::Depth, Aov::ObjectId],
+            light_paths: Vec::new(),
+            luminance: ColorSpace::LinearSrgb.luminance_weights(),
+        };
+        let camera = Camera::new(
+            Vec3::new(13.0, 2.0, 3.0),
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            20.0,
+            1.5,
+            0.1,
+            10.0,
+        );
+        let scene = Scene::random(Mat3::IDENTITY, 1.0, 1);
+        let bar = ProgressBar::hidden();
+        let path = std::env::temp_dir().join(format!("checkpoint-{}.bin", std::process::id()));
+
+        let mut whole = Render::new(&settings);
+        whole.pass(&camera, &scene, &settings, 6, &bar, &AtomicBool::new(false));
+
+        let mut first = Render::new(&settings);
+        first.pass(&camera, &scene, &settings, 2, &bar, &AtomicBool::new(false));
+        save(&path, &settings, "frame", &[first]).unwrap();
+        let mut resumed = load(&path, &settings, "frame").unwrap();
+        resumed[0].pass(&camera, &scene, &settings, 4, &bar, &AtomicBool::new(false));
+
+        assert_eq!(resumed[0].counts(), whole.counts());
+        for (a, b) in whole.aov_images().iter().zip(resumed[0].aov_images()) {
+            for (a, b) in a.iter().zip(b) {
+                assert!((*a - b).length() <= 1e-5 * a.length().max(1.0));
+            }
+        }
+        for (a, b) in whole.image().iter().zip(resumed[0].image()) {
+            assert!((*a - b).length() < 1e-4);
+        }
+
+        assert!(load(&path, &settings, "other frame").is_err());
+        let other = RenderSettings {
+            seed: 4,
+            ..settings
+        };
+        assert!(load(&path, &other, "frame").is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,199 @@
+use crate::mat3::Mat3;
+use crate::vec3::Vec3;
+use std::str::FromStr;
+
+/// An RGB colour space defined by its primaries and white point.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorSpace {
+    /// Rec.709 primaries with a D65 white point.
+    LinearSrgb,
+    /// ACES AP1 primaries with the ACES (~D60) white point.
+    AcesCg,
+    /// Rec.2020 primaries with a D65 white point.
+    Rec2020,
+    /// DCI-P3 primaries with a D65 white point.
+    DisplayP3,
+}
+
+impl FromStr for ColorSpace {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "srgb" | "linear-srgb" | "rec709" => Ok(ColorSpace::LinearSrgb),
+            "acescg" => Ok(ColorSpace::AcesCg),
+            "rec2020" => Ok(ColorSpace::Rec2020),
+            "p3" | "display-p3" => Ok(ColorSpace::DisplayP3),
+            _ => Err(format!("unknown colour space '{}'", s)),
+        }
+    }
+}
+
+impl ColorSpace {
+    /// Name written into output files so other tools can interpret them.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ColorSpace::LinearSrgb => "sRGB",
+            ColorSpace::AcesCg => "ACEScg",
+            ColorSpace::Rec2020 => "Rec.2020",
+            ColorSpace::DisplayP3 => "Display P3",
+        }
+    }
+
+    /// CIE xy chromaticities of the red, green and blue primaries and white.
+    pub fn chromaticities(&self) -> [(f32, f32); 4] {
+        const D65: (f32, f32) = (0.3127, 0.3290);
+        match self {
+            ColorSpace::LinearSrgb => [(0.64, 0.33), (0.30, 0.60), (0.15, 0.06), D65],
+            ColorSpace::AcesCg => [
+                (0.713, 0.293),
+                (0.165, 0.830),
+                (0.128, 0.044),
+                (0.32168, 0.33767),
+            ],
+            ColorSpace::Rec2020 => [(0.708, 0.292), (0.170, 0.797), (0.131, 0.046), D65],
+            ColorSpace::DisplayP3 => [(0.680, 0.320), (0.265, 0.690), (0.150, 0.060), D65],
+        }
+    }
+
+    /// Matrix from linear RGB in this space to CIE XYZ.
+    pub fn to_xyz(self) -> Mat3 {
+        let [r, g, b, w] = self.chromaticities().map(|(x, y)| xy_to_xyz(x, y));
+        let primaries = Mat3::from_columns(r, g, b);
+        let scale = primaries.inverse().transform(w);
+        primaries * Mat3::diagonal(scale)
+    }
+
+    /// Weights giving the luminance of linear RGB in this space: the Y row of
+    /// `to_xyz`.
+    pub fn luminance_weights(self) -> Vec3 {
+        let xyz = self.to_xyz();
+        let y = |primary: Vec3| xyz.transform(primary).y();
+        Vec3::new(
+            y(Vec3::new(1.0, 0.0, 0.0)),
+            y(Vec3::new(0.0, 1.0, 0.0)),
+            y(Vec3::new(0.0, 0.0, 1.0)),
+        )
+    }
+
+    /// Whether 8-bit images can be written in this space. ACEScg is
+    /// scene-linear and only goes into EXR files.
+    pub fn is_display(&self) -> bool {
+        *self != ColorSpace::AcesCg
+    }
+
+    /// Matrix converting linear RGB in this space to linear RGB in `target`,
+    /// adapting the white point with the Bradford transform.
+    pub fn conversion_to(&self, target: ColorSpace) -> Mat3 {
+        if *self == target {
+            return Mat3::IDENTITY;
+        }
+
+        let source_white = xy_to_xyz(self.chromaticities()[3].0, self.chromaticities()[3].1);
+        let target_white = xy_to_xyz(target.chromaticities()[3].0, target.chromaticities()[3].1);
+        target.to_xyz().inverse() * bradford(source_white, target_white) * self.to_xyz()
+    }
+
+    /// Encodes a linear value in `[0, 1]` with the transfer function displays
+    /// expect for this space: the sRGB curve, or for Rec.2020 the BT.1886
+    /// display gamma of 2.4 rather than the camera curve. ACEScg is a
+    /// scene-linear space and stays linear.
+    pub fn encode(&self, x: f32) -> f32 {
+        match self {
+            ColorSpace::LinearSrgb | ColorSpace::DisplayP3 => {
+                if x <= 0.0031308 {
+                    12.92 * x
+                } else {
+                    1.055 * x.powf(1.0 / 2.4) - 0.055
+                }
+            }
+            ColorSpace::Rec2020 => x.max(0.0).powf(1.0 / 2.4),
+            ColorSpace::AcesCg => x,
+        }
+    }
+}
+
+fn xy_to_xyz(x: f32, y: f32) -> Vec3 {
+    Vec3::new(x / y, 1.0, (1.0 - x - y) / y)
+}
+
+// Chromatic adaptation between two white points in XYZ.
+fn bradford(source: Vec3, target: Vec3) -> Mat3 {
+    const BRADFORD: Mat3 = Mat3::new([
+        [0.8951, 0.2664, -0.1614],
+        [-0.7502, 1.7135, 0.0367],
+        [0.0389, -0.0685, 1.0296],
+    ]);
+
+    let s = BRADFORD.transform(source);
+    let t = BRADFORD.transform(target);
+    let gain = Mat3::diagonal(Vec3::new(t.x() / s.x(), t.y() / s.y(), t.z() / s.z()));
+    BRADFORD.inverse() * gain * BRADFORD
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: Vec3, b: Vec3, tolerance: f32) {
+        assert!((a - b).length() < tolerance, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn test_srgb_to_xyz() {
+        // The well known sRGB red primary row of the RGB to XYZ matrix.
+        let red = ColorSpace::LinearSrgb
+            .to_xyz()
+            .transform(Vec3::new(1.0, 0.0, 0.0));
+        assert_close(red, Vec3::new(0.4124, 0.2126, 0.0193), 1e-3);
+    }
+
+    #[test]
+    fn test_luminance_weights() {
+        let srgb = ColorSpace::LinearSrgb.luminance_weights();
+        assert_close(srgb, Vec3::new(0.2126, 0.7152, 0.0722), 1e-3);
+        let rec2020 = ColorSpace::Rec2020.luminance_weights();
+        assert_close(rec2020, Vec3::new(0.2627, 0.6780, 0.0593), 1e-3);
+        for space in [ColorSpace::AcesCg, ColorSpace::DisplayP3] {
+            let w = space.luminance_weights();
+            assert!((w.x() + w.y() + w.z() - 1.0).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_white_maps_to_white() {
+        let white = Vec3::new(1.0, 1.0, 1.0);
+        for target in [
+            ColorSpace::AcesCg,
+            ColorSpace::Rec2020,
+            ColorSpace::DisplayP3,
+        ] {
+            let m = ColorSpace::LinearSrgb.conversion_to(target);
+            assert_close(m.transform(white), white, 1e-3);
+        }
+    }
+
+    #[test]
+    fn test_transfer_functions() {
+        let srgb = ColorSpace::LinearSrgb;
+        assert_eq!(srgb.encode(0.0), 0.0);
+        assert!((srgb.encode(1.0) - 1.0).abs() < 1e-6);
+        assert!((srgb.encode(0.18) - 0.4613).abs() < 1e-3);
+        assert!((ColorSpace::Rec2020.encode(1.0) - 1.0).abs() < 1e-6);
+        assert!((ColorSpace::Rec2020.encode(0.18) - 0.4894).abs() < 1e-3);
+        assert_eq!(ColorSpace::AcesCg.encode(0.18), 0.18);
+    }
+
+    #[test]
+    fn test_srgb_to_acescg() {
+        let m = ColorSpace::LinearSrgb.conversion_to(ColorSpace::AcesCg);
+        assert_close(
+            m.transform(Vec3::new(1.0, 0.0, 0.0)),
+            Vec3::new(0.6131, 0.0702, 0.0206),
+            2e-3,
+        );
+        let back = ColorSpace::AcesCg.conversion_to(ColorSpace::LinearSrgb);
+        let c = Vec3::new(0.2, 0.5, 0.8);
+        assert_close(back.transform(m.transform(c)), c, 1e-4);
+    }
+}
@@ -0,0 +1,273 @@
+use crate::tonemap::luminance;
+use crate::vec3::Vec3;
+use rayon::prelude::*;
+
+/// B3-spline taps of the à-trous kernel.
+const KERNEL: [f32; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+/// Smallest albedo divided out of the image, so black surfaces keep their
+/// noise instead of blowing it up.
+const MIN_ALBEDO: f32 = 0.01;
+
+/// Edge-avoiding à-trous wavelet filter guided by variance, normals and
+/// albedo, after Dammertz et al. and Schied et al.'s SVGF.
+///
+/// Each iteration blurs with a 5x5 kernel whose taps are spread twice as far
+/// as in the last, weighted down across edges: where luminance differs by
+/// more than the noise explains, where normals turn away and where albedo
+/// changes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Denoiser {
+    pub iterations: usize,
+    /// Luminance differences, in standard deviations of the noise, that are
+    /// still smoothed over.
+    pub sigma_luminance: f32,
+    /// Exponent of the cosine between normals; higher keeps sharper creases.
+    pub sigma_normal: f32,
+    /// Albedo difference that is still smoothed over.
+    pub sigma_albedo: f32,
+}
+
+impl Default for Denoiser {
+    fn default() -> Self {
+        Denoiser {
+            iterations: 3,
+            sigma_luminance: 4.0,
+            sigma_normal: 128.0,
+            sigma_albedo: 0.1,
+        }
+    }
+}
+
+/// Per-pixel buffers steering the filter; missing ones are not used, except
+/// variance, which is then estimated from each pixel's neighbourhood.
+#[derive(Default)]
+pub struct Guides<'a> {
+    pub albedo: Option<&'a [Vec3]>,
+    pub normal: Option<&'a [Vec3]>,
+    /// Variance of each pixel's mean luminance.
+    pub variance: Option<&'a [f32]>,
+}
+
+impl Denoiser {
+    /// Filters a `width` x `height` image, rows top to bottom.
+    ///
+    /// With an albedo guide the filter works on illumination, the image divided
+    /// by albedo, and multiplies the albedo back afterwards, so texture is not
+    /// blurred along with the noise.
+    pub fn denoise(
+        &self,
+        color: &[Vec3],
+        guides: &Guides,
+        width: usize,
+        height: usize,
+    ) -> Vec<Vec3> {
+        let albedo: Option<Vec<Vec3>> = guides.albedo.map(|albedo| {
+            albedo
+                .iter()
+                .map(|a| {
+                    Vec3::new(
+                        a.x().max(MIN_ALBEDO),
+                        a.y().max(MIN_ALBEDO),
+                        a.z().max(MIN_ALBEDO),
+                    )
+                })
+                .collect()
+        });
+
+        let mut image: Vec<Vec3> = match &albedo {
+            Some(albedo) => color
+                .iter()
+                .zip(albedo)
+                .map(|(&c, &a)| divide(c, a))
+                .collect(),
+            None => color.to_vec(),
+        };
+        let mut variance: Vec<f32> = match (guides.variance, &albedo) {
+            (Some(variance), Some(albedo)) => variance
+                .iter()
+                .zip(albedo)
+                .map(|(&v, &a)| v / luminance(a).powi(2))
+                .collect(),
+            (Some(variance), None) => variance.to_vec(),
+            (None, _) => spatial_variance(&image, width, height),
+        };
+
+        for level in 0..self.iterations {
+            let (next_image, next_variance) =
+                self.step(&image, &variance, guides, width, height, 1 << level);
+            image = next_image;
+            variance = next_variance;
+        }
+
+        match &albedo {
+            Some(albedo) => image.iter().zip(albedo).map(|(&c, &a)| c * a).collect(),
+            None => image,
+        }
+    }
+
+    // One iteration with taps `step` pixels apart. The variance is filtered
+    // with the squared weights, as befits a weighted mean of noisy values.
+    fn step(
+        &self,
+        image: &[Vec3],
+        variance: &[f32],
+        guides: &Guides,
+        width: usize,
+        height: usize,
+        step: usize,
+    ) -> (Vec<Vec3>, Vec<f32>) {
+        let blurred = blur(variance, width, height);
+
+        (0..width * height)
+            .into_par_iter()
+            .map(|p| {
+                let (x, y) = ((p % width) as isize, (p / width) as isize);
+                let lp = luminance(image[p]);
+                let scale = self.sigma_luminance * blurred[p].max(0.0).sqrt() + 1e-6;
+
+                let (mut sum, mut sum_variance, mut total) = (Vec3::default(), 0.0, 0.0);
+                for (j, ky) in KERNEL.iter().enumerate() {
+                    for (i, kx) in KERNEL.iter().enumerate() {
+                        let qx = x + (i as isize - 2) * step as isize;
+                        let qy = y + (j as isize - 2) * step as isize;
+                        if qx < 0 || qy < 0 || qx >= width as isize || qy >= height as isize {
+                            continue;
+                        }
+                        let q = qy as usize * width + qx as usize;
+
+                        let mut w = kx * ky;
+                        if q != p {
+                            w *= (-(luminance(image[q]) - lp).abs() / scale).exp();
+                            if let Some(normal) = guides.normal {
+                                w *= self.normal_weight(normal[p], normal[q]);
+                            }
+                            if let Some(albedo) = guides.albedo {
+                                let d = (albedo[p] - albedo[q]).length();
+                                w *= (-d / self.sigma_albedo).exp();
+                            }
+                        }
+
+                        sum = sum + image[q] * w;
+                        sum_variance += w * w * variance[q];
+                        total += w;
+                    }
+                }
+                (sum / total, sum_variance / (total * total))
+            })
+            .unzip()
+    }
+
+    fn normal_weight(&self, a: Vec3, b: Vec3) -> f32 {
+        // Pixels that hit nothing have no normal; they only blend with each
+        // other. Normals averaged over a silhouette are shorter than one, so
+        // they are normalised first.
+        match (a.squared_length() > 0.0, b.squared_length() > 0.0) {
+            (false, false) => 1.0,
+            (true, true) => {
+                let cos = Vec3::dot(&Vec3::unit_vector(&a), &Vec3::unit_vector(&b));
+                cos.max(0.0).powf(self.sigma_normal)
+            }
+            _ => 0.0,
+        }
+    }
+}
+
+fn divide(a: Vec3, b: Vec3) -> Vec3 {
+    Vec3::new(a.x() / b.x(), a.y() / b.y(), a.z() / b.z())
+}
+
+// 3x3 Gaussian blur of a buffer, stabilising the variance estimate.
+fn blur(values: &[f32], width: usize, height: usize) -> Vec<f32> {
+    const TAPS: [f32; 3] = [0.25, 0.5, 0.25];
+    (0..width * height)
+        .map(|p| {
+            let (x, y) = (p % width, p / width);
+            let (mut sum, mut total) = (0.0, 0.0);
+            for (j, ky) in TAPS.iter().enumerate() {
+                for (i, kx) in TAPS.iter().enumerate() {
+                    let (Some(qx), Some(qy)) = ((x + i).checked_sub(1), (y + j).checked_sub(1))
+                    else {
+                        continue;
+                    };
+                    if qx < width && qy < height {
+                        sum += kx * ky * values[qy * width + qx];
+                        total += kx * ky;
+                    }
+                }
+            }
+            sum / total
+        })
+        .collect()
+}
+
+// Variance of luminance over each pixel's 3x3 neighbourhood, standing in for
+// the variance of its mean when the render's statistics are not available.
+fn spatial_variance(image: &[Vec3], width: usize, height: usize) -> Vec<f32> {
+    let luminances: Vec<f32> = image.iter().map(|&c| luminance(c)).collect();
+    let squares: Vec<f32> = luminances.iter().map(|l| l * l).collect();
+    let mean = blur(&luminances, width, height);
+    let mean_square = blur(&squares, width, height);
+    mean.iter()
+        .zip(mean_square)
+        .map(|(m, m2)| (m2 - m * m).max(0.0))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler;
+
+    // A left half at 0.2 and a right half at 0.8, with or without noise.
+    fn halves(noise: f32) -> Vec<Vec3> {
+        (0..32 * 32)
+            .map(|p| {
+                let base = if p % 32 < 16 { 0.2 } else { 0.8 };
+                let n = (sampler::to_unit(sampler::hash(&[p as u64])) - 0.5) * noise;
+                Vec3::new(base + n, base + n, base + n)
+            })
+            .collect()
+    }
+
+    fn error(a: &[Vec3], b: &[Vec3]) -> f32 {
+        a.iter()
+            .zip(b)
+            .map(|(a, b)| (*a - *b).squared_length())
+            .sum::<f32>()
+            / a.len() as f32
+    }
+
+    #[test]
+    fn test_removes_noise_and_keeps_edges() {
+        let (clean, noisy) = (halves(0.0), halves(0.2));
+        let variance = vec![0.2 * 0.2 / 12.0; 32 * 32];
+        let guides = Guides {
+            variance: Some(&variance),
+            ..Guides::default()
+        };
+        let denoised = Denoiser::default().denoise(&noisy, &guides, 32, 32);
+
+        assert!(error(&denoised, &clean) < error(&noisy, &clean) / 5.0);
+        // Pixels next to the edge stay on their side of it.
+        let row = &denoised[16 * 32..17 * 32];
+        assert!(
+            row[15].x() < 0.35 && row[16].x() > 0.65,
+            "{:?}",
+            &row[14..18]
+        );
+    }
+
+    #[test]
+    fn test_albedo_keeps_texture() {
+        let albedo = halves(0.0);
+        let lit = vec![Vec3::new(2.0, 2.0, 2.0); 32 * 32];
+        let color: Vec<Vec3> = albedo.iter().zip(&lit).map(|(&a, &l)| a * l).collect();
+        let guides = Guides {
+            albedo: Some(&albedo),
+            ..Guides::default()
+        };
+        let denoised = Denoiser::default().denoise(&color, &guides, 32, 32);
+        assert!(error(&denoised, &color) < 1e-8);
+    }
+}
@@ -0,0 +1,616 @@
+use crate::checkpoint::{read_string, read_u64, write_string, write_u64};
+use crate::render::{Job, Render, RenderSettings, TileResult};
+use indicatif::ProgressBar;
+use rayon::prelude::*;
+use rayon::ThreadPool;
+use std::collections::VecDeque;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+const MAGIC: &[u8; 8] = b"RTWORK04";
+
+/// How long either side waits for the other to connect, set up or take a
+/// message, and the least a coordinator waits for a batch.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a batch may take beyond `TIMEOUT` for every sample one worker
+/// thread takes; far more than any integrator needs.
+const PER_SAMPLE: Duration = Duration::from_millis(1);
+
+/// How long a batch may take beyond that for every photon a worker shoots
+/// before rendering it; again far more than needed.
+const PER_PHOTON: Duration = Duration::from_micros(100);
+
+/// How long a worker waits for the next batch. The coordinator may be waiting
+/// for the slowest of the other workers, or writing snapshots, in between.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(3600);
+
+/// Serves coordinators connecting to `listener` until the process is killed.
+///
+/// A coordinator first sends its command line, from which `setup` rebuilds
+/// the frame, then asks for batches of tiles. Each request carries the
+/// statistics of the tiles' pixels, so a worker keeps no state between
+/// requests and any worker can take over any tile.
+///
+/// Scene, lens and mask files are read from the worker's own disk, so
+/// `setup` also returns the summary of the frame it built, as `main::setup`
+/// does for checkpoints. Coordinators whose frame differs are turned away.
+pub fn serve<F>(listener: TcpListener, pool: &ThreadPool, setup: F) -> io::Result<()>
+where
+    F: Fn(&[String]) -> io::Result<(Job, String)> + Sync,
+{
+    let setup = &setup;
+    thread::scope(|scope| {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    scope.spawn(move || {
+                        if let Err(e) = handle(stream, pool, setup) {
+                            eprintln!("worker: connection closed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => eprintln!("worker: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+fn handle<F>(stream: TcpStream, pool: &ThreadPool, setup: &F) -> io::Result<()>
+where
+    F: Fn(&[String]) -> io::Result<(Job, String)>,
+{
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let mut input = BufReader::new(stream.try_clone()?);
+    let mut output = BufWriter::new(stream);
+
+    let mut magic = [0; 8];
+    input.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a render coordinator",
+        ));
+    }
+    let args = (0..read_u64(&mut input)?)
+        .map(|_| read_string(&mut input))
+        .collect::<io::Result<Vec<String>>>()?;
+    let expected = read_string(&mut input)?;
+
+    // Only the outcome goes back: set-up errors can quote local files.
+    let refuse = |output: &mut BufWriter<TcpStream>, reason: &str| {
+        write_u64(output, 1)?;
+        write_string(output, reason)?;
+        output.flush()
+    };
+    let job = match setup(&args) {
+        Ok((job, frame)) if frame == expected => {
+            write_u64(&mut output, 0)?;
+            write_u64(&mut output, pool.current_num_threads() as u64)?;
+            output.flush()?;
+            job
+        }
+        Ok(_) => {
+            refuse(&mut output, "the worker's scene or camera differs")?;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the coordinator's scene or camera differs",
+            ));
+        }
+        Err(e) => {
+            refuse(&mut output, "the worker could not set up the frame")?;
+            return Err(e);
+        }
+    };
+    let Job {
+        scene,
+        cameras,
+        settings,
+    } = &job;
+    let mut renders: Vec<Render> = cameras.iter().map(|_| Render::new(settings)).collect();
+
+    loop {
+        input.get_ref().set_read_timeout(Some(IDLE_TIMEOUT))?;
+        let count = read_u64(&mut input)?;
+        if count == 0 {
+            return Ok(());
+        }
+        input.get_ref().set_read_timeout(Some(TIMEOUT))?;
+        let samples = read_u64(&mut input)? as usize;
+
+        let mut batch = Vec::new();
+        for _ in 0..count {
+            let eye = read_u64(&mut input)? as usize;
+            let tile = settings.tiles.get(read_u64(&mut input)? as usize);
+            let (Some(render), Some(tile)) = (renders.get_mut(eye), tile) else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "request for an unknown tile",
+                ));
+            };
+            render.load_tile_stats(tile, &mut input)?;
+            batch.push((eye, tile));
+        }
+
+        // Batches are rendered in full; a coordinator cancels by sending no
+        // more of them.
+        let cancel = AtomicBool::new(false);
+        let results: Vec<TileResult> = pool.install(|| {
+            for (eye, render) in renders.iter().enumerate() {
+                let tiles = batch.iter().filter(|(e, _)| *e == eye);
+                render.prepare(scene, settings, tiles.map(|(_, tile)| *tile), samples);
+            }
+            batch
+                .par_iter()
+                .map(|&(eye, tile)| {
+                    let camera = &cameras[eye];
+                    renders[eye].render_tile(camera, scene, settings, tile, samples, &cancel)
+                })
+                .collect()
+        });
+        for result in &results {
+            result.save(&mut output)?;
+        }
+        output.flush()?;
+    }
+}
+
+/// The command line to send to workers: everything but the worker list.
+pub fn forwarded_args(args: &[String]) -> Vec<String> {
+    let mut forwarded = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--workers" {
+            args.next();
+        } else {
+            forwarded.push(arg.clone());
+        }
+    }
+    forwarded
+}
+
+/// Connections to the worker processes sharing a render.
+pub struct Coordinator {
+    workers: Vec<Worker>,
+}
+
+struct Worker {
+    address: String,
+    input: BufReader<TcpStream>,
+    output: BufWriter<TcpStream>,
+    threads: usize,
+    /// The least time to wait for a batch.
+    timeout: Duration,
+}
+
+impl Coordinator {
+    /// Connects to the workers at `addresses` and has each set up the frame
+    /// described by `args`, which must come out as `frame`. Workers that
+    /// cannot be reached, fail to set up or build a different frame are
+    /// reported and left out.
+    pub fn connect(addresses: &[String], args: &[String], frame: &str) -> io::Result<Coordinator> {
+        let workers: Vec<Worker> = addresses
+            .iter()
+            .filter_map(|address| match Worker::connect(address, args, frame) {
+                Ok(worker) => Some(worker),
+                Err(e) => {
+                    eprintln!("worker {}: {}", address, e);
+                    None
+                }
+            })
+            .collect();
+
+        if workers.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "no worker could be reached",
+            ));
+        }
+        Ok(Coordinator { workers })
+    }
+
+    /// Like `Render::pass` for every image, but rendered by the workers.
+    ///
+    /// Each worker takes batches of tiles as fast as it returns them. When a
+    /// worker fails, or takes far longer over a batch than it should, its
+    /// batch goes back into the queue for the others and the worker is
+    /// dropped. Results are merged in tile order once all are in, so
+    /// the image is the same as a local render. Once `cancel` is set no more
+    /// batches are sent.
+    pub fn pass(
+        &mut self,
+        renders: &mut [Render],
+        settings: &RenderSettings,
+        samples: usize,
+        bar: &ProgressBar,
+        cancel: &AtomicBool,
+    ) -> io::Result<()> {
+        let step = renders
+            .iter()
+            .filter_map(Render::max_pass_samples)
+            .fold(samples, usize::min)
+            .max(1);
+        let mut left = samples;
+        while left > 0 && !cancel.load(Ordering::Relaxed) {
+            let samples = left.min(step);
+            left -= samples;
+            self.step(renders, settings, samples, bar, cancel)?;
+        }
+        Ok(())
+    }
+
+    // One step of a pass, of no more samples than every integrator prepares.
+    fn step(
+        &mut self,
+        renders: &mut [Render],
+        settings: &RenderSettings,
+        samples: usize,
+        bar: &ProgressBar,
+        cancel: &AtomicBool,
+    ) -> io::Result<()> {
+        for render in renders.iter_mut() {
+            render.redistribute(settings);
+        }
+        let mut queue: VecDeque<(usize, usize)> = (0..renders.len())
+            .flat_map(|eye| (0..settings.tiles.len()).map(move |tile| (eye, tile)))
+            .collect();
+        let mut results = Vec::new();
+
+        while !queue.is_empty() && !cancel.load(Ordering::Relaxed) {
+            if self.workers.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "every worker failed",
+                ));
+            }
+
+            let shared = Mutex::new(queue);
+            let renders: &[Render] = renders;
+            let outcomes: Vec<io::Result<()>> = thread::scope(|scope| {
+                let handles: Vec<_> = self
+                    .workers
+                    .iter_mut()
+                    .map(|worker| {
+                        scope.spawn(|| worker.run(&shared, renders, settings, samples, bar, cancel))
+                    })
+                    .collect();
+                let mut outcomes = Vec::new();
+                for handle in handles {
+                    let (done, outcome) = handle.join().unwrap();
+                    results.extend(done);
+                    outcomes.push(outcome);
+                }
+                outcomes
+            });
+
+            let mut outcomes = outcomes.into_iter();
+            self.workers
+                .retain(|worker| match outcomes.next().unwrap() {
+                    Ok(()) => true,
+                    Err(e) => {
+                        eprintln!(
+                            "worker {} failed, reassigning its tiles: {}",
+                            worker.address, e
+                        );
+                        false
+                    }
+                });
+            queue = shared.into_inner().unwrap();
+        }
+
+        results.sort_by_key(|(eye, result): &(usize, TileResult)| (*eye, result.tile().index));
+        for (eye, result) in results {
+            renders[eye].merge(result);
+        }
+        Ok(())
+    }
+}
+
+impl Worker {
+    fn connect(address: &str, args: &[String], frame: &str) -> io::Result<Worker> {
+        let socket = address.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "address resolves to nothing")
+        })?;
+        let stream = TcpStream::connect_timeout(&socket, TIMEOUT)?;
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        let mut input = BufReader::new(stream.try_clone()?);
+        let mut output = BufWriter::new(stream);
+
+        output.write_all(MAGIC)?;
+        write_u64(&mut output, args.len() as u64)?;
+        for arg in args {
+            write_string(&mut output, arg)?;
+        }
+        write_string(&mut output, frame)?;
+        output.flush()?;
+
+        if read_u64(&mut input)? != 0 {
+            return Err(io::Error::other(read_string(&mut input)?));
+        }
+        let threads = read_u64(&mut input)?.max(1) as usize;
+
+        Ok(Worker {
+            address: address.to_string(),
+            input,
+            output,
+            threads,
+            timeout: TIMEOUT,
+        })
+    }
+
+    /// Renders batches from `queue` until it is empty or `cancel` is set,
+    /// returning the finished tiles along with how the worker fared. A failed
+    /// batch is put back before the error is returned.
+    fn run(
+        &mut self,
+        queue: &Mutex<VecDeque<(usize, usize)>>,
+        renders: &[Render],
+        settings: &RenderSettings,
+        samples: usize,
+        bar: &ProgressBar,
+        cancel: &AtomicBool,
+    ) -> (Vec<(usize, TileResult)>, io::Result<()>) {
+        let mut done = Vec::new();
+        while !cancel.load(Ordering::Relaxed) {
+            let batch: Vec<(usize, usize)> = {
+                let mut queue = queue.lock().unwrap();
+                let n = self.threads.min(queue.len());
+                queue.drain(..n).collect()
+            };
+            if batch.is_empty() {
+                break;
+            }
+
+            match self.request(&batch, renders, settings, samples) {
+                Ok(results) => {
+                    bar.inc(results.iter().map(|(_, r)| r.taken()).sum());
+                    done.extend(results);
+                }
+                Err(e) => {
+                    queue.lock().unwrap().extend(batch);
+                    return (done, Err(e));
+                }
+            }
+        }
+        (done, Ok(()))
+    }
+
+    fn request(
+        &mut self,
+        batch: &[(usize, usize)],
+        renders: &[Render],
+        settings: &RenderSettings,
+        samples: usize,
+    ) -> io::Result<Vec<(usize, TileResult)>> {
+        write_u64(&mut self.output, batch.len() as u64)?;
+        write_u64(&mut self.output, samples as u64)?;
+        for &(eye, tile) in batch {
+            write_u64(&mut self.output, eye as u64)?;
+            write_u64(&mut self.output, tile as u64)?;
+            renders[eye].save_tile_stats(&settings.tiles[tile], &mut self.output)?;
+        }
+        self.output.flush()?;
+
+        // The results only come once the whole batch is rendered, so the wait
+        // grows with the biggest tile a worker thread takes, and with the
+        // photon maps of every sample index traced before any tile.
+        let area = batch.iter().map(|&(_, tile)| settings.tiles[tile].area());
+        let work = area.max().unwrap_or(0) * samples;
+        let photons = settings.integrator.photons().saturating_mul(samples);
+        let allowance =
+            |per: Duration, count: usize| per.saturating_mul(count.try_into().unwrap_or(u32::MAX));
+        let timeout = self.timeout + allowance(PER_SAMPLE, work) + allowance(PER_PHOTON, photons);
+        self.input.get_ref().set_read_timeout(Some(timeout))?;
+        batch
+            .iter()
+            .map(|&(eye, tile)| {
+                let result = renders[eye].load_tile(&settings.tiles[tile], &mut self.input);
+                match result {
+                    Ok(result) => Ok((eye, result)),
+                    Err(e) if is_timeout(&e) => Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("no answer after {:.0?}", timeout),
+                    )),
+                    Err(e) => Err(e),
+                }
+            })
+            .collect()
+    }
+}
+
+// Sockets report a read timeout as `WouldBlock` on some platforms.
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+    )
+}
+
+impl Drop for Worker {
+    // Tells the worker the render is over; it may already be gone.
+    fn drop(&mut self) {
+        let _ = write_u64(&mut self.output, 0).and_then(|_| self.output.flush());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aov::Aov;
+    use crate::camera::Camera;
+    use crate::colorspace::ColorSpace;
+    use crate::firefly::Fireflies;
+    use crate::integrator::{Bounces, IntegratorKind};
+    use crate::lpe::{self, Lpe};
+    use crate::mat3::Mat3;
+    use crate::sampler::SamplerKind;
+    use crate::scene::Scene;
+    use crate::tiles::{self, TileOrder};
+    use crate::vec3::Vec3;
+    use rayon::ThreadPoolBuilder;
+
+    fn job() -> Job {
+        let settings = RenderSettings {
+            width: 10,
+            height: 6,
+            samples: 4,
+            filter: "gaussian".parse().unwrap(),
+            sampler: SamplerKind::Sobol,
+            integrator: IntegratorKind::Path,
+            bounces: Bounces::default(),
+            fireflies: Fireflies::default(),
+            seed: 5,
+            adaptive: None,
+            tiles: tiles::layout(10, 6, 4, TileOrder::Spiral, None, None),
+            aovs: Aov::ALL.to_vec(),
+            light_paths: lpe::COMPONENTS
+                .iter()
+                .map(|(name, text)| Lpe::new(name, text, &[String::from("sky")]).unwrap())
+                .collect(),
+            luminance: ColorSpace::LinearSrgb.luminance_weights(),
+        };
+        let camera = Camera::new(
+            Vec3::new(13.0, 2.0, 3.0),
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            20.0,
+            10.0 / 6.0,
+            0.1,
+            10.0,
+        );
+        Job {
+            scene: Scene::random(Mat3::IDENTITY, 1.0, 2),
+            cameras: vec![camera],
+            settings,
+        }
+    }
+
+    fn start_worker(frame: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let pool = ThreadPoolBuilder::new().num_threads(2).build().unwrap();
+            serve(listener, &pool, |_| Ok((job(), frame.to_string())))
+        });
+        address
+    }
+
+    // Accepts the set-up, then hangs up on the first request.
+    fn start_failing_worker() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut input = BufReader::new(stream.try_clone().unwrap());
+            let mut output = BufWriter::new(stream);
+            let mut magic = [0; 8];
+            input.read_exact(&mut magic).unwrap();
+            for _ in 0..read_u64(&mut input).unwrap() {
+                read_string(&mut input).unwrap();
+            }
+            read_string(&mut input).unwrap();
+            write_u64(&mut output, 0).unwrap();
+            write_u64(&mut output, 3).unwrap();
+            output.flush().unwrap();
+            read_u64(&mut input).unwrap();
+        });
+        address
+    }
+
+    // Accepts the set-up and the first request, then never answers.
+    fn start_hanging_worker() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut input = BufReader::new(stream.try_clone().unwrap());
+            let mut output = BufWriter::new(stream);
+            let mut magic = [0; 8];
+            input.read_exact(&mut magic).unwrap();
+            for _ in 0..read_u64(&mut input).unwrap() {
+                read_string(&mut input).unwrap();
+            }
+            read_string(&mut input).unwrap();
+            write_u64(&mut output, 0).unwrap();
+            write_u64(&mut output, 1).unwrap();
+            output.flush().unwrap();
+            read_u64(&mut input).unwrap();
+            thread::sleep(Duration::from_secs(3600));
+        });
+        address
+    }
+
+    #[test]
+    fn test_workers_match_local_render() {
+        let job = job();
+        let bar = ProgressBar::hidden();
+
+        let mut local = Render::new(&job.settings);
+        local.pass(
+            &job.cameras[0],
+            &job.scene,
+            &job.settings,
+            4,
+            &bar,
+            &AtomicBool::new(false),
+        );
+
+        // An address nobody listens on.
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap();
+        let unreachable = closed.local_addr().unwrap().to_string();
+        drop(closed);
+
+        let addresses = [
+            start_worker("frame"),
+            start_failing_worker(),
+            unreachable,
+            start_worker("frame"),
+            start_worker("another frame"),
+            start_hanging_worker(),
+        ];
+        let mut coordinator = Coordinator::connect(&addresses, &[], "frame").unwrap();
+        assert_eq!(coordinator.workers.len(), 4);
+        for worker in &mut coordinator.workers {
+            worker.timeout = Duration::from_millis(500);
+        }
+
+        let mut remote = vec![Render::new(&job.settings)];
+        while !remote[0].is_done(&job.settings) {
+            coordinator
+                .pass(&mut remote, &job.settings, 2, &bar, &AtomicBool::new(false))
+                .unwrap();
+        }
+        assert_eq!(coordinator.workers.len(), 2);
+        assert_eq!(remote[0].counts(), local.counts());
+        for (a, b) in remote[0].image().iter().zip(local.image()) {
+            assert!((*a - b).length() < 1e-5);
+        }
+        let buffers = |render: &Render| {
+            let mut buffers = render.aov_images();
+            buffers.extend(render.light_path_images());
+            buffers
+        };
+        for (a, b) in buffers(&local).iter().zip(buffers(&remote[0])) {
+            for (a, b) in a.iter().zip(b) {
+                assert!((*a - b).length() <= 1e-5 * a.length().max(1.0));
+            }
+        }
+    }
+
+    #[test]
+    fn test_forwarded_args() {
+        let args: Vec<String> = ["out.ppm", "--workers", "a:1,b:2", "--seed", "3"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        assert_eq!(forwarded_args(&args), ["out.ppm", "--seed", "3"]);
+    }
+}
@@ -0,0 +1,130 @@
+use crate::vec3::Vec3;
+
+/// F-number of the thin lens when physical exposure is used without one, and
+/// of a pinhole camera, whose aperture gives none.
+pub const DEFAULT_F_NUMBER: f32 = 8.0;
+
+/// Camera settings that turn scene luminance (cd/m²) into relative sensor
+/// exposure, following the saturation-based ISO speed convention.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Exposure {
+    pub iso: f32,
+    /// Shutter time in seconds.
+    pub shutter: f32,
+    pub f_number: f32,
+}
+
+impl Exposure {
+    /// Exposure value at ISO 100 for these settings.
+    pub fn ev100(&self) -> f32 {
+        (self.f_number * self.f_number / self.shutter * 100.0 / self.iso).log2()
+    }
+
+    /// Factor mapping luminance to exposure, where 1.0 is the saturation point
+    /// of the sensor.
+    pub fn scale(&self) -> f32 {
+        1.0 / (1.2 * 2.0f32.powf(self.ev100()))
+    }
+}
+
+/// Parses a shutter time given either in seconds (`0.008`) or as a fraction
+/// (`1/125`).
+pub fn parse_shutter(value: &str) -> Option<f32> {
+    let seconds = match value.split_once('/') {
+        Some((n, d)) => n.trim().parse::<f32>().ok()? / d.trim().parse::<f32>().ok()?,
+        None => value.parse().ok()?,
+    };
+    (seconds > 0.0 && seconds.is_finite()).then_some(seconds)
+}
+
+/// Per-channel gains that make light from a black body at `kelvin` appear
+/// neutral, relative to the D65 white point of linear sRGB. Green is kept at 1.
+pub fn white_balance(kelvin: f32) -> Vec3 {
+    let reference = blackbody_rgb(6504.0);
+    let source = blackbody_rgb(kelvin);
+    let gains = Vec3::new(
+        reference.r() / source.r(),
+        reference.g() / source.g(),
+        reference.b() / source.b(),
+    );
+    gains / gains.g()
+}
+
+/// Linear sRGB colour of a black body radiator, with arbitrary scale.
+fn blackbody_rgb(kelvin: f32) -> Vec3 {
+    const C1: f64 = 3.741_771_852e-16;
+    const C2: f64 = 1.438_776_877e-2;
+
+    let kelvin = kelvin.clamp(1000.0, 40000.0) as f64;
+    let (mut x, mut y, mut z) = (0.0, 0.0, 0.0);
+
+    for nm in (380..=780).step_by(5) {
+        let lambda = nm as f64 * 1e-9;
+        let radiance = C1 / (lambda.powi(5) * ((C2 / (lambda * kelvin)).exp() - 1.0));
+        let (cx, cy, cz) = cie_1931(nm as f64);
+        x += radiance * cx;
+        y += radiance * cy;
+        z += radiance * cz;
+    }
+
+    let (x, y, z) = (x / y, 1.0, z / y);
+    Vec3::new(
+        (3.2404542 * x - 1.5371385 * y - 0.4985314 * z) as f32,
+        (-0.9692660 * x + 1.8760108 * y + 0.0415560 * z) as f32,
+        (0.0556434 * x - 0.2040259 * y + 1.0572252 * z) as f32,
+    )
+}
+
+// Multi-lobe Gaussian fit of the CIE 1931 2° colour matching functions
+// (Wyman, Sloan and Shirley 2013).
+fn cie_1931(nm: f64) -> (f64, f64, f64) {
+    let g = |x: f64, mu: f64, s1: f64, s2: f64| {
+        let s = if x < mu { s1 } else { s2 };
+        (-0.5 * ((x - mu) / s).powi(2)).exp()
+    };
+
+    let x = 1.056 * g(nm, 599.8, 37.9, 31.0) + 0.362 * g(nm, 442.0, 16.0, 26.7)
+        - 0.065 * g(nm, 501.1, 20.4, 26.2);
+    let y = 0.821 * g(nm, 568.8, 46.9, 40.5) + 0.286 * g(nm, 530.9, 16.3, 31.1);
+    let z = 1.217 * g(nm, 437.0, 11.8, 36.0) + 0.681 * g(nm, 459.0, 26.0, 13.8);
+    (x, y, z)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sunny_16() {
+        // f/16 at 1/100s and ISO 100 is EV 14.6, close to bright sunlight.
+        let exposure = Exposure {
+            iso: 100.0,
+            shutter: 0.01,
+            f_number: 16.0,
+        };
+        assert!((exposure.ev100() - 14.64).abs() < 0.01);
+        // Doubling the ISO doubles the exposure.
+        let brighter = Exposure {
+            iso: 200.0,
+            ..exposure
+        };
+        assert!((brighter.scale() / exposure.scale() - 2.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_parse_shutter() {
+        assert_eq!(parse_shutter("1/125"), Some(0.008));
+        assert_eq!(parse_shutter("2"), Some(2.0));
+        assert_eq!(parse_shutter("1/0"), None);
+        assert_eq!(parse_shutter("fast"), None);
+    }
+
+    #[test]
+    fn test_white_balance() {
+        let neutral = white_balance(6504.0);
+        assert!((neutral - Vec3::new(1.0, 1.0, 1.0)).length() < 1e-4);
+        // Balancing for warm tungsten light cools the image down.
+        let tungsten = white_balance(3200.0);
+        assert!(tungsten.b() > 1.0 && tungsten.r() < 1.0);
+    }
+}
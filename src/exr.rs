@@ -0,0 +1,368 @@
+use crate::vec3::Vec3;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+const MAGIC: [u8; 4] = [0x76, 0x2f, 0x31, 0x01];
+const FLOAT: i32 = 2;
+
+/// One channel of 32-bit float values, rows top to bottom.
+pub struct Channel {
+    pub name: String,
+    pub values: Vec<f32>,
+}
+
+impl Channel {
+    /// Channels named `names` holding the x, y and z components of `pixels`,
+    /// as many as there are names.
+    pub fn from_pixels(names: &[&str], pixels: &[Vec3]) -> Vec<Channel> {
+        names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| Channel {
+                name: name.to_string(),
+                values: pixels.iter().map(|p| [p.x(), p.y(), p.z()][i]).collect(),
+            })
+            .collect()
+    }
+}
+
+/// A decoded OpenEXR image.
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub channels: Vec<Channel>,
+}
+
+impl Image {
+    /// Pixels gathered from the channels named `names` into x, y and z;
+    /// `None` if one is missing. Missing components are zero.
+    pub fn pixels(&self, names: &[&str]) -> Option<Vec<Vec3>> {
+        let channels: Vec<&[f32]> = names
+            .iter()
+            .map(|name| {
+                self.channels
+                    .iter()
+                    .find(|c| c.name == *name)
+                    .map(|c| &c.values[..])
+            })
+            .collect::<Option<_>>()?;
+        let component = |c: usize, i: usize| channels.get(c).map_or(0.0, |values| values[i]);
+        Some(
+            (0..self.width * self.height)
+                .map(|i| Vec3::new(component(0, i), component(1, i), component(2, i)))
+                .collect(),
+        )
+    }
+}
+
+/// Writes a single-part scanline OpenEXR file with uncompressed 32-bit float
+/// channels. Readers expect channels in alphabetical order, so they are
+/// sorted by name.
+pub fn write<P: AsRef<Path>>(
+    path: P,
+    width: usize,
+    height: usize,
+    channels: &[Channel],
+) -> io::Result<()> {
+    let mut channels: Vec<&Channel> = channels.iter().collect();
+    channels.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&MAGIC);
+    header.extend_from_slice(&2u32.to_le_bytes());
+
+    let mut list = Vec::new();
+    for channel in &channels {
+        list.extend_from_slice(channel.name.as_bytes());
+        list.push(0);
+        list.extend_from_slice(&FLOAT.to_le_bytes());
+        list.extend_from_slice(&[0; 4]); // pLinear and reserved
+        list.extend_from_slice(&1i32.to_le_bytes());
+        list.extend_from_slice(&1i32.to_le_bytes());
+    }
+    list.push(0);
+    attribute(&mut header, "channels", "chlist", &list);
+    attribute(&mut header, "compression", "compression", &[0]);
+    let window: Vec<u8> = [0, 0, width as i32 - 1, height as i32 - 1]
+        .iter()
+        .flat_map(|v| v.to_le_bytes())
+        .collect();
+    attribute(&mut header, "dataWindow", "box2i", &window);
+    attribute(&mut header, "displayWindow", "box2i", &window);
+    attribute(&mut header, "lineOrder", "lineOrder", &[0]);
+    attribute(
+        &mut header,
+        "pixelAspectRatio",
+        "float",
+        &1f32.to_le_bytes(),
+    );
+    attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8]);
+    attribute(
+        &mut header,
+        "screenWindowWidth",
+        "float",
+        &1f32.to_le_bytes(),
+    );
+    header.push(0);
+
+    // One scanline per block, each a line number, a size and the line of every
+    // channel in turn.
+    let line_size = channels.len() * width * 4;
+    let first = header.len() + height * 8;
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(&header)?;
+    for y in 0..height {
+        let offset = first + y * (8 + line_size);
+        file.write_all(&(offset as u64).to_le_bytes())?;
+    }
+    for y in 0..height {
+        file.write_all(&(y as i32).to_le_bytes())?;
+        file.write_all(&(line_size as i32).to_le_bytes())?;
+        for channel in &channels {
+            for v in &channel.values[y * width..(y + 1) * width] {
+                file.write_all(&v.to_le_bytes())?;
+            }
+        }
+    }
+    file.flush()
+}
+
+fn attribute(header: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]) {
+    header.extend_from_slice(name.as_bytes());
+    header.push(0);
+    header.extend_from_slice(kind.as_bytes());
+    header.push(0);
+    header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+    header.extend_from_slice(value);
+}
+
+/// Reads a single-part scanline OpenEXR file with uncompressed 32-bit float
+/// channels, such as those written by `write`.
+pub fn read<P: AsRef<Path>>(path: P) -> io::Result<Image> {
+    decode(&fs::read(path)?)
+}
+
+pub fn decode(bytes: &[u8]) -> io::Result<Image> {
+    let mut reader = Reader { bytes, pos: 0 };
+    if reader.take(4)? != MAGIC {
+        return Err(bad("not an OpenEXR file"));
+    }
+    let version = reader.u32()?;
+    if version & 0xff != 2 || version & !0xff != 0 {
+        return Err(bad("only single-part scanline OpenEXR files are supported"));
+    }
+
+    let mut names = Vec::new();
+    let mut window = None;
+    loop {
+        let name = reader.string()?;
+        if name.is_empty() {
+            break;
+        }
+        let kind = reader.string()?;
+        let size = reader.u32()? as usize;
+        let mut value = Reader {
+            bytes: reader.take(size)?,
+            pos: 0,
+        };
+        match (name.as_str(), kind.as_str()) {
+            ("channels", "chlist") => loop {
+                let channel = value.string()?;
+                if channel.is_empty() {
+                    break;
+                }
+                if value.u32()? as i32 != FLOAT {
+                    return Err(bad("only 32-bit float channels are supported"));
+                }
+                value.take(12)?;
+                names.push(channel);
+            },
+            ("compression", _) if value.take(1)? != [0] => {
+                return Err(bad("only uncompressed OpenEXR files are supported"));
+            }
+            ("dataWindow", "box2i") => {
+                let mut v = [0; 4];
+                for v in v.iter_mut() {
+                    *v = value.u32()? as i32;
+                }
+                window = Some(v);
+            }
+            _ => {}
+        }
+    }
+
+    // Every value takes four bytes of the file, so a window claiming more
+    // than fits is corrupt, and nothing is allocated for it.
+    let [x0, y0, x1, y1] = window.ok_or_else(|| bad("missing data window"))?;
+    let side = |from: i32, to: i32| (to as i64 - from as i64 + 1).max(0) as usize;
+    let (width, height) = (side(x0, x1), side(y0, y1));
+    let size = width
+        .checked_mul(height)
+        .and_then(|pixels| pixels.checked_mul(names.len()))
+        .and_then(|values| values.checked_mul(4));
+    if size.is_none_or(|size| size > reader.bytes.len()) {
+        return Err(bad("data window does not fit in the file"));
+    }
+    let mut channels: Vec<Channel> = names
+        .into_iter()
+        .map(|name| Channel {
+            name,
+            values: vec![0.0; width * height],
+        })
+        .collect();
+
+    let offsets = (0..height)
+        .map(|_| Ok(reader.u64()? as usize))
+        .collect::<io::Result<Vec<usize>>>()?;
+    for offset in offsets {
+        reader.pos = offset;
+        let y = reader.u32()? as i32 as i64 - y0 as i64;
+        reader.u32()?;
+        if !(0..height as i64).contains(&y) {
+            return Err(bad("scanline outside the data window"));
+        }
+        let y = y as usize;
+        for channel in channels.iter_mut() {
+            for v in &mut channel.values[y * width..(y + 1) * width] {
+                *v = f32::from_le_bytes(reader.take(4)?.try_into().unwrap());
+            }
+        }
+    }
+
+    Ok(Image {
+        width,
+        height,
+        channels,
+    })
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        let slice = self
+            .bytes
+            .get(self.pos..self.pos.saturating_add(n))
+            .ok_or_else(|| bad("truncated OpenEXR file"))?;
+        self.pos += n;
+        Ok(slice)
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        let rest = &self.bytes[self.pos.min(self.bytes.len())..];
+        let len = rest
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or_else(|| bad("truncated OpenEXR file"))?;
+        let s = String::from_utf8_lossy(&rest[..len]).into_owned();
+        self.pos += len + 1;
+        Ok(s)
+    }
+}
+
+fn bad(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layout() {
+        let pixels: Vec<Vec3> = (0..6).map(|i| Vec3::new(i as f32, 0.0, 0.5)).collect();
+        let mut channels = Channel::from_pixels(&["R", "G", "B"], &pixels);
+        channels.push(Channel {
+            name: "Z".to_string(),
+            values: vec![7.0; 6],
+        });
+
+        let path = std::env::temp_dir().join(format!("exr-{}.exr", std::process::id()));
+        write(&path, 3, 2, &channels).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(bytes[..4], MAGIC);
+        // The offset table points at the second line, whose channels come in
+        // the order B, G, R, Z.
+        let header = bytes.len() - 2 * (8 + 4 * 3 * 4) - 2 * 8;
+        let offset = u64::from_le_bytes(bytes[header + 8..header + 16].try_into().unwrap());
+        let line = &bytes[offset as usize..];
+        assert_eq!(line[..4], 1i32.to_le_bytes());
+        let value = |i: usize| f32::from_le_bytes(line[8 + 4 * i..12 + 4 * i].try_into().unwrap());
+        assert_eq!((value(0), value(6), value(9)), (0.5, 3.0, 7.0));
+    }
+
+    #[test]
+    fn test_corrupt_files_are_rejected() {
+        let mut header = MAGIC.to_vec();
+        header.extend(2u32.to_le_bytes());
+        header.extend(b"channels\0chlist\0");
+        header.extend(19u32.to_le_bytes());
+        header.extend(b"R\0");
+        header.extend(FLOAT.to_le_bytes());
+        header.extend([0; 12]);
+        header.push(0);
+        let with_window = |window: [i32; 4], offset: u64| {
+            let mut bytes = header.clone();
+            bytes.extend(b"dataWindow\0box2i\0");
+            bytes.extend(16u32.to_le_bytes());
+            window.iter().for_each(|v| bytes.extend(v.to_le_bytes()));
+            bytes.push(0);
+            bytes.extend(offset.to_le_bytes());
+            bytes
+        };
+
+        // One pixel, as a reference that the header itself is sound.
+        let mut valid = with_window([0, 0, 0, 0], 0);
+        let offset = valid.len() as u64;
+        valid = with_window([0, 0, 0, 0], offset);
+        valid.extend(0i32.to_le_bytes());
+        valid.extend(4u32.to_le_bytes());
+        valid.extend(1.5f32.to_le_bytes());
+        assert_eq!(decode(&valid).unwrap().channels[0].values, [1.5]);
+
+        for bytes in [
+            with_window([i32::MIN, 0, i32::MAX, 0], 0),
+            with_window([0, 0, 65535, 65535], 0),
+            with_window([0, 0, 0, 0], u64::MAX),
+            with_window([0, 0, 0, 0], 0),
+        ] {
+            assert!(decode(&bytes).is_err());
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let pixels: Vec<Vec3> = (0..6)
+            .map(|i| Vec3::new(i as f32, -(i as f32), 0.5))
+            .collect();
+        let mut channels = Channel::from_pixels(&["R", "G", "B"], &pixels);
+        channels.push(Channel {
+            name: "Z".to_string(),
+            values: vec![7.0; 6],
+        });
+
+        let path = std::env::temp_dir().join(format!("exr-read-{}.exr", std::process::id()));
+        write(&path, 3, 2, &channels).unwrap();
+        let image = read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!((image.width, image.height), (3, 2));
+        assert_eq!(image.pixels(&["R", "G", "B"]).unwrap(), pixels);
+        assert_eq!(image.pixels(&["Z"]).unwrap()[5], Vec3::new(7.0, 0.0, 0.0));
+        assert!(image.pixels(&["A"]).is_none());
+    }
+}
@@ -0,0 +1,177 @@
+use crate::checkpoint;
+use crate::filter::Filter;
+use crate::vec3::Vec3;
+use std::io::{self, Read, Write};
+
+/// Accumulates filtered samples for a rectangle of the image.
+///
+/// Positions are continuous image coordinates with `(0, 0)` at the top-left
+/// corner of the frame and pixel centres at half-integers. A tile only stores
+/// the pixels it covers, so samples near its edge that fall into neighbouring
+/// pixels are kept and merged later.
+#[derive(Clone)]
+pub struct Film {
+    x0: usize,
+    y0: usize,
+    width: usize,
+    height: usize,
+    filter: Filter,
+    sums: Vec<Vec3>,
+    weights: Vec<f32>,
+}
+
+impl Film {
+    pub fn new(width: usize, height: usize, filter: Filter) -> Film {
+        Film::region(0, 0, width, height, filter)
+    }
+
+    fn region(x0: usize, y0: usize, width: usize, height: usize, filter: Filter) -> Film {
+        Film {
+            x0,
+            y0,
+            width,
+            height,
+            filter,
+            sums: vec![Vec3::default(); width * height],
+            weights: vec![0.0; width * height],
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// An empty tile able to take every sample drawn inside the pixels
+    /// `[x0, x1) x [y0, y1)` of this film.
+    pub fn tile(&self, x0: usize, y0: usize, x1: usize, y1: usize) -> Film {
+        let margin = (self.filter.radius - 0.5).ceil().max(0.0) as usize;
+        let tx0 = (self.x0 + x0).saturating_sub(margin).max(self.x0);
+        let ty0 = (self.y0 + y0).saturating_sub(margin).max(self.y0);
+        let tx1 = (self.x0 + x1 + margin).min(self.x0 + self.width);
+        let ty1 = (self.y0 + y1 + margin).min(self.y0 + self.height);
+
+        Film::region(tx0, ty0, tx1 - tx0, ty1 - ty0, self.filter)
+    }
+
+    /// Splats a sample taken at image position `(x, y)` onto every pixel whose
+    /// filter footprint covers it.
+    pub fn add_sample(&mut self, x: f32, y: f32, radiance: Vec3) {
+        let r = self.filter.radius;
+        let min_x = ((x - r - 0.5).ceil().max(self.x0 as f32)) as usize;
+        let min_y = ((y - r - 0.5).ceil().max(self.y0 as f32)) as usize;
+        let max_x = ((x + r - 0.5).floor() as isize).min((self.x0 + self.width) as isize - 1);
+        let max_y = ((y + r - 0.5).floor() as isize).min((self.y0 + self.height) as isize - 1);
+
+        for py in min_y as isize..=max_y {
+            for px in min_x as isize..=max_x {
+                let weight = self
+                    .filter
+                    .evaluate(px as f32 + 0.5 - x, py as f32 + 0.5 - y);
+                if weight == 0.0 {
+                    continue;
+                }
+                let index = (py as usize - self.y0) * self.width + (px as usize - self.x0);
+                self.sums[index] = self.sums[index] + radiance * weight;
+                self.weights[index] += weight;
+            }
+        }
+    }
+
+    /// Adds the samples of a tile taken from this film.
+    pub fn merge(&mut self, tile: &Film) {
+        for row in 0..tile.height {
+            let src = row * tile.width;
+            let dst = (tile.y0 + row - self.y0) * self.width + (tile.x0 - self.x0);
+            for i in 0..tile.width {
+                self.sums[dst + i] = self.sums[dst + i] + tile.sums[src + i];
+                self.weights[dst + i] += tile.weights[src + i];
+            }
+        }
+    }
+
+    /// Writes the accumulated sums and weights.
+    pub fn save(&self, out: &mut impl Write) -> io::Result<()> {
+        for (sum, &weight) in self.sums.iter().zip(&self.weights) {
+            for v in [sum.x(), sum.y(), sum.z(), weight] {
+                checkpoint::write_f32(out, v)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces the accumulated samples with ones written by `save` from a
+    /// film of the same size.
+    pub fn load(&mut self, input: &mut impl Read) -> io::Result<()> {
+        for (sum, weight) in self.sums.iter_mut().zip(self.weights.iter_mut()) {
+            let x = checkpoint::read_f32(input)?;
+            let y = checkpoint::read_f32(input)?;
+            let z = checkpoint::read_f32(input)?;
+            *sum = Vec3::new(x, y, z);
+            *weight = checkpoint::read_f32(input)?;
+        }
+        Ok(())
+    }
+
+    /// The filtered pixel values, rows top to bottom.
+    pub fn resolve(&self) -> Vec<Vec3> {
+        self.sums
+            .iter()
+            .zip(&self.weights)
+            .map(|(&sum, &weight)| {
+                if weight.abs() > 1e-6 {
+                    sum / weight
+                } else {
+                    Vec3::default()
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_box_filter_averages_own_pixel() {
+        let mut film = Film::new(2, 1, Filter::default());
+        film.add_sample(0.25, 0.5, Vec3::new(1.0, 1.0, 1.0));
+        film.add_sample(0.75, 0.5, Vec3::new(3.0, 3.0, 3.0));
+        film.add_sample(1.5, 0.5, Vec3::new(5.0, 5.0, 5.0));
+        let pixels = film.resolve();
+        assert_eq!(pixels[0], Vec3::new(2.0, 2.0, 2.0));
+        assert_eq!(pixels[1], Vec3::new(5.0, 5.0, 5.0));
+    }
+
+    #[test]
+    fn test_wide_filter_splats_to_neighbours() {
+        let filter: Filter = "tent:1.5".parse().unwrap();
+        let mut film = Film::new(3, 3, filter);
+        film.add_sample(1.5, 1.5, Vec3::new(1.0, 1.0, 1.0));
+        assert!(film
+            .resolve()
+            .iter()
+            .all(|p| *p == Vec3::new(1.0, 1.0, 1.0)));
+    }
+
+    #[test]
+    fn test_tiles_merge_like_one_film() {
+        let filter: Filter = "gaussian".parse().unwrap();
+        let mut whole = Film::new(4, 4, filter);
+        let mut merged = Film::new(4, 4, filter);
+        let samples = [(0.3, 0.7, 1.0), (1.9, 1.2, 2.0), (3.5, 3.9, 4.0)];
+
+        for row in 0..4 {
+            let mut tile = merged.tile(0, row, 4, row + 1);
+            for &(x, y, v) in samples.iter().filter(|s| s.1 as usize == row) {
+                whole.add_sample(x, y, Vec3::new(v, v, v));
+                tile.add_sample(x, y, Vec3::new(v, v, v));
+            }
+            merged.merge(&tile);
+        }
+
+        for (a, b) in whole.resolve().iter().zip(merged.resolve()) {
+            assert!((*a - b).length() < 1e-6);
+        }
+    }
+}
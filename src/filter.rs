@@ -0,0 +1,165 @@
+use std::f32::consts::PI;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FilterKind {
+    Box,
+    Tent,
+    Gaussian,
+    /// Mitchell-Netravali with B = C = 1/3.
+    Mitchell,
+    /// Sinc windowed by a sinc over the full radius.
+    Lanczos,
+    BlackmanHarris,
+}
+
+/// Pixel reconstruction filter: a separable kernel of the given radius in
+/// pixels, centred on each pixel.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Filter {
+    pub kind: FilterKind,
+    pub radius: f32,
+}
+
+impl Default for Filter {
+    /// The box of one pixel, so every sample only counts towards its own pixel.
+    fn default() -> Self {
+        Filter {
+            kind: FilterKind::Box,
+            radius: 0.5,
+        }
+    }
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    /// Parses `name[:radius]`, e.g. `mitchell` or `gaussian:2`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, radius) = s.split_once(':').unwrap_or((s, ""));
+        let (kind, default_radius) = match name {
+            "box" => (FilterKind::Box, 0.5),
+            "tent" | "triangle" => (FilterKind::Tent, 1.0),
+            "gaussian" => (FilterKind::Gaussian, 1.5),
+            "mitchell" => (FilterKind::Mitchell, 2.0),
+            "lanczos" => (FilterKind::Lanczos, 3.0),
+            "blackman-harris" => (FilterKind::BlackmanHarris, 2.0),
+            _ => return Err(format!("unknown filter '{}'", name)),
+        };
+
+        let radius = if radius.is_empty() {
+            default_radius
+        } else {
+            radius
+                .parse::<f32>()
+                .ok()
+                .filter(|r| *r > 0.0)
+                .ok_or_else(|| format!("invalid filter radius '{}'", radius))?
+        };
+
+        Ok(Filter { kind, radius })
+    }
+}
+
+impl Filter {
+    /// Weight of a sample `(dx, dy)` pixels away from a pixel centre.
+    pub fn evaluate(&self, dx: f32, dy: f32) -> f32 {
+        self.evaluate_1d(dx) * self.evaluate_1d(dy)
+    }
+
+    fn evaluate_1d(&self, x: f32) -> f32 {
+        let x = x.abs();
+        let r = self.radius;
+        if x > r {
+            return 0.0;
+        }
+
+        match self.kind {
+            FilterKind::Box => 1.0,
+            FilterKind::Tent => r - x,
+            FilterKind::Gaussian => {
+                // Falls to zero at the radius; sigma scales with it.
+                let sigma = r / 3.0;
+                let gaussian = |x: f32| (-x * x / (2.0 * sigma * sigma)).exp();
+                gaussian(x) - gaussian(r)
+            }
+            FilterKind::Mitchell => mitchell(2.0 * x / r),
+            FilterKind::Lanczos => sinc(x) * sinc(x / r),
+            FilterKind::BlackmanHarris => {
+                let n = (x + r) / (2.0 * r);
+                0.35875 - 0.48829 * (2.0 * PI * n).cos() + 0.14128 * (4.0 * PI * n).cos()
+                    - 0.01168 * (6.0 * PI * n).cos()
+            }
+        }
+    }
+}
+
+fn sinc(x: f32) -> f32 {
+    if x.abs() < 1e-5 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+fn mitchell(x: f32) -> f32 {
+    const B: f32 = 1.0 / 3.0;
+    const C: f32 = 1.0 / 3.0;
+
+    if x > 1.0 {
+        ((-B - 6.0 * C) * x.powi(3)
+            + (6.0 * B + 30.0 * C) * x.powi(2)
+            + (-12.0 * B - 48.0 * C) * x
+            + (8.0 * B + 24.0 * C))
+            / 6.0
+    } else {
+        ((12.0 - 9.0 * B - 6.0 * C) * x.powi(3)
+            + (-18.0 + 12.0 * B + 6.0 * C) * x.powi(2)
+            + (6.0 - 2.0 * B))
+            / 6.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_filter() {
+        assert_eq!("box".parse::<Filter>(), Ok(Filter::default()));
+        assert_eq!(
+            "gaussian:2".parse::<Filter>(),
+            Ok(Filter {
+                kind: FilterKind::Gaussian,
+                radius: 2.0
+            })
+        );
+        assert!("gaussian:-1".parse::<Filter>().is_err());
+        assert!("sharp".parse::<Filter>().is_err());
+    }
+
+    #[test]
+    fn test_filters_peak_at_centre_and_vanish_outside() {
+        for name in [
+            "box",
+            "tent",
+            "gaussian",
+            "mitchell",
+            "lanczos",
+            "blackman-harris",
+        ] {
+            let filter: Filter = name.parse().unwrap();
+            let centre = filter.evaluate(0.0, 0.0);
+            assert!(centre > 0.0, "{}", name);
+            assert!(filter.evaluate(0.3, 0.2) <= centre, "{}", name);
+            assert_eq!(filter.evaluate(filter.radius + 0.01, 0.0), 0.0, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_mitchell_negative_lobe() {
+        let filter: Filter = "mitchell".parse().unwrap();
+        assert!(filter.evaluate(1.5, 0.0) < 0.0);
+        assert!(filter.evaluate(2.0, 0.0).abs() < 1e-6);
+    }
+}
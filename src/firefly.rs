@@ -0,0 +1,214 @@
+use crate::checkpoint;
+use crate::lpe::{self, Event};
+use crate::vec3::Vec3;
+use std::io::{self, Read, Write};
+
+/// Pixels of invalid samples listed in the report; the rest are only
+/// counted.
+const REPORTED: usize = 10;
+
+/// What to do about samples far brighter than their neighbours, and about
+/// samples that are not numbers at all.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Fireflies {
+    /// Most radiance a sample may bring, in its brightest channel.
+    pub sample: Option<f32>,
+    /// Most radiance the light a path finds after bouncing may bring, in
+    /// its brightest channel.
+    pub bounce: Option<f32>,
+    /// Panic with the paths of the first NaN or infinite sample instead of
+    /// replacing it with black.
+    pub abort: bool,
+}
+
+impl Fireflies {
+    /// Checks one sample, `radiance` and its light path `shares`, and clamps
+    /// it if it is too bright. A sample that is not a number is replaced
+    /// with black, and `false` returned; in abort mode this panics instead,
+    /// naming the sample as `what` says and listing its recorded `paths`.
+    pub fn check(
+        &self,
+        radiance: &mut Vec3,
+        shares: &mut [Vec3],
+        paths: &[(Vec<Event>, Vec3)],
+        what: impl Fn() -> String,
+    ) -> bool {
+        if !is_finite(*radiance) {
+            if self.abort {
+                let paths: Vec<String> = paths
+                    .iter()
+                    .map(|(events, value)| format!("{} {:?}", lpe::text(events), value))
+                    .collect();
+                panic!(
+                    "{} is {:?}, from paths [{}]",
+                    what(),
+                    radiance,
+                    paths.join(", ")
+                );
+            }
+            *radiance = Vec3::default();
+            shares.fill(Vec3::default());
+            return false;
+        }
+        if let Some(limit) = self.sample {
+            let scale = clamp(*radiance, limit);
+            *radiance = *radiance * scale;
+            for share in shares.iter_mut() {
+                *share = *share * scale;
+            }
+        }
+        true
+    }
+}
+
+fn is_finite(v: Vec3) -> bool {
+    v.x().is_finite() && v.y().is_finite() && v.z().is_finite()
+}
+
+/// The factor that brings the brightest channel of `v` down to `limit`, or
+/// 1 if it is not above it. Scaling all channels keeps the hue.
+pub fn clamp(v: Vec3, limit: f32) -> f32 {
+    let max = v.x().max(v.y()).max(v.z());
+    if max > limit {
+        limit / max
+    } else {
+        1.0
+    }
+}
+
+/// Samples that were NaN or infinite: how many there were, and the pixels
+/// of the first few, which is all the report names.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct InvalidSamples {
+    count: u64,
+    pixels: Vec<(usize, usize)>,
+}
+
+impl InvalidSamples {
+    /// Counts an invalid sample of pixel (`x`, `y`).
+    pub fn add(&mut self, x: usize, y: usize) {
+        self.count += 1;
+        if self.pixels.len() < REPORTED {
+            self.pixels.push((x, y));
+        }
+    }
+
+    /// Adds the samples counted in `other` after those counted here.
+    pub fn merge(&mut self, other: &InvalidSamples) {
+        self.count += other.count;
+        let room = REPORTED - self.pixels.len();
+        self.pixels.extend(other.pixels.iter().take(room));
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// A warning naming the count and the first pixels.
+    pub fn report(&self) -> String {
+        let listed: Vec<String> = self
+            .pixels
+            .iter()
+            .map(|(x, y)| format!("({}, {})", x, y))
+            .collect();
+        let more = match self.count.saturating_sub(listed.len() as u64) {
+            0 => String::new(),
+            n => format!(" and {} more", n),
+        };
+        format!(
+            "warning: {} samples were NaN or infinite and left black, in pixels {}{}",
+            self.count,
+            listed.join(", "),
+            more
+        )
+    }
+
+    pub fn save(&self, out: &mut impl Write) -> io::Result<()> {
+        checkpoint::write_u64(out, self.count)?;
+        checkpoint::write_u64(out, self.pixels.len() as u64)?;
+        self.pixels.iter().try_for_each(|&(x, y)| {
+            checkpoint::write_u64(out, x as u64)?;
+            checkpoint::write_u64(out, y as u64)
+        })
+    }
+
+    pub fn load(input: &mut impl Read) -> io::Result<InvalidSamples> {
+        let count = checkpoint::read_u64(input)?;
+        let listed = checkpoint::read_u64(input)?;
+        if listed > REPORTED as u64 || listed > count {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "corrupt invalid sample list",
+            ));
+        }
+        let pixels = (0..listed)
+            .map(|_| {
+                let x = checkpoint::read_u64(input)? as usize;
+                Ok((x, checkpoint::read_u64(input)? as usize))
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(InvalidSamples { count, pixels })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let fireflies = Fireflies {
+            sample: Some(2.0),
+            ..Fireflies::default()
+        };
+        let mut radiance = Vec3::new(4.0, 1.0, 0.0);
+        let mut shares = [Vec3::new(3.0, 1.0, 0.0), Vec3::new(1.0, 0.0, 0.0)];
+        assert!(fireflies.check(&mut radiance, &mut shares, &[], String::new));
+        assert_eq!(radiance, Vec3::new(2.0, 0.5, 0.0));
+        assert_eq!(shares[0] + shares[1], radiance);
+
+        let mut radiance = Vec3::new(f32::NAN, 1.0, 0.0);
+        assert!(!fireflies.check(&mut radiance, &mut shares, &[], String::new));
+        assert_eq!(radiance, Vec3::default());
+        assert_eq!(shares, [Vec3::default(); 2]);
+    }
+
+    #[test]
+    fn test_invalid_samples_keep_first_pixels() {
+        let mut invalid = InvalidSamples::default();
+        for x in 0..4 {
+            invalid.add(x, 0);
+        }
+        let mut more = InvalidSamples::default();
+        for x in 0..1000 {
+            more.add(x, 1);
+        }
+        invalid.merge(&more);
+        assert_eq!(invalid.count(), 1004);
+        assert_eq!(invalid.pixels.len(), REPORTED);
+        assert_eq!(invalid.pixels[4], (0, 1));
+        assert!(invalid.report().ends_with("(5, 1) and 994 more"));
+
+        let mut saved = Vec::new();
+        invalid.save(&mut saved).unwrap();
+        assert_eq!(
+            InvalidSamples::load(&mut saved.as_slice()).unwrap(),
+            invalid
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "pixel (3, 4) is Vec3 { e: [inf, 0.0, 0.0] }, from paths [CDL")]
+    fn test_abort_names_path() {
+        let fireflies = Fireflies {
+            abort: true,
+            ..Fireflies::default()
+        };
+        let path = vec![Event::Camera, Event::Diffuse, Event::Light(0)];
+        let mut radiance = Vec3::new(f32::INFINITY, 0.0, 0.0);
+        let paths = [(path, radiance)];
+        fireflies.check(&mut radiance, &mut [], &paths, || {
+            String::from("pixel (3, 4)")
+        });
+    }
+}
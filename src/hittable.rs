@@ -8,6 +8,8 @@ pub struct HitRecord {
     pub p: Vec3,
     pub normal: Vec3,
     pub material: Material,
+    /// Index of the hit object in the scene's list.
+    pub object: usize,
 }
 
 impl HitRecord {
@@ -1,5 +1,17 @@
 use crate::hittable::{HitRecord, Hittable};
 use crate::ray::Ray;
+use std::cell::Cell;
+
+thread_local! {
+    // Object intersection tests made on this thread, for the cost preview.
+    static TESTS: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Object intersection tests made on this thread so far. There is no
+/// acceleration structure, so every ray tests every object of a list.
+pub fn tests() -> u64 {
+    TESTS.with(Cell::get)
+}
 
 pub struct HittableList {
     list: Vec<Box<dyn Hittable + Send + Sync>>,
@@ -13,13 +25,17 @@ impl HittableList {
 
 impl Hittable for HittableList {
     fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        TESTS.with(|tests| tests.set(tests.get() + self.list.len() as u64));
         let mut closest_so_far = t_max;
         let mut temp_rec = None;
 
-        for object in &self.list {
+        for (index, object) in self.list.iter().enumerate() {
             if let Some(rec) = object.hit(r, t_min, closest_so_far) {
                 closest_so_far = rec.t;
-                temp_rec = Some(rec);
+                temp_rec = Some(HitRecord {
+                    object: index,
+                    ..rec
+                });
             }
         }
 
@@ -0,0 +1,392 @@
+use crate::bdpt::Bidirectional;
+use crate::firefly;
+use crate::hittable::Hittable;
+use crate::lpe::Event;
+use crate::material::{scatter, Material};
+use crate::mlt::MetropolisSettings;
+use crate::photon::{CausticPathTracer, PhotonMapper, PhotonSettings};
+use crate::preview::{Preview, PreviewIntegrator};
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::scene::Scene;
+use crate::vec3::Vec3;
+use std::ops::Range;
+use std::str::FromStr;
+
+/// Most bounces a path takes before it is cut off, in the integrators that
+/// do not follow `Bounces` and by default in those that do.
+pub const MAX_DEPTH: usize = 50;
+
+/// How far the path tracers follow a path: the most bounces it may take in
+/// all and of each kind, and when Russian roulette starts ending it at
+/// random. By default a path stops after `MAX_DEPTH` bounces of any kind,
+/// without roulette.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bounces {
+    /// Bounces of every kind together.
+    pub total: usize,
+    /// Bounces off diffuse surfaces.
+    pub diffuse: usize,
+    /// Reflections off metal and glass.
+    pub specular: usize,
+    /// Refractions through glass.
+    pub transmission: usize,
+    /// Bounces after which a path goes on only with the probability of its
+    /// brightest throughput channel, and is weighted up to make up for the
+    /// paths that stop; `None` follows every path to its limits.
+    pub roulette: Option<usize>,
+}
+
+impl Default for Bounces {
+    fn default() -> Self {
+        Bounces {
+            total: MAX_DEPTH,
+            diffuse: MAX_DEPTH,
+            specular: MAX_DEPTH,
+            transmission: MAX_DEPTH,
+            roulette: None,
+        }
+    }
+}
+
+impl Bounces {
+    /// Counts a bounce of kind `event` in `taken`, the diffuse, specular and
+    /// transmission bounces of a path so far, and tells whether the path
+    /// may still take it, both by its kind and in all.
+    pub fn take(&self, taken: &mut [usize; 3], event: Event) -> bool {
+        let (kind, max) = match event {
+            Event::Diffuse => (0, self.diffuse),
+            Event::Specular => (1, self.specular),
+            Event::Transmission => (2, self.transmission),
+            Event::Camera | Event::Light(_) => return true,
+        };
+        taken[kind] += 1;
+        taken[kind] <= max && taken.iter().sum::<usize>() <= self.total
+    }
+
+    /// Plays Russian roulette for a path that has taken `depth` bounces and
+    /// carries `beta` after the next: `None` if it stops there, otherwise
+    /// the probability it went on with, to divide `beta` by. Takes one
+    /// dimension of `sampler` on every bounce it plays.
+    pub fn roulette(&self, depth: usize, beta: Vec3, sampler: &mut dyn Sampler) -> Option<f32> {
+        match self.roulette {
+            Some(start) if depth >= start => {
+                let survival = beta.x().max(beta.y()).max(beta.z()).min(1.0);
+                if sampler.next_1d() < survival {
+                    Some(survival)
+                } else {
+                    None
+                }
+            }
+            _ => Some(1.0),
+        }
+    }
+}
+
+/// Estimates the light arriving at the camera from the scene.
+pub trait Integrator {
+    /// Readies what samples `indices` of every pixel share, before a pass
+    /// takes them. Called outside the parallel work on the pass's tiles, so
+    /// it can be parallel itself.
+    fn prepare(&self, _scene: &Scene, _indices: Range<usize>) {}
+
+    /// Most samples per pixel a pass may take, if `prepare` only readies so
+    /// many indices at a time.
+    fn max_pass_samples(&self) -> Option<usize> {
+        None
+    }
+
+    /// Radiance arriving along camera ray `r`, for sample `index` of its
+    /// pixel. Each path that carries light is also passed to `record` with
+    /// its events, from the camera to the light, and its share of the
+    /// radiance; the shares add up to the result.
+    fn radiance(
+        &self,
+        r: &Ray,
+        scene: &Scene,
+        sampler: &mut dyn Sampler,
+        index: usize,
+        record: &mut dyn FnMut(&[Event], Vec3),
+    ) -> Vec3;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IntegratorKind {
+    /// Unidirectional path tracing from the camera.
+    Path,
+    /// Bidirectional path tracing, weighting every way of connecting a
+    /// camera path to a light path by multiple importance sampling.
+    Bidirectional,
+    /// Progressive photon mapping, gathering all light from photons.
+    Photon(PhotonSettings),
+    /// Path tracing with caustics gathered from photons.
+    PathCaustics(PhotonSettings),
+    /// Primary sample space Metropolis light transport over path-traced
+    /// paths.
+    Metropolis(MetropolisSettings),
+    /// A quick look at the scene instead of its light.
+    Preview(Preview),
+}
+
+impl FromStr for IntegratorKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "path" => Ok(IntegratorKind::Path),
+            "bdpt" | "bidirectional" => Ok(IntegratorKind::Bidirectional),
+            "photon" | "ppm" => Ok(IntegratorKind::Photon(PhotonSettings::default())),
+            "path-caustics" => Ok(IntegratorKind::PathCaustics(PhotonSettings::default())),
+            "mlt" | "pssmlt" | "metropolis" => {
+                Ok(IntegratorKind::Metropolis(MetropolisSettings::default()))
+            }
+            "normals" => Ok(IntegratorKind::Preview(Preview::Normals)),
+            "albedo" => Ok(IntegratorKind::Preview(Preview::Albedo)),
+            "ao" | "ambient-occlusion" => Ok(IntegratorKind::Preview(Preview::Occlusion(1.0))),
+            "bounces" | "depth" => Ok(IntegratorKind::Preview(Preview::Bounces)),
+            "cost" => Ok(IntegratorKind::Preview(Preview::Cost)),
+            _ => Err(format!("unknown integrator '{}'", s)),
+        }
+    }
+}
+
+impl IntegratorKind {
+    /// An integrator for one image. Samples made with the same seed come out
+    /// the same. Metropolis takes its paths from the path tracer; the path
+    /// tracers follow paths as far as `bounces` lets them, and clamp the
+    /// light they find after bouncing to `clamp`.
+    pub fn create(
+        self,
+        seed: u64,
+        bounces: Bounces,
+        clamp: Option<f32>,
+    ) -> Box<dyn Integrator + Send + Sync> {
+        match self {
+            IntegratorKind::Path | IntegratorKind::Metropolis(_) => {
+                Box::new(PathTracer::new(bounces).with_clamp(clamp))
+            }
+            IntegratorKind::Bidirectional => Box::new(Bidirectional),
+            IntegratorKind::Photon(photons) => Box::new(PhotonMapper::new(photons, seed)),
+            IntegratorKind::PathCaustics(photons) => {
+                Box::new(CausticPathTracer::new(photons, seed, bounces).with_clamp(clamp))
+            }
+            IntegratorKind::Preview(preview) => Box::new(PreviewIntegrator::new(preview, bounces)),
+        }
+    }
+
+    /// The same integrator, shooting and gathering photons as `photons` says
+    /// if it uses any.
+    pub fn with_photons(self, photons: PhotonSettings) -> IntegratorKind {
+        match self {
+            IntegratorKind::Photon(_) => IntegratorKind::Photon(photons),
+            IntegratorKind::PathCaustics(_) => IntegratorKind::PathCaustics(photons),
+            kind => kind,
+        }
+    }
+
+    /// Photons shot for each sample index, by the integrators that use them.
+    pub fn photons(&self) -> usize {
+        match self {
+            IntegratorKind::Photon(photons) | IntegratorKind::PathCaustics(photons) => {
+                photons.photons
+            }
+            _ => 0,
+        }
+    }
+
+    /// The same integrator, running its chains as `metropolis` says if it is
+    /// Metropolis.
+    pub fn with_metropolis(self, metropolis: MetropolisSettings) -> IntegratorKind {
+        match self {
+            IntegratorKind::Metropolis(_) => IntegratorKind::Metropolis(metropolis),
+            kind => kind,
+        }
+    }
+
+    /// The same integrator, looking `distance` far for occluders if it
+    /// previews ambient occlusion.
+    pub fn with_occlusion(self, distance: f32) -> IntegratorKind {
+        match self {
+            IntegratorKind::Preview(Preview::Occlusion(_)) => {
+                IntegratorKind::Preview(Preview::Occlusion(distance))
+            }
+            kind => kind,
+        }
+    }
+}
+
+/// Follows one path from the camera, scattering at every surface until it
+/// reaches a light or the sky, or runs out of bounces.
+pub struct PathTracer {
+    bounces: Bounces,
+    clamp: Option<f32>,
+}
+
+impl PathTracer {
+    pub fn new(bounces: Bounces) -> PathTracer {
+        PathTracer {
+            bounces,
+            clamp: None,
+        }
+    }
+
+    /// The same path tracer, scaling the light a path finds after bouncing
+    /// down to at most `clamp` in its brightest channel.
+    pub fn with_clamp(self, clamp: Option<f32>) -> PathTracer {
+        PathTracer { clamp, ..self }
+    }
+}
+
+impl Integrator for PathTracer {
+    fn radiance(
+        &self,
+        r: &Ray,
+        scene: &Scene,
+        sampler: &mut dyn Sampler,
+        _index: usize,
+        record: &mut dyn FnMut(&[Event], Vec3),
+    ) -> Vec3 {
+        let mut path = vec![Event::Camera];
+        let (mut r, mut beta) = (*r, Vec3::new(1.0, 1.0, 1.0));
+        let mut taken = [0; 3];
+        let radiance = loop {
+            let Some(rec) = scene.world.hit(&r, 0.001, f32::MAX) else {
+                path.push(Event::Light(scene.sky.group));
+                break beta * scene.sky.color(r.direction());
+            };
+            if let Material::DiffuseLight { emit, group } = rec.material {
+                path.push(Event::Light(group));
+                break beta * emit;
+            }
+
+            let mut scattered = Ray::new(Vec3::default(), Vec3::default());
+            let mut attenuation = Vec3::default();
+
+            // Every bounce takes the same three dimensions, whatever the material.
+            let (u0, u1) = sampler.next_2d();
+            let u = [u0, u1, sampler.next_1d()];
+
+            if !scatter(&rec.material, &r, &rec, u, &mut attenuation, &mut scattered) {
+                break Vec3::default();
+            }
+            let event = Event::scatter(&r, &rec, &scattered);
+            if !self.bounces.take(&mut taken, event) {
+                break Vec3::default();
+            }
+            beta = beta * attenuation;
+            match self.bounces.roulette(path.len() - 1, beta, sampler) {
+                Some(survival) => beta = beta / survival,
+                None => break Vec3::default(),
+            }
+            path.push(event);
+            r = scattered;
+        };
+        let radiance = match self.clamp {
+            Some(limit) if path.len() > 2 => radiance * firefly::clamp(radiance, limit),
+            _ => radiance,
+        };
+        record(&path, radiance);
+        radiance
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mat3::Mat3;
+    use crate::sampler::SamplerKind;
+
+    fn scene() -> Scene {
+        Scene::parse(
+            "sky 0.5 0.7 1.0 0.5 0.7 1.0\n\
+             sphere 0 -1000 0 1000 lambertian 0.8 0.8 0.8\n\
+             sphere 0 1 0 1 dielectric 1.5\n\
+             sphere 0 3 0 0.5 light 4 4 4\n",
+            Mat3::IDENTITY,
+            1.0,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_bounce_limits() {
+        let bounces = Bounces {
+            total: MAX_DEPTH,
+            diffuse: 1,
+            specular: 0,
+            transmission: 2,
+            roulette: None,
+        };
+        let mut taken = [0; 3];
+        assert!(bounces.take(&mut taken, Event::Diffuse));
+        assert!(!bounces.take(&mut taken, Event::Diffuse));
+        assert!(!bounces.take(&mut taken, Event::Specular));
+        assert!(bounces.take(&mut taken, Event::Transmission));
+        assert!(bounces.take(&mut taken, Event::Transmission));
+        assert!(!bounces.take(&mut taken, Event::Transmission));
+        // The total counts every kind.
+        let shallow = Bounces {
+            total: 2,
+            ..bounces
+        };
+        let mut taken = [0; 3];
+        assert!(shallow.take(&mut taken, Event::Diffuse));
+        assert!(shallow.take(&mut taken, Event::Transmission));
+        assert!(!shallow.take(&mut taken, Event::Transmission));
+
+        // Without bounces only lights and the sky seen directly are left.
+        let tracer = PathTracer::new(Bounces {
+            total: 0,
+            ..Bounces::default()
+        });
+        let scene = scene();
+        let mut sampler = SamplerKind::Independent.create(1, 1);
+        let mut radiance = |origin: Vec3, direction: Vec3| {
+            sampler.start_sample(0, 0, 0);
+            let r = Ray::new(origin, direction);
+            tracer.radiance(&r, &scene, sampler.as_mut(), 0, &mut |_, _| {})
+        };
+        let side = Vec3::new(5.0, 1.0, 0.0);
+        assert_eq!(radiance(side, Vec3::new(-1.0, -1.0, 0.0)), Vec3::default());
+        assert_eq!(
+            radiance(side, Vec3::new(0.0, 1.0, 0.0)),
+            Vec3::new(0.5, 0.7, 1.0)
+        );
+        assert_eq!(
+            radiance(Vec3::new(0.0, 10.0, 0.0), Vec3::new(0.0, -1.0, 0.0)),
+            Vec3::new(4.0, 4.0, 4.0)
+        );
+    }
+
+    #[test]
+    fn test_roulette_is_unbiased() {
+        let scene = scene();
+        let r = Ray::new(Vec3::new(5.0, 1.5, 1.0), Vec3::new(-1.0, -0.2, -0.2));
+        let samples = 20000;
+        let mean = |roulette: Option<usize>| {
+            let tracer = PathTracer::new(Bounces {
+                roulette,
+                ..Bounces::default()
+            });
+            let mut sampler = SamplerKind::Independent.create(samples, 1);
+            let (mut sum, mut events) = (Vec3::default(), 0);
+            for index in 0..samples {
+                sampler.start_sample(0, 0, index);
+                sum = sum
+                    + tracer.radiance(&r, &scene, sampler.as_mut(), index, &mut |path, _| {
+                        events += path.len()
+                    });
+            }
+            (sum / samples as f32, events)
+        };
+        let ((full, full_events), (roulette, roulette_events)) = (mean(None), mean(Some(0)));
+        assert!(
+            (full - roulette).length() < 0.02 * full.length(),
+            "{:?} {:?}",
+            full,
+            roulette
+        );
+        // Roulette is only worth it if paths come out shorter.
+        assert!(roulette_events < full_events);
+    }
+}
@@ -0,0 +1,451 @@
+use crate::ray::Ray;
+use crate::vec3::Vec3;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// One spherical interface of a lens prescription, in metres.
+///
+/// A zero `curvature_radius` marks the aperture stop.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LensElement {
+    pub curvature_radius: f32,
+    pub thickness: f32,
+    pub eta: f32,
+    pub aperture_radius: f32,
+}
+
+/// A sequence of lens elements ordered from the scene side to the film.
+///
+/// Everything is in metres, so the scene is taken to be modelled in metres:
+/// the focus distance and the world positions of rays share the lens's units.
+///
+/// Rays are traced in camera space: the film lies in the `z = 0` plane and
+/// the lens and scene are towards `+z`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LensSystem {
+    elements: Vec<LensElement>,
+    film_width: f32,
+    film_height: f32,
+    // Radius of the disk on the rear element that contains every ray that can
+    // make it through the system.
+    pupil_radius: f32,
+}
+
+impl LensSystem {
+    /// Parses a prescription with one `radius thickness ior aperture` line per
+    /// interface, all in millimetres, `#` starting a comment.
+    pub fn parse(text: &str) -> io::Result<Vec<LensElement>> {
+        let mut elements = Vec::new();
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let values = line
+                .split_whitespace()
+                .map(|v| v.parse::<f32>())
+                .collect::<Result<Vec<f32>, _>>()
+                .ok()
+                .filter(|v| v.len() == 4)
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("lens prescription line {}: expected 4 numbers", number + 1),
+                    )
+                })?;
+
+            elements.push(LensElement {
+                curvature_radius: values[0] * 0.001,
+                thickness: values[1] * 0.001,
+                eta: values[2],
+                aperture_radius: values[3] * 0.001 / 2.0,
+            });
+        }
+
+        if elements.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "lens prescription has no elements",
+            ));
+        }
+
+        Ok(elements)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Vec<LensElement>> {
+        LensSystem::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Builds a lens focused at `focus_dist` from the film, with a film of the
+    /// given diagonal (mm) and aspect ratio. `stop_diameter` (mm) narrows the
+    /// aperture stop when given.
+    pub fn new(
+        mut elements: Vec<LensElement>,
+        film_diagonal: f32,
+        aspect: f32,
+        stop_diameter: Option<f32>,
+        focus_dist: f32,
+    ) -> LensSystem {
+        if let Some(diameter) = stop_diameter {
+            for element in elements.iter_mut().filter(|e| e.curvature_radius == 0.0) {
+                element.aperture_radius = element.aperture_radius.min(diameter * 0.001 / 2.0);
+            }
+        }
+
+        let diagonal = film_diagonal * 0.001;
+        let film_height = diagonal / (1.0 + aspect * aspect).sqrt();
+
+        let mut lens = LensSystem {
+            elements,
+            film_width: aspect * film_height,
+            film_height,
+            pupil_radius: 0.0,
+        };
+        lens.focus(focus_dist);
+        lens.pupil_radius = lens.bound_exit_pupil();
+        lens
+    }
+
+    /// The f-number: effective focal length over the diameter of the aperture
+    /// stop, which stands in for the entrance pupil. `None` for a system
+    /// without a stop or one that does not focus parallel rays.
+    pub fn f_number(&self) -> Option<f32> {
+        let ((pz0, fz0), _) = self.thick_lens()?;
+        let stop = self.elements.iter().find(|e| e.curvature_radius == 0.0)?;
+        Some((fz0 - pz0).abs() / (2.0 * stop.aperture_radius))
+    }
+
+    fn rear_z(&self) -> f32 {
+        self.elements
+            .last()
+            .map(|e| e.thickness)
+            .unwrap_or_default()
+    }
+
+    fn front_z(&self) -> f32 {
+        self.elements.iter().map(|e| e.thickness).sum()
+    }
+
+    fn rear_aperture(&self) -> f32 {
+        self.elements
+            .last()
+            .map(|e| e.aperture_radius)
+            .unwrap_or_default()
+    }
+
+    /// Point on the film for image coordinates `(s, t)`. The lens inverts the
+    /// image, so the film is flipped to keep the render upright.
+    pub fn film_point(&self, s: f32, t: f32) -> Vec3 {
+        Vec3::new(
+            (0.5 - s) * self.film_width,
+            (0.5 - t) * self.film_height,
+            0.0,
+        )
+    }
+
+    /// Traces a ray from `film` towards the point `(disk.x, disk.y)` of the unit
+    /// disk scaled to the exit pupil. Returns the ray leaving the front element,
+    /// or `None` when it is blocked by an element or the aperture stop.
+    pub fn sample_ray(&self, film: Vec3, disk: Vec3) -> Option<Ray> {
+        let rear = Vec3::new(
+            self.pupil_radius * disk.x(),
+            self.pupil_radius * disk.y(),
+            self.rear_z(),
+        );
+        self.trace_from_film(Ray::new(film, rear - film))
+    }
+
+    fn trace_from_film(&self, r: Ray) -> Option<Ray> {
+        let mut origin = flip(r.origin());
+        let mut direction = flip(r.direction());
+        let mut element_z = 0.0;
+
+        for (i, element) in self.elements.iter().enumerate().rev() {
+            element_z -= element.thickness;
+
+            let stop = element.curvature_radius == 0.0;
+            let (t, normal) = if stop {
+                if direction.z() >= 0.0 {
+                    return None;
+                }
+                ((element_z - origin.z()) / direction.z(), Vec3::default())
+            } else {
+                intersect_element(element.curvature_radius, element_z, origin, direction)?
+            };
+
+            let hit = origin + t * direction;
+            if hit.x() * hit.x() + hit.y() * hit.y() > element.aperture_radius.powi(2) {
+                return None;
+            }
+            origin = hit;
+
+            if !stop {
+                let eta_i = element.eta;
+                let eta_t = match i {
+                    0 => 1.0,
+                    _ if self.elements[i - 1].eta == 0.0 => 1.0,
+                    _ => self.elements[i - 1].eta,
+                };
+                direction = refract(-Vec3::unit_vector(&direction), normal, eta_i / eta_t)?;
+            }
+        }
+
+        Some(Ray::new(flip(origin), flip(direction)))
+    }
+
+    fn trace_from_scene(&self, r: Ray) -> Option<Ray> {
+        let mut origin = flip(r.origin());
+        let mut direction = flip(r.direction());
+        let mut element_z = -self.front_z();
+
+        for (i, element) in self.elements.iter().enumerate() {
+            let stop = element.curvature_radius == 0.0;
+            let (t, normal) = if stop {
+                ((element_z - origin.z()) / direction.z(), Vec3::default())
+            } else {
+                intersect_element(element.curvature_radius, element_z, origin, direction)?
+            };
+
+            let hit = origin + t * direction;
+            if hit.x() * hit.x() + hit.y() * hit.y() > element.aperture_radius.powi(2) {
+                return None;
+            }
+            origin = hit;
+
+            if !stop {
+                let eta_i = match i {
+                    0 => 1.0,
+                    _ if self.elements[i - 1].eta == 0.0 => 1.0,
+                    _ => self.elements[i - 1].eta,
+                };
+                let eta_t = if element.eta != 0.0 { element.eta } else { 1.0 };
+                direction = refract(-Vec3::unit_vector(&direction), normal, eta_i / eta_t)?;
+            }
+
+            element_z += element.thickness;
+        }
+
+        Some(Ray::new(flip(origin), flip(direction)))
+    }
+
+    /// Moves the film so that objects `focus_dist` away are sharp, using the
+    /// thick lens approximation of the system.
+    fn focus(&mut self, focus_dist: f32) {
+        let Some(((pz0, fz0), (pz1, _))) = self.thick_lens() else {
+            return;
+        };
+
+        let f = fz0 - pz0;
+        let z = -focus_dist;
+        let c = (pz1 - z - pz0) * (pz1 - z - 4.0 * f - pz0);
+        if c <= 0.0 {
+            return;
+        }
+        let delta = 0.5 * (pz1 - z + pz0 - c.sqrt());
+
+        if let Some(last) = self.elements.last_mut() {
+            last.thickness += delta;
+        }
+    }
+
+    // Principal plane and focal point for rays entering from the scene and
+    // from the film side.
+    fn thick_lens(&self) -> Option<((f32, f32), (f32, f32))> {
+        let x = 0.001 * (self.film_width.powi(2) + self.film_height.powi(2)).sqrt();
+
+        let scene = Ray::new(
+            Vec3::new(x, 0.0, self.front_z() + 1.0),
+            Vec3::new(0.0, 0.0, -1.0),
+        );
+        let film = self.trace_from_scene(scene)?;
+        let first = cardinal_points(scene, film);
+
+        let film = Ray::new(
+            Vec3::new(x, 0.0, self.rear_z() - 1.0),
+            Vec3::new(0.0, 0.0, 1.0),
+        );
+        let scene = self.trace_from_film(film)?;
+        let second = cardinal_points(film, scene);
+
+        Some((first, second))
+    }
+
+    // Searches the rear element for the largest radius any film point can
+    // still see through the system, so rays are not wasted on blocked areas.
+    fn bound_exit_pupil(&self) -> f32 {
+        const FILM_STEPS: usize = 16;
+        const GRID: usize = 64;
+
+        let search = 1.5 * self.rear_aperture();
+        let half_diagonal = 0.5 * (self.film_width.powi(2) + self.film_height.powi(2)).sqrt();
+        let mut radius: f32 = 0.0;
+
+        for f in 0..FILM_STEPS {
+            let film = Vec3::new(half_diagonal * f as f32 / (FILM_STEPS - 1) as f32, 0.0, 0.0);
+            for gx in 0..GRID {
+                for gy in 0..GRID {
+                    let x = search * (2.0 * (gx as f32 + 0.5) / GRID as f32 - 1.0);
+                    let y = search * (2.0 * (gy as f32 + 0.5) / GRID as f32 - 1.0);
+                    let rear = Vec3::new(x, y, self.rear_z());
+                    if self.trace_from_film(Ray::new(film, rear - film)).is_some() {
+                        radius = radius.max((x * x + y * y).sqrt());
+                    }
+                }
+            }
+        }
+
+        // Pad by one grid cell so the edge of the pupil is not clipped.
+        if radius > 0.0 {
+            (radius + search * 2.0 / GRID as f32).min(search)
+        } else {
+            self.rear_aperture()
+        }
+    }
+}
+
+fn flip(v: Vec3) -> Vec3 {
+    Vec3::new(v.x(), v.y(), -v.z())
+}
+
+// Intersects a spherical interface whose vertex is at `element_z` (lens
+// space), returning the distance and the normal facing the incoming ray.
+fn intersect_element(
+    radius: f32,
+    element_z: f32,
+    origin: Vec3,
+    direction: Vec3,
+) -> Option<(f32, Vec3)> {
+    let center = Vec3::new(0.0, 0.0, element_z + radius);
+    let oc = origin - center;
+    let a = direction.squared_length();
+    let b = 2.0 * Vec3::dot(&direction, &oc);
+    let c = oc.squared_length() - radius * radius;
+
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
+        return None;
+    }
+    let root = discriminant.sqrt();
+    let t0 = (-b - root) / (2.0 * a);
+    let t1 = (-b + root) / (2.0 * a);
+
+    let closer = (direction.z() > 0.0) ^ (radius < 0.0);
+    let t = if closer { t0.min(t1) } else { t0.max(t1) };
+    if t < 0.0 {
+        return None;
+    }
+
+    let mut normal = Vec3::unit_vector(&(oc + t * direction));
+    if Vec3::dot(&normal, &-direction) < 0.0 {
+        normal = -normal;
+    }
+    Some((t, normal))
+}
+
+fn refract(wi: Vec3, n: Vec3, eta: f32) -> Option<Vec3> {
+    let cos_i = Vec3::dot(&n, &wi);
+    let sin2_i = (1.0 - cos_i * cos_i).max(0.0);
+    let sin2_t = eta * eta * sin2_i;
+    if sin2_t >= 1.0 {
+        return None;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    Some(eta * -wi + (eta * cos_i - cos_t) * n)
+}
+
+fn cardinal_points(r_in: Ray, r_out: Ray) -> (f32, f32) {
+    let tf = -r_out.origin().x() / r_out.direction().x();
+    let fz = -r_out.point_at_parameter(tf).z();
+    let tp = (r_in.origin().x() - r_out.origin().x()) / r_out.direction().x();
+    let pz = -r_out.point_at_parameter(tp).z();
+    (pz, fz)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOUBLE_GAUSS: &str = include_str!("../lenses/dgauss.50mm.dat");
+
+    fn lens(focus_dist: f32) -> LensSystem {
+        LensSystem::new(
+            LensSystem::parse(DOUBLE_GAUSS).unwrap(),
+            35.0,
+            1.5,
+            None,
+            focus_dist,
+        )
+    }
+
+    #[test]
+    fn test_parse_prescription() {
+        let elements = LensSystem::parse(DOUBLE_GAUSS).unwrap();
+        assert_eq!(elements.len(), 11);
+        assert_eq!(elements[5].curvature_radius, 0.0);
+        assert!((elements[5].aperture_radius - 0.00855).abs() < 1e-6);
+        assert!(LensSystem::parse("1 2 3").is_err());
+    }
+
+    #[test]
+    fn test_focal_length() {
+        // Paraxial rays entering parallel to the axis converge about 50mm behind
+        // the rear principal plane.
+        let (first, _) = lens(10.0).thick_lens().unwrap();
+        let focal_length = first.1 - first.0;
+        assert!(
+            (focal_length.abs() - 0.05).abs() < 0.002,
+            "{}",
+            focal_length
+        );
+    }
+
+    #[test]
+    fn test_f_number() {
+        // A 50mm lens with a 17.1mm stop, narrowed to 10mm.
+        let wide = lens(10.0).f_number().unwrap();
+        assert!((wide - 2.9).abs() < 0.2, "{}", wide);
+        let narrow = LensSystem::new(
+            LensSystem::parse(DOUBLE_GAUSS).unwrap(),
+            35.0,
+            1.5,
+            Some(10.0),
+            10.0,
+        );
+        assert!((narrow.f_number().unwrap() - 5.0).abs() < 0.3);
+    }
+
+    #[test]
+    fn test_focus_converges() {
+        // Two rays from the film centre through different parts of the pupil
+        // meet again near the focus distance.
+        let lens = lens(2.0);
+        let film = Vec3::default();
+        let a = lens.sample_ray(film, Vec3::new(0.1, 0.0, 0.0)).unwrap();
+        let b = lens.sample_ray(film, Vec3::new(-0.1, 0.0, 0.0)).unwrap();
+
+        let t = -a.origin().x() / a.direction().x();
+        let z = a.point_at_parameter(t).z();
+        assert!((z - 2.0).abs() < 0.1, "{}", z);
+        let t = -b.origin().x() / b.direction().x();
+        assert!((b.point_at_parameter(t).z() - z).abs() < 0.05);
+    }
+
+    #[test]
+    fn test_stop_blocks_rays() {
+        let lens = lens(2.0);
+        let narrow = LensSystem::new(
+            LensSystem::parse(DOUBLE_GAUSS).unwrap(),
+            35.0,
+            1.5,
+            Some(2.0),
+            2.0,
+        );
+        assert!(narrow.pupil_radius < lens.pupil_radius);
+        assert!(lens
+            .sample_ray(Vec3::default(), Vec3::new(0.0, 0.0, 0.0))
+            .is_some());
+    }
+}
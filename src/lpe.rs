@@ -0,0 +1,282 @@
+use crate::hittable::HitRecord;
+use crate::material::Material;
+use crate::ray::Ray;
+use crate::vec3::Vec3;
+
+/// One vertex of a light path, written as a letter in expressions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    /// `C`, where every path starts.
+    Camera,
+    /// `D`, a bounce off a diffuse surface.
+    Diffuse,
+    /// `S`, a reflection off metal or glass.
+    Specular,
+    /// `T`, a refraction through glass.
+    Transmission,
+    /// `L`, a light of the scene's light group with this index, or the sky.
+    Light(usize),
+}
+
+impl Event {
+    /// The event of `ray_in` hitting `rec` and scattering into `scattered`.
+    pub fn scatter(ray_in: &Ray, rec: &HitRecord, scattered: &Ray) -> Event {
+        Event::between(
+            &rec.material,
+            rec.normal,
+            -ray_in.direction(),
+            scattered.direction(),
+        )
+    }
+
+    /// The event of a path passing a surface of `material` with normal
+    /// `normal`, whose neighbouring vertices lie in directions `a` and `b`.
+    pub fn between(material: &Material, normal: Vec3, a: Vec3, b: Vec3) -> Event {
+        match material {
+            // Lights do not scatter.
+            Material::Lambertian { .. } | Material::DiffuseLight { .. } => Event::Diffuse,
+            Material::Metal { .. } => Event::Specular,
+            Material::Dielectric { .. } => {
+                // A refracted path has its neighbours on either side.
+                if Vec3::dot(&a, &normal) * Vec3::dot(&b, &normal) < 0.0 {
+                    Event::Transmission
+                } else {
+                    Event::Specular
+                }
+            }
+        }
+    }
+
+    fn letter(&self) -> char {
+        match self {
+            Event::Camera => 'C',
+            Event::Diffuse => 'D',
+            Event::Specular => 'S',
+            Event::Transmission => 'T',
+            Event::Light(_) => 'L',
+        }
+    }
+}
+
+/// The letters of the events of a path, as expressions write them.
+pub fn text(events: &[Event]) -> String {
+    events.iter().map(Event::letter).collect()
+}
+
+/// The components every image is split into by `--lpes all`. Every path
+/// that reaches a light matches exactly one, so they add up to the image.
+pub const COMPONENTS: [(&str, &str); 5] = [
+    ("emission", "CL"),
+    ("diffuse-direct", "CDL"),
+    ("diffuse-indirect", "CD.+L"),
+    ("specular", "CS.*L"),
+    ("transmission", "CT.*L"),
+];
+
+/// Parses a comma-separated list of built-in component names, `all` for all
+/// of them, and `NAME=EXPR` pairs into names and expressions.
+pub fn parse_list(s: &str) -> Result<Vec<(String, String)>, String> {
+    let mut list = Vec::new();
+    for item in s.split(',').map(str::trim) {
+        let components = COMPONENTS
+            .iter()
+            .filter(|(name, _)| item == "all" || *name == item);
+        let count = list.len();
+        list.extend(components.map(|(name, text)| (name.to_string(), text.to_string())));
+        if list.len() == count {
+            let (name, text) = item
+                .split_once('=')
+                .ok_or_else(|| format!("unknown light path component '{}'", item))?;
+            list.push((name.to_string(), text.to_string()));
+        }
+    }
+    Ok(list)
+}
+
+/// A named light path expression: a regular expression over the events of a
+/// path, selecting the paths whose radiance goes into its buffer.
+///
+/// Letters match the events of `Event`, `L'NAME'` only lights of the group
+/// NAME, `.` any event and `[DS]` any of the letters inside. Each of these
+/// can be followed by `*`, `+` or `?`. The expression has to match the whole
+/// path, from the camera to the light.
+#[derive(Debug, Clone)]
+pub struct Lpe {
+    name: String,
+    text: String,
+    items: Vec<(Atom, Repeat)>,
+}
+
+#[derive(Debug, Clone)]
+enum Atom {
+    Any,
+    Letters(Vec<char>),
+    Group(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Repeat {
+    Once,
+    Optional,
+    Many,
+    OneOrMore,
+}
+
+impl Lpe {
+    /// Parses `text`, resolving light group names against `groups`.
+    pub fn new(name: &str, text: &str, groups: &[String]) -> Result<Lpe, String> {
+        let invalid = |why: &str| format!("invalid light path expression '{}': {}", text, why);
+        let mut items = Vec::new();
+        let mut chars = text.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            let atom = match c {
+                '.' => Atom::Any,
+                'C' | 'D' | 'S' | 'T' => Atom::Letters(vec![c]),
+                'L' if chars.peek() == Some(&'\'') => {
+                    chars.next();
+                    let group: String = chars.by_ref().take_while(|&c| c != '\'').collect();
+                    let index = groups
+                        .iter()
+                        .position(|g| *g == group)
+                        .ok_or_else(|| invalid(&format!("no light group '{}'", group)))?;
+                    Atom::Group(index)
+                }
+                'L' => Atom::Letters(vec![c]),
+                '[' => {
+                    let letters: Vec<char> = chars.by_ref().take_while(|&c| c != ']').collect();
+                    if letters.is_empty() || !letters.iter().all(|c| "CDSTL".contains(*c)) {
+                        return Err(invalid("bad letter set"));
+                    }
+                    Atom::Letters(letters)
+                }
+                _ => return Err(invalid(&format!("unexpected '{}'", c))),
+            };
+            let repeat = match chars.peek() {
+                Some('*') => Repeat::Many,
+                Some('+') => Repeat::OneOrMore,
+                Some('?') => Repeat::Optional,
+                _ => Repeat::Once,
+            };
+            if repeat != Repeat::Once {
+                chars.next();
+            }
+            items.push((atom, repeat));
+        }
+
+        Ok(Lpe {
+            name: name.to_string(),
+            text: text.to_string(),
+            items,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Whether the path made of `events` is selected.
+    pub fn matches(&self, events: &[Event]) -> bool {
+        matches(&self.items, events)
+    }
+}
+
+fn matches(items: &[(Atom, Repeat)], events: &[Event]) -> bool {
+    let Some(((atom, repeat), rest)) = items.split_first() else {
+        return events.is_empty();
+    };
+    let (min, max) = match repeat {
+        Repeat::Once => (1, 1),
+        Repeat::Optional => (0, 1),
+        Repeat::Many => (0, usize::MAX),
+        Repeat::OneOrMore => (1, usize::MAX),
+    };
+    // Paths are a few dozen events at most, so plain backtracking will do.
+    let run = events
+        .iter()
+        .take(max)
+        .take_while(|e| atom.matches(e))
+        .count();
+    (min..=run).rev().any(|n| matches(rest, &events[n..]))
+}
+
+impl Atom {
+    fn matches(&self, event: &Event) -> bool {
+        match (self, event) {
+            (Atom::Any, _) => true,
+            (Atom::Letters(letters), _) => letters.contains(&event.letter()),
+            (Atom::Group(group), Event::Light(light)) => group == light,
+            (Atom::Group(_), _) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(letters: &str) -> Vec<Event> {
+        letters
+            .chars()
+            .map(|c| match c {
+                'C' => Event::Camera,
+                'D' => Event::Diffuse,
+                'S' => Event::Specular,
+                'T' => Event::Transmission,
+                _ => Event::Light(c.to_digit(10).unwrap() as usize),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_components_partition_paths() {
+        let groups = [String::from("sky")];
+        let components: Vec<Lpe> = COMPONENTS
+            .iter()
+            .map(|(name, text)| Lpe::new(name, text, &groups).unwrap())
+            .collect();
+        for (letters, expected) in [
+            ("C0", "emission"),
+            ("CD0", "diffuse-direct"),
+            ("CDSTD0", "diffuse-indirect"),
+            ("CSD0", "specular"),
+            ("CTT0", "transmission"),
+        ] {
+            let matched: Vec<&str> = components
+                .iter()
+                .filter(|lpe| lpe.matches(&path(letters)))
+                .map(Lpe::name)
+                .collect();
+            assert_eq!(matched, [expected], "{}", letters);
+        }
+        // Paths that never reach a light match nothing.
+        assert!(components.iter().all(|lpe| !lpe.matches(&path("CDD"))));
+    }
+
+    #[test]
+    fn test_groups_and_sets() {
+        let groups = [String::from("key"), String::from("fill")];
+        let caustic = Lpe::new("caustic", "C[ST]+D?L'fill'", &groups).unwrap();
+        assert!(caustic.matches(&path("CTTD1")));
+        assert!(caustic.matches(&path("CS1")));
+        assert!(!caustic.matches(&path("CTTD0")));
+        assert!(!caustic.matches(&path("CD1")));
+
+        assert!(Lpe::new("x", "CL'rim'", &groups).is_err());
+        assert!(Lpe::new("x", "C(D)L", &groups).is_err());
+        assert!(Lpe::new("x", "C[DX]L", &groups).is_err());
+        assert_eq!(
+            parse_list("specular, glossy=CS+L").unwrap(),
+            [
+                (String::from("specular"), String::from("CS.*L")),
+                (String::from("glossy"), String::from("CS+L"))
+            ]
+        );
+        assert_eq!(parse_list("all,caustic=CTDL").unwrap().len(), 6);
+        assert!(parse_list("sheen").is_err());
+    }
+}
@@ -1,163 +1,247 @@
 use indicatif::ProgressBar;
-use rand::prelude::*;
-use rayon::prelude::*;
-use rayon::ThreadPoolBuilder;
-use std::cell::RefCell;
+use rayon::{ThreadPool, ThreadPoolBuilder};
 use std::env;
-use std::fs::File;
-use std::io::{self, Write};
+use std::fs;
+use std::io;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Instant;
 
+mod adaptive;
+mod aov;
+mod aperture;
+mod bdpt;
+mod bluenoise;
 mod camera;
+mod checkpoint;
+mod colorspace;
+mod denoise;
+mod distributed;
+mod exposure;
+mod exr;
+mod film;
+mod filter;
+mod firefly;
 mod hittable;
 mod hittable_list;
+mod integrator;
+mod lens;
+mod lpe;
+mod mat3;
 mod material;
+mod mlt;
+mod options;
+mod photon;
+mod ppm;
+mod preview;
 mod ray;
+mod render;
+mod sampler;
+mod scene;
+mod server;
 mod sphere;
+mod stereo;
+mod tiles;
+mod tonemap;
 mod vec3;
 
+use aov::Aov;
+use aperture::Aperture;
 use camera::Camera;
-use hittable::{HitRecord, Hittable};
-use hittable_list::HittableList;
-use material::scatter;
-use ray::Ray;
-use sphere::Sphere;
+use colorspace::ColorSpace;
+use denoise::{Denoiser, Guides};
+use distributed::Coordinator;
+use exposure::Exposure;
+use integrator::{Bounces, IntegratorKind};
+use lens::LensSystem;
+use lpe::Lpe;
+use mat3::Mat3;
+use options::Options;
+use render::{Job, Render, RenderSettings};
+use scene::Scene;
+use stereo::Eye;
+use tonemap::ToneMap;
 use vec3::Vec3;
 
-// Create a thread-local RNG
-thread_local! {
-    static THREAD_RNG: RefCell<ThreadRng> = RefCell::new(rand::thread_rng());
-}
+fn main() -> io::Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().is_some_and(|command| command == "denoise") {
+        return denoise_command(&args[1..]);
+    }
+    let options = Options::from_args(args.iter().cloned())?;
 
-fn color(r: &Ray, world: &HittableList, depth: i32) -> Vec3 {
-    let _rec = HitRecord::default();
+    // Set the number of worker threads
+    let num_threads = 8;
 
-    if let Some(rec) = world.hit(&r, 0.001, std::f32::MAX) {
-        let mut scattered = Ray::new(Vec3::default(), Vec3::default());
-        let mut attenuation = Vec3::default();
+    // Build a custom thread pool with the specified number of threads
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(num_threads)
+        .build()
+        .unwrap();
 
-        if depth < 50 && scatter(&rec.material, r, &rec, &mut attenuation, &mut scattered) {
-            return attenuation * color(&scattered, world, depth + 1);
-        } else {
-            return Vec3::new(0.0, 0.0, 0.0);
-        }
-    } else {
-        let unit_direction = Vec3::unit_vector(&r.direction());
-        let t = 0.5 * (unit_direction.y() + 1.0);
+    if let Some(address) = &options.worker {
+        // The coordinator sends its own command line, which sets up the same
+        // frame here.
+        let listener = TcpListener::bind(address)?;
+        eprintln!("worker listening on {}", listener.local_addr()?);
+        return distributed::serve(listener, &pool, |args| {
+            let (job, _, frame) = setup(&Options::from_args(args.iter().cloned())?)?;
+            Ok((job, frame))
+        });
+    }
 
-        Vec3::new(1.0, 1.0, 1.0) * (1.0 - t) + Vec3::new(0.5, 0.7, 1.0) * t
+    if let Some(address) = &options.serve {
+        let listener = TcpListener::bind(address)?;
+        eprintln!("serving on http://{}", listener.local_addr()?);
+        let dir = env::temp_dir().join(format!("raytracer-jobs-{}", process::id()));
+        return server::serve(listener, &dir, |args, bar, cancel| {
+            let options = Options::from_args(args.iter().cloned())?;
+            render(&options, args, &pool, bar, cancel)
+        });
     }
+
+    let bar = ProgressBar::new(0);
+    render(&options, &args, &pool, &bar, &AtomicBool::new(false)).map(|_| ())
 }
 
-fn random_in_unit_sphere() -> Vec3 {
-    THREAD_RNG.with(|rng| {
-        let mut rng = rng.borrow_mut();
-        let unit_vec = Vec3::new(1.0, 1.0, 1.0);
+/// Renders the frame described by `options`, given on the command line as
+/// `args`, and writes its images. Progress is counted in samples on `bar`.
+/// Once `cancel` is set the render stops as soon as it can, without writing
+/// anything more. Returns whether the render ran to the end.
+fn render(
+    options: &Options,
+    args: &[String],
+    pool: &ThreadPool,
+    bar: &ProgressBar,
+    cancel: &AtomicBool,
+) -> io::Result<bool> {
+    let (job, exposed, frame) = setup(options)?;
+    let Job {
+        scene,
+        cameras,
+        settings,
+    } = &job;
+    let mut coordinator = match &options.workers {
+        Some(addresses) => Some(Coordinator::connect(
+            addresses,
+            &distributed::forwarded_args(args),
+            &frame,
+        )?),
+        None => None,
+    };
+
+    let mut renders: Vec<Render> = match &options.resume {
+        Some(path) => checkpoint::load(path, settings, &frame)?,
+        None => cameras.iter().map(|_| Render::new(settings)).collect(),
+    };
+    if renders.len() != cameras.len() {
+        return Err(options::invalid(format!(
+            "checkpoint has {} images, this render needs {}",
+            renders.len(),
+            cameras.len()
+        )));
+    }
 
-        loop {
-            let p =
-                2.0 * Vec3::new(rng.gen::<f32>(), rng.gen::<f32>(), rng.gen::<f32>()) - unit_vec;
-            if p.squared_length() < 1.0 {
-                return p;
+    // Each pass refines every image a little; snapshots of the whole frame are
+    // written between passes until the samples or the time run out.
+    let pixels: usize = settings.tiles.iter().map(tiles::Tile::area).sum();
+    bar.set_length((cameras.len() * pixels * settings.samples) as u64);
+    let start = Instant::now();
+    let mut last_snapshot = start;
+    let mut last_checkpoint = start;
+    let mut passes = 0;
+    while !renders.iter().all(|render| render.is_done(settings)) {
+        if cancel.load(Ordering::Relaxed) {
+            return Ok(false);
+        }
+        match &mut coordinator {
+            Some(coordinator) => {
+                coordinator.pass(&mut renders, settings, options.pass_samples(), bar, cancel)?
+            }
+            None => {
+                for (render, camera) in renders.iter_mut().zip(cameras) {
+                    pool.install(|| {
+                        render.pass(camera, scene, settings, options.pass_samples(), bar, cancel)
+                    });
+                }
             }
         }
-    })
-}
-
-fn main() -> io::Result<()> {
-    let width = 720;
-    let height = 1024;
-    let samples = 500;
-    let max_value = 255;
-
-    // Set the number of worker threads
-    let num_threads = 8;
-
-    let mut list: Vec<Box<dyn Hittable + Send + Sync>> = Vec::new();
-
-    let mut rng = rand::thread_rng();
+        // A cancelled pass stops partway, with nothing worth writing.
+        if cancel.load(Ordering::Relaxed) {
+            return Ok(false);
+        }
+        passes += 1;
 
-    list.push(Box::new(Sphere::sphere(
-        Vec3::new(0.0, -1000.0, -1.0),
-        1000.0,
-        material::Material::Lambertian {
-            albedo: Vec3::new(0.5, 0.5, 0.5),
-        },
-    )));
-
-    for a in -11..11 {
-        for b in -11..11 {
-            let choose_mat = rng.gen::<f32>();
-            let center = Vec3::new(
-                a as f32 + 0.9 * rng.gen::<f32>(),
-                0.2,
-                b as f32 + 0.9 * rng.gen::<f32>(),
-            );
-            if (center - Vec3::new(4.0, 0.2, 0.0)).length() > 0.9 {
-                if choose_mat < 0.8 {
-                    // diffuse
-                    list.push(Box::new(Sphere::sphere(
-                        center,
-                        0.2,
-                        material::Material::Lambertian {
-                            albedo: Vec3::new(
-                                rng.gen::<f32>() * rng.gen::<f32>(),
-                                rng.gen::<f32>() * rng.gen::<f32>(),
-                                rng.gen::<f32>() * rng.gen::<f32>(),
-                            ),
-                        },
-                    )));
-                } else if choose_mat < 0.95 {
-                    //metal
-                    list.push(Box::new(Sphere::sphere(
-                        center,
-                        0.2,
-                        material::Material::Metal {
-                            albedo: Vec3::new(
-                                0.5 * (1.0 + rng.gen::<f32>()),
-                                0.5 * (1.0 + rng.gen::<f32>()),
-                                0.5 * (1.0 + rng.gen::<f32>()),
-                            ),
-                            fuzz: (0.5 * rng.gen::<f32>()),
-                        },
-                    )));
-                } else {
-                    //glass
-                    list.push(Box::new(Sphere::sphere(
-                        center,
-                        0.2,
-                        material::Material::Dielectric { ref_idx: 1.5 },
-                    )));
-                }
+        if options
+            .time_limit
+            .is_some_and(|limit| start.elapsed() >= limit)
+        {
+            break;
+        }
+        let snapshot = options.snapshot_passes.is_some_and(|n| passes % n == 0)
+            || options
+                .snapshot_interval
+                .is_some_and(|interval| last_snapshot.elapsed() >= interval);
+        if snapshot {
+            write_images(options, &renders, exposed, settings)?;
+            last_snapshot = Instant::now();
+        }
+        if let Some(path) = &options.checkpoint {
+            if last_checkpoint.elapsed() >= options.checkpoint_interval {
+                checkpoint::save(path, settings, &frame, &renders)?;
+                last_checkpoint = Instant::now();
             }
         }
     }
+    if cancel.load(Ordering::Relaxed) {
+        return Ok(false);
+    }
+    bar.finish();
+    for render in &renders {
+        let invalid = render.invalid_samples();
+        if invalid.count() > 0 {
+            eprintln!("{}", invalid.report());
+        }
+    }
 
-    list.push(Box::new(Sphere::sphere(
-        Vec3::new(0.0, 1.0, 0.0),
-        1.0,
-        material::Material::Dielectric { ref_idx: 1.5 },
-    )));
-
-    list.push(Box::new(Sphere::sphere(
-        Vec3::new(-4.0, 1.0, 0.0),
-        1.0,
-        material::Material::Lambertian {
-            albedo: Vec3::new(0.4, 0.2, 0.1),
-        },
-    )));
-
-    list.push(Box::new(Sphere::sphere(
-        Vec3::new(4.0, 1.0, 0.0),
-        1.0,
-        material::Material::Metal {
-            albedo: Vec3::new(0.7, 0.6, 0.5),
-            fuzz: 0.0,
-        },
-    )));
+    if let Some(path) = &options.checkpoint {
+        checkpoint::save(path, settings, &frame, &renders)?;
+    }
+    write_images(options, &renders, exposed, settings)?;
+    Ok(true)
+}
 
-    let world = Arc::new(HittableList::new(list));
+/// Builds the frame described by `options`, along with the matrix exposing
+/// and white balancing its linear working-space pixels and a summary of the
+/// scene and cameras that checkpoints of the frame are tied to.
+fn setup(options: &Options) -> io::Result<(Job, Mat3, String)> {
+    let width = 720;
+    let height = 1024;
+    let samples = options.samples;
+
+    // 8-bit images need a display encoding; the rest only fit an EXR.
+    if !options.output_space.is_display() && options.exr.is_none() {
+        return Err(options::invalid(format!(
+            "{} is scene-linear; write it with --exr",
+            options.output_space.name()
+        )));
+    }
 
+    let colors = options.scene_space.conversion_to(options.working_space);
+    let (scene, source) = match &options.scene {
+        Some(path) => {
+            let text = fs::read_to_string(path)?;
+            let scene = Scene::parse(&text, colors, options.sky_luminance);
+            (scene.map_err(options::invalid)?, text)
+        }
+        None => (
+            Scene::random(colors, options.sky_luminance, options.seed),
+            format!("random {}", options.seed),
+        ),
+    };
     let look_from = Vec3::new(13.0, 2.0, 3.0);
     let look_at = Vec3::new(0.0, 0.0, 0.0);
 
@@ -167,74 +251,433 @@ fn main() -> io::Result<()> {
 
     let vup = Vec3::new(0.0, 1.0, 0.0);
 
-    let camera = Camera::new(
+    let aspect = width as f32 / height as f32;
+    let projection = match &options.lens {
+        Some(path) => camera::Projection::Lens(Arc::new(LensSystem::new(
+            LensSystem::load(path)?,
+            options.film_diagonal,
+            aspect,
+            options.lens_stop,
+            dist_to_focus,
+        ))),
+        None => options.projection.clone(),
+    };
+
+    let camera_projection = projection.clone();
+    let mut camera = Camera::new(
         look_from,
         look_at,
         vup,
         20.0,
-        width as f32 / height as f32,
+        aspect,
         aperture,
         dist_to_focus,
+    )
+    .with_projection(projection)
+    .with_aperture(Aperture {
+        shape: options.aperture.clone(),
+        squeeze: options.squeeze,
+    });
+    // Physical exposure needs a real f-number, which the artistic default
+    // aperture is not.
+    let physical = options.iso.is_some() || options.shutter.is_some() || options.f_number.is_some();
+    if options.lens.is_some() {
+        if options.f_number.is_some() {
+            return Err(options::invalid(String::from(
+                "--f-number sets the thin-lens aperture; narrow a lens prescription with \
+                 --lens-stop",
+            )));
+        }
+    } else if physical {
+        camera = camera.with_f_number(options.f_number.unwrap_or(exposure::DEFAULT_F_NUMBER));
+    }
+    let f_number = camera.f_number();
+
+    // Exposure, white balance and the move into the output space all happen
+    // in one matrix applied to the linear working-space pixels.
+    let mut scale = 1.0;
+    if physical {
+        let settings = Exposure {
+            iso: options.iso.unwrap_or(100.0),
+            shutter: options.shutter.unwrap_or(1.0 / 125.0),
+            f_number,
+        };
+        scale = settings.scale();
+    }
+    // Exposure and white balance stay in the working space, where the image
+    // is tone mapped before it is converted to the output space.
+    let mut exposed = Mat3::diagonal(Vec3::new(scale, scale, scale));
+    if let Some(kelvin) = options.white_balance {
+        // The gains are computed for sRGB primaries.
+        let (srgb, working) = (ColorSpace::LinearSrgb, options.working_space);
+        exposed = srgb.conversion_to(working)
+            * Mat3::diagonal(exposure::white_balance(kelvin))
+            * working.conversion_to(srgb)
+            * exposed;
+    }
+    // Previews are data to look at, not light: they skip exposure and white
+    // balance, and `write_images` skips the colour spaces, as the sample
+    // heatmap does.
+    if let IntegratorKind::Preview(_) = options.integrator {
+        exposed = Mat3::IDENTITY;
+    }
+
+    let mut aovs = options.aovs.clone();
+    if options.denoise {
+        for guide in [Aov::Albedo, Aov::Normal] {
+            if !aovs.contains(&guide) {
+                aovs.push(guide);
+            }
+        }
+    }
+    let mut light_paths = options
+        .lpes
+        .iter()
+        .map(|(name, text)| Lpe::new(name, text, &scene.light_groups))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(options::invalid)?;
+    if options.light_groups {
+        for group in &scene.light_groups {
+            let text = format!("C.*L'{}'", group);
+            let lpe = Lpe::new(&format!("light-{}", group), &text, &scene.light_groups);
+            light_paths.push(lpe.map_err(options::invalid)?);
+        }
+    }
+    let integrator = options
+        .integrator
+        .with_photons(options.photons)
+        .with_metropolis(options.metropolis)
+        .with_occlusion(options.ao_distance);
+    if let IntegratorKind::Metropolis(_) = integrator {
+        let per_pixel = options.adaptive.is_some() || !aovs.is_empty();
+        let resumable = options.checkpoint.is_some() || options.resume.is_some();
+        if per_pixel || resumable || options.workers.is_some() {
+            return Err(options::invalid(String::from(
+                "the Metropolis integrator renders the whole frame at once and cannot be \
+                 combined with --adaptive, --aovs, --denoise, --checkpoint, --resume or --workers",
+            )));
+        }
+    }
+    // Bidirectional paths and photons are followed to `MAX_DEPTH` bounces,
+    // whatever their kinds, and their light is never clamped per bounce.
+    let follows_bounces = !matches!(
+        integrator,
+        IntegratorKind::Bidirectional | IntegratorKind::Photon(_)
     );
+    if !follows_bounces
+        && (options.bounces != Bounces::default() || options.fireflies.bounce.is_some())
+    {
+        return Err(options::invalid(String::from(
+            "the bidirectional and photon mapping integrators cannot be combined with \
+             --max-depth, --max-diffuse, --max-specular, --max-transmission, --roulette or \
+             --clamp-bounce",
+        )));
+    }
+    let settings = RenderSettings {
+        width,
+        height,
+        samples,
+        filter: options.filter,
+        sampler: options.sampler,
+        integrator,
+        bounces: options.bounces,
+        fireflies: options.fireflies,
+        seed: options.seed,
+        adaptive: options.adaptive,
+        tiles: tiles::layout(
+            width,
+            height,
+            options.tile_size,
+            options.tile_order,
+            options.crop,
+            options.tile_list.as_deref(),
+        ),
+        aovs,
+        light_paths,
+        luminance: options.working_space.luminance_weights(),
+    };
+
+    let cameras = match options.stereo {
+        None => vec![camera],
+        Some(mode) => {
+            let convergence = options.convergence.unwrap_or(dist_to_focus);
+            [Eye::Left, Eye::Right]
+                .map(|eye| {
+                    camera
+                        .clone()
+                        .eye(mode, options.interocular, convergence, eye)
+                })
+                .to_vec()
+        }
+    };
 
-    let bar = ProgressBar::new((height * width) as u64);
-    // bar.inc(1);
+    // Lens prescriptions and aperture masks are part of the projection and
+    // aperture, so their contents count too.
+    let scene_summary = format!(
+        "{} {:?} {:?} {}",
+        source, options.scene_space, options.working_space, options.sky_luminance
+    );
+    let camera_summary = format!(
+        "{:?} {:?} {} f/{} {:?} {} {:?}",
+        camera_projection,
+        options.aperture,
+        options.squeeze,
+        f_number,
+        options.stereo,
+        options.interocular,
+        options.convergence
+    );
+    let frame = format!(
+        "scene {:016x} camera {:016x}",
+        checkpoint::digest(scene_summary.as_bytes()),
+        checkpoint::digest(camera_summary.as_bytes())
+    );
 
-    // Get the output file name from the command-line arguments or default to "res.ppm"
-    let args: Vec<String> = env::args().collect();
-    let filename = if args.len() > 1 { &args[1] } else { "res.ppm" };
+    Ok((
+        Job {
+            scene,
+            cameras,
+            settings,
+        },
+        exposed,
+        frame,
+    ))
+}
 
-    let mut file = File::create(filename)?;
+/// Writes the image, and the AOVs, EXR and sample heatmap if asked for, from
+/// the current state of the renders: one, or one per eye in stereo.
+fn write_images(
+    options: &Options,
+    renders: &[Render],
+    exposed: Mat3,
+    settings: &RenderSettings,
+) -> io::Result<()> {
+    let (width, height) = (settings.width, settings.height);
+    let compose = |images: Vec<Vec<Vec3>>| match &images[..] {
+        [left, right] => stereo::compose(options.stereo_layout, left, right, width, height),
+        _ => (images.into_iter().next().unwrap(), width, height),
+    };
+
+    let (pixels, width, height) = compose(
+        renders
+            .iter()
+            .map(|render| match options.denoise {
+                true => denoised(options, render, settings),
+                false => render.image(),
+            })
+            .collect(),
+    );
+    // Previews are shown as they are, like the AOVs.
+    let (tonemap, working, space, dither) = match settings.integrator {
+        IntegratorKind::Preview(_) => {
+            let srgb = ColorSpace::LinearSrgb;
+            (ToneMap::Clamp, srgb, srgb, false)
+        }
+        _ => {
+            let (working, space) = (options.working_space, options.output_space);
+            (options.tonemap, working, space, options.dither)
+        }
+    };
+    let to_output = working.conversion_to(space);
+    let pixels: Vec<Vec3> = pixels.into_iter().map(|p| exposed.transform(p)).collect();
+    // Scene-linear output spaces only go into the EXR.
+    if space.is_display() {
+        let display = tonemap::to_display(&pixels, tonemap, working, space, dither);
+        ppm::write(&options.output, width, height, &display, space.name())?;
+    }
 
-    writeln!(file, "P3\n{} {}\n{}", width, height, max_value)?;
+    let aovs: Vec<Vec<Vec3>> = (0..settings.aovs.len())
+        .map(|i| {
+            compose(
+                renders
+                    .iter()
+                    .map(|r| r.aov_images().swap_remove(i))
+                    .collect(),
+            )
+            .0
+        })
+        .collect();
+    let light_paths: Vec<Vec<Vec3>> = (0..settings.light_paths.len())
+        .map(|i| {
+            let (pixels, _, _) = compose(
+                renders
+                    .iter()
+                    .map(|r| r.light_path_images().swap_remove(i))
+                    .collect(),
+            );
+            pixels.into_iter().map(|p| exposed.transform(p)).collect()
+        })
+        .collect();
+    match &options.exr {
+        Some(path) => {
+            // The EXR keeps the image as rendered, along with everything the
+            // denoise command needs to denoise it later.
+            let image: Vec<Vec3> = match options.denoise {
+                true => compose(renders.iter().map(Render::image).collect())
+                    .0
+                    .into_iter()
+                    .map(|p| to_output.transform(exposed.transform(p)))
+                    .collect(),
+                false => pixels.iter().map(|&p| to_output.transform(p)).collect(),
+            };
+            let white = exposed.transform(Vec3::new(1.0, 1.0, 1.0));
+            let scale = tonemap::luminance_in(white, settings.luminance);
+            let (variance, _, _) = compose(
+                renders
+                    .iter()
+                    .map(|render| {
+                        let variances = render.variances().into_iter();
+                        variances
+                            .map(|v| Vec3::new(v, v, v) * scale * scale)
+                            .collect()
+                    })
+                    .collect(),
+            );
 
-    // Build a custom thread pool with the specified number of threads
-    let pool = ThreadPoolBuilder::new()
-        .num_threads(num_threads)
-        .build()
-        .unwrap();
+            let mut channels = exr::Channel::from_pixels(&["R", "G", "B"], &image);
+            channels.extend(exr::Channel::from_pixels(&["variance"], &variance));
+            for (aov, values) in settings.aovs.iter().zip(&aovs) {
+                channels.extend(exr::Channel::from_pixels(aov.channels(), values));
+            }
+            for (lpe, pixels) in settings.light_paths.iter().zip(&light_paths) {
+                let names = ["R", "G", "B"].map(|c| format!("{}.{}", lpe.name(), c));
+                let names: Vec<&str> = names.iter().map(String::as_str).collect();
+                let pixels: Vec<Vec3> = pixels.iter().map(|&p| to_output.transform(p)).collect();
+                channels.extend(exr::Channel::from_pixels(&names, &pixels));
+            }
+            exr::write(path, width, height, &channels)?;
+        }
+        None => {
+            let requested = settings.aovs.iter().zip(&aovs);
+            for (aov, values) in requested.filter(|(aov, _)| options.aovs.contains(aov)) {
+                let srgb = ColorSpace::LinearSrgb;
+                let display =
+                    tonemap::to_display(&aov.display(values), ToneMap::Clamp, srgb, srgb, false);
+                let path = aov_path(&options.output, aov.name());
+                ppm::write(path, width, height, &display, ColorSpace::LinearSrgb.name())?;
+            }
+            // Light paths are shown like the image, though only their linear
+            // values in the EXR add up to it.
+            for (lpe, pixels) in settings.light_paths.iter().zip(&light_paths) {
+                let display = tonemap::to_display(pixels, tonemap, working, space, dither);
+                let path = aov_path(&options.output, lpe.name());
+                ppm::write(path, width, height, &display, space.name())?;
+            }
+        }
+    }
 
-    let pixels: Vec<Vec3> = pool.install(|| {
-        (0..height)
-            .into_par_iter()
-            .rev()
-            .flat_map(|j| {
-                (0..width)
-                    .into_par_iter()
-                    .map(|i| {
-                        let mut col = Vec3::default();
-
-                        for _ in 0..samples {
-                            let u = (i as f32
-                                + THREAD_RNG.with(|rng| rng.borrow_mut().gen::<f32>()))
-                                / width as f32;
-                            let v = (j as f32
-                                + THREAD_RNG.with(|rng| rng.borrow_mut().gen::<f32>()))
-                                / height as f32;
-
-                            let r = camera.get_ray(u, v);
-                            col = col + color(&r, &world, 0);
-                        }
-
-                        col = col / samples as f32;
-                        col = Vec3::new(col.r().sqrt(), col.g().sqrt(), col.b().sqrt());
-                        bar.inc(1);
-                        col
-                    })
-                    .collect::<Vec<Vec3>>()
-            })
-            .collect()
-    });
+    if let Some(path) = &options.spp_heatmap {
+        let most = match options.adaptive {
+            Some(_) => adaptive::MAX_FACTOR * options.samples,
+            None => options.samples,
+        };
+        let (heat, _, _) = compose(
+            renders
+                .iter()
+                .map(|render| adaptive::heatmap(&render.counts(), most))
+                .collect(),
+        );
+        let srgb = ColorSpace::LinearSrgb;
+        let display = tonemap::to_display(&heat, ToneMap::Clamp, srgb, srgb, false);
+        ppm::write(path, width, height, &display, srgb.name())?;
+    }
 
-    bar.finish();
+    Ok(())
+}
 
-    for pixel in pixels {
-        let ir = (255.99 * pixel.r()) as i32;
-        let ig = (255.99 * pixel.g()) as i32;
-        let ib = (255.99 * pixel.b()) as i32;
+fn denoiser(options: &Options) -> Denoiser {
+    Denoiser {
+        iterations: options.denoise_iterations,
+        ..Denoiser::default()
+    }
+}
 
-        writeln!(file, "{} {} {}", ir, ig, ib)?;
+/// The image of `render` denoised, guided by its albedo and normal AOVs and
+/// the variance of its pixels.
+fn denoised(options: &Options, render: &Render, settings: &RenderSettings) -> Vec<Vec3> {
+    let aovs = render.aov_images();
+    let guide = |aov: Aov| {
+        let i = settings.aovs.iter().position(|a| *a == aov)?;
+        Some(&aovs[i][..])
+    };
+    let variance = render.variances();
+    let guides = Guides {
+        albedo: guide(Aov::Albedo),
+        normal: guide(Aov::Normal),
+        variance: Some(&variance),
+    };
+    denoiser(options).denoise(&render.image(), &guides, settings.width, settings.height)
+}
+
+/// `raytracer denoise INPUT.exr OUTPUT [--name value ...]` denoises the image
+/// of an EXR written with `--exr`, guided by whichever of its albedo, normal
+/// and variance channels are there. An `.exr` output gets every channel of the
+/// input with the image replaced; anything else is written as a PPM with the
+/// usual display options.
+fn denoise_command(args: &[String]) -> io::Result<()> {
+    let Some((input, rest)) = args.split_first() else {
+        return Err(options::invalid(String::from(
+            "usage: raytracer denoise INPUT.exr OUTPUT [--name value ...]",
+        )));
+    };
+    let options = Options::from_args(rest.iter().cloned())?;
+
+    let image = exr::read(input)?;
+    let color = image
+        .pixels(&["R", "G", "B"])
+        .ok_or_else(|| options::invalid(format!("{} has no R, G and B channels", input)))?;
+    let albedo = image.pixels(Aov::Albedo.channels());
+    let normal = image.pixels(Aov::Normal.channels());
+    let variance = image
+        .channels
+        .iter()
+        .find(|c| c.name == "variance")
+        .map(|c| &c.values[..]);
+    let guides = Guides {
+        albedo: albedo.as_deref(),
+        normal: normal.as_deref(),
+        variance,
+    };
+    let denoised = denoiser(&options).denoise(&color, &guides, image.width, image.height);
+
+    if options.output.ends_with(".exr") {
+        let mut channels: Vec<exr::Channel> = image
+            .channels
+            .into_iter()
+            .filter(|c| !["R", "G", "B"].contains(&c.name.as_str()))
+            .collect();
+        channels.extend(exr::Channel::from_pixels(&["R", "G", "B"], &denoised));
+        exr::write(&options.output, image.width, image.height, &channels)
+    } else if !options.output_space.is_display() {
+        Err(options::invalid(format!(
+            "{} is scene-linear; write it to an .exr file",
+            options.output_space.name()
+        )))
+    } else {
+        // The EXR holds the output space; tone mapping happens in the
+        // working space, as when rendering.
+        let (working, space) = (options.working_space, options.output_space);
+        let to_working = space.conversion_to(working);
+        let pixels: Vec<Vec3> = denoised.iter().map(|&p| to_working.transform(p)).collect();
+        let display = tonemap::to_display(&pixels, options.tonemap, working, space, options.dither);
+        ppm::write(
+            &options.output,
+            image.width,
+            image.height,
+            &display,
+            options.output_space.name(),
+        )
     }
+}
 
-    Ok(())
+/// `res.ppm` becomes `res.NAME.ppm`.
+fn aov_path(output: &str, name: &str) -> PathBuf {
+    let path = Path::new(output);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    match path.extension() {
+        Some(extension) => {
+            path.with_file_name(format!("{}.{}.{}", stem, name, extension.to_string_lossy()))
+        }
+        None => path.with_file_name(format!("{}.{}", stem, name)),
+    }
 }
@@ -0,0 +1,98 @@
+use crate::vec3::Vec3;
+use std::ops;
+
+/// A row-major 3x3 matrix, used for colour transforms.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Mat3 {
+    rows: [[f32; 3]; 3],
+}
+
+impl Mat3 {
+    pub const IDENTITY: Mat3 = Mat3::new([[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]]);
+
+    pub const fn new(rows: [[f32; 3]; 3]) -> Mat3 {
+        Mat3 { rows }
+    }
+
+    /// Matrix with the given vectors as its columns.
+    pub fn from_columns(a: Vec3, b: Vec3, c: Vec3) -> Mat3 {
+        Mat3::new([
+            [a.x(), b.x(), c.x()],
+            [a.y(), b.y(), c.y()],
+            [a.z(), b.z(), c.z()],
+        ])
+    }
+
+    pub fn diagonal(d: Vec3) -> Mat3 {
+        Mat3::new([[d.x(), 0.0, 0.0], [0.0, d.y(), 0.0], [0.0, 0.0, d.z()]])
+    }
+
+    #[inline]
+    pub fn transform(&self, v: Vec3) -> Vec3 {
+        let r = &self.rows;
+        Vec3::new(
+            r[0][0] * v.x() + r[0][1] * v.y() + r[0][2] * v.z(),
+            r[1][0] * v.x() + r[1][1] * v.y() + r[1][2] * v.z(),
+            r[2][0] * v.x() + r[2][1] * v.y() + r[2][2] * v.z(),
+        )
+    }
+
+    pub fn inverse(&self) -> Mat3 {
+        let m = &self.rows;
+        let cofactor = |r0: usize, r1: usize, c0: usize, c1: usize| {
+            m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0]
+        };
+
+        let adjugate = [
+            [
+                cofactor(1, 2, 1, 2),
+                -cofactor(0, 2, 1, 2),
+                cofactor(0, 1, 1, 2),
+            ],
+            [
+                -cofactor(1, 2, 0, 2),
+                cofactor(0, 2, 0, 2),
+                -cofactor(0, 1, 0, 2),
+            ],
+            [
+                cofactor(1, 2, 0, 1),
+                -cofactor(0, 2, 0, 1),
+                cofactor(0, 1, 0, 1),
+            ],
+        ];
+        let det = m[0][0] * adjugate[0][0] + m[0][1] * adjugate[1][0] + m[0][2] * adjugate[2][0];
+
+        Mat3::new(adjugate.map(|row| row.map(|v| v / det)))
+    }
+}
+
+impl ops::Mul for Mat3 {
+    type Output = Mat3;
+
+    fn mul(self, rhs: Mat3) -> Self::Output {
+        let mut rows = [[0.0; 3]; 3];
+        for (i, row) in rows.iter_mut().enumerate() {
+            for (j, value) in row.iter_mut().enumerate() {
+                *value = (0..3).map(|k| self.rows[i][k] * rhs.rows[k][j]).sum();
+            }
+        }
+        Mat3 { rows }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inverse() {
+        let m = Mat3::new([[2.0, 0.0, 1.0], [1.0, 3.0, 0.0], [0.0, 1.0, 4.0]]);
+        let product = m * m.inverse();
+        for i in 0..3 {
+            for j in 0..3 {
+                let expected = if i == j { 1.0 } else { 0.0 };
+                assert!((product.rows[i][j] - expected).abs() < 1e-6);
+            }
+        }
+    }
+}
@@ -1,11 +1,24 @@
-use crate::{hittable::HitRecord, random_in_unit_sphere, ray::Ray, vec3::Vec3};
-use rand::Rng;
+use crate::{hittable::HitRecord, ray::Ray, sampler, vec3::Vec3};
+use std::f32::consts::PI;
 
 #[derive(Debug, Clone, Copy)]
 pub enum Material {
-    Lambertian { albedo: Vec3 },
-    Metal { albedo: Vec3, fuzz: f32 },
-    Dielectric { ref_idx: f32 },
+    Lambertian {
+        albedo: Vec3,
+    },
+    Metal {
+        albedo: Vec3,
+        fuzz: f32,
+    },
+    Dielectric {
+        ref_idx: f32,
+    },
+    /// Emits `emit` and reflects nothing; `group` is the index of its light
+    /// group in the scene.
+    DiffuseLight {
+        emit: Vec3,
+        group: usize,
+    },
 }
 
 impl Default for Material {
@@ -17,16 +30,21 @@ impl Default for Material {
     }
 }
 
+/// Scatters `ray_in` off the surface, using the three numbers in `u` for
+/// every random choice.
 pub fn scatter(
     material: &Material,
     ray_in: &Ray,
     rec: &HitRecord,
+    u: [f32; 3],
     attenuation: &mut Vec3,
     scattered: &mut Ray,
 ) -> bool {
+    let in_ball = || sampler::uniform_ball((u[0], u[1]), u[2]);
+
     match material {
         Material::Lambertian { albedo } => {
-            let target = rec.p + rec.normal + random_in_unit_sphere();
+            let target = rec.p + rec.normal + in_ball();
             *scattered = Ray::new(rec.p, target - rec.p);
             *attenuation = *albedo;
             true
@@ -34,7 +52,7 @@ pub fn scatter(
         Material::Metal { albedo, fuzz } => {
             let fuzz = fuzz.min(1.0);
             let reflected = reflect(&Vec3::unit_vector(&ray_in.direction()), &rec.normal);
-            *scattered = Ray::new(rec.p, reflected + fuzz * random_in_unit_sphere());
+            *scattered = Ray::new(rec.p, reflected + fuzz * in_ball());
             *attenuation = *albedo;
             Vec3::dot(&scattered.direction(), &rec.normal) > 0.0
         }
@@ -60,8 +78,7 @@ pub fn scatter(
             let refracted = refract(&ray_in.direction(), &outward_normal, ni_over_nt);
             let reflect_prob = refracted.map(|_| schlick(cosine, *ref_idx)).unwrap_or(1.0);
 
-            let mut rng = rand::thread_rng();
-            *scattered = if rng.gen::<f32>() < reflect_prob {
+            *scattered = if u[0] < reflect_prob {
                 Ray::new(rec.p, reflect(&ray_in.direction(), &rec.normal))
             } else {
                 Ray::new(rec.p, refracted.unwrap())
@@ -69,7 +86,85 @@ pub fn scatter(
 
             true
         }
+        Material::DiffuseLight { .. } => false,
+    }
+}
+
+/// Which way light is carried along a path.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transport {
+    /// The path was traced from the camera, so it samples directions towards
+    /// the light.
+    Radiance,
+    /// The path was traced from a light.
+    Importance,
+}
+
+impl Transport {
+    pub fn other(self) -> Transport {
+        match self {
+            Transport::Radiance => Transport::Importance,
+            Transport::Importance => Transport::Radiance,
+        }
+    }
+}
+
+/// Whether the surface scatters into a single direction.
+pub fn is_delta(material: &Material) -> bool {
+    match material {
+        Material::Metal { fuzz, .. } => *fuzz <= 0.0,
+        Material::Dielectric { .. } => true,
+        Material::Lambertian { .. } | Material::DiffuseLight { .. } => false,
+    }
+}
+
+/// The scattering function implied by `scatter` for light coming from
+/// direction `to_light` and leaving towards `to_camera`.
+pub fn bsdf(material: &Material, n: Vec3, to_camera: Vec3, to_light: Vec3) -> Vec3 {
+    let cos = Vec3::dot(&to_light, &n);
+    if cos <= 0.0 {
+        return Vec3::default();
+    }
+    match *material {
+        Material::Lambertian { albedo } => albedo * (2.0 * cos * cos / PI),
+        Material::Metal { albedo, fuzz } if fuzz > 0.0 => {
+            albedo * (glossy_pdf(reflect(&-to_camera, &n), fuzz, to_light) / cos)
+        }
+        _ => Vec3::default(),
+    }
+}
+
+/// Density over directions of sampling `to` after arriving from `from`.
+/// Paths from the camera sample diffuse surfaces as `scatter` does; paths
+/// from lights sample the cosine, which suits their throughput better.
+pub fn bsdf_pdf(material: &Material, n: Vec3, from: Vec3, to: Vec3, mode: Transport) -> f32 {
+    let cos = Vec3::dot(&to, &n);
+    if cos <= 0.0 {
+        return 0.0;
+    }
+    match *material {
+        Material::Lambertian { .. } => match mode {
+            Transport::Radiance => 2.0 * cos * cos * cos / PI,
+            Transport::Importance => cos / PI,
+        },
+        Material::Metal { fuzz, .. } if fuzz > 0.0 => glossy_pdf(reflect(&-from, &n), fuzz, to),
+        _ => 0.0,
+    }
+}
+
+// Density of the direction of `reflected + fuzz * x` with `x` uniform in the
+// unit ball: the length of the ray through the ball of radius `fuzz` around
+// `reflected`, weighted by the squared distance.
+fn glossy_pdf(reflected: Vec3, fuzz: f32, w: Vec3) -> f32 {
+    let fuzz = fuzz.min(1.0);
+    let cos = Vec3::dot(&reflected, &w);
+    let sin2 = 1.0 - cos * cos;
+    if cos <= 0.0 || sin2 > fuzz * fuzz {
+        return 0.0;
     }
+    let root = (fuzz * fuzz - sin2).sqrt();
+    let (near, far) = ((cos - root).max(0.0), cos + root);
+    (far.powi(3) - near.powi(3)) / (4.0 * PI * fuzz.powi(3))
 }
 
 fn schlick(cosine: f32, ref_idx: f32) -> f32 {
@@ -94,3 +189,26 @@ fn refract(v: &Vec3, n: &Vec3, ni_over_nt: f32) -> Option<Vec3> {
 pub fn reflect(v: &Vec3, n: &Vec3) -> Vec3 {
     *v - 2.0 * Vec3::dot(v, n) * *n
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glossy_pdf_integrates_to_one() {
+        let reflected = Vec3::unit_vector(&Vec3::new(0.3, 1.0, -0.2));
+        for fuzz in [0.2, 0.7, 1.0] {
+            let mut sum = 0.0;
+            let n = 400;
+            for i in 0..n {
+                for j in 0..n {
+                    let u = ((i as f32 + 0.5) / n as f32, (j as f32 + 0.5) / n as f32);
+                    let w = sampler::uniform_ball(u, 1.0);
+                    sum += glossy_pdf(reflected, fuzz, w) * 4.0 * PI;
+                }
+            }
+            let mean = sum / (n * n) as f32;
+            assert!((mean - 1.0).abs() < 0.02, "fuzz {}: {}", fuzz, mean);
+        }
+    }
+}
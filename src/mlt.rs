@@ -0,0 +1,567 @@
+use crate::camera::Camera;
+use crate::integrator::Integrator;
+use crate::render::RenderSettings;
+use crate::sampler::{self, Sampler};
+use crate::scene::Scene;
+use crate::tonemap;
+use crate::vec3::Vec3;
+use indicatif::ProgressBar;
+use rand::prelude::*;
+use rayon::prelude::*;
+use std::f32::consts::PI;
+
+/// How the Metropolis integrator explores the image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MetropolisSettings {
+    /// Independent paths traced to estimate the image's brightness and to
+    /// start the chains from.
+    pub bootstrap: usize,
+    /// Markov chains run side by side.
+    pub chains: usize,
+    /// Probability of a mutation replacing every number of the path rather
+    /// than nudging them.
+    pub large_step: f32,
+    /// Standard deviation of the nudges.
+    pub sigma: f32,
+}
+
+impl Default for MetropolisSettings {
+    fn default() -> Self {
+        MetropolisSettings {
+            bootstrap: 100_000,
+            chains: 1000,
+            large_step: 0.3,
+            sigma: 0.01,
+        }
+    }
+}
+
+/// Primary sample space Metropolis light transport, after Kelemen et al. and
+/// PBRT.
+///
+/// A path is made from a list of uniform numbers, which the path tracer
+/// takes as its samples. Chains of such lists are mutated, accepting each
+/// mutation in proportion to the luminance of the path it makes, so that
+/// paths carrying much light, however hard they are to find, are explored
+/// once found. Every path is splatted into the pixel it goes through; the
+/// image is normalised by the mean luminance of independent bootstrap paths.
+///
+/// The chains roam the whole image at once, so samples are counted over the
+/// frame rather than per pixel, and splats go into single pixels whatever
+/// the filter.
+pub struct Metropolis {
+    settings: MetropolisSettings,
+    seed: u64,
+    width: usize,
+    height: usize,
+    /// The rectangle of pixels the rendered tiles cover.
+    region: (usize, usize, usize, usize),
+    /// Mean luminance of the bootstrap paths, once they have been traced.
+    normalization: Option<f32>,
+    chains: Vec<Chain>,
+    mutations: u64,
+    image: Vec<Vec3>,
+    light_paths: Vec<Vec<Vec3>>,
+}
+
+/// A path of the chain: the pixel it goes through, its radiance and the
+/// shares of it selected by each light path expression.
+#[derive(Clone)]
+struct State {
+    pixel: usize,
+    radiance: Vec3,
+    shares: Vec<Vec3>,
+    luminance: f32,
+}
+
+struct Chain {
+    sampler: MetropolisSampler,
+    current: State,
+}
+
+/// A state added to the image with a weight.
+struct Splat {
+    state: State,
+    weight: f32,
+}
+
+impl Metropolis {
+    pub fn new(metropolis: MetropolisSettings, settings: &RenderSettings) -> Metropolis {
+        let pixels = settings.width * settings.height;
+        let tiles = &settings.tiles;
+        let region = (
+            tiles.iter().map(|t| t.x0).min().unwrap_or(0),
+            tiles.iter().map(|t| t.y0).min().unwrap_or(0),
+            tiles.iter().map(|t| t.x1).max().unwrap_or(0),
+            tiles.iter().map(|t| t.y1).max().unwrap_or(0),
+        );
+        Metropolis {
+            settings: metropolis,
+            seed: settings.seed,
+            width: settings.width,
+            height: settings.height,
+            region,
+            normalization: None,
+            chains: Vec::new(),
+            mutations: 0,
+            image: vec![Vec3::default(); pixels],
+            light_paths: vec![vec![Vec3::default(); pixels]; settings.light_paths.len()],
+        }
+    }
+
+    fn pixels(&self) -> u64 {
+        let (x0, y0, x1, y1) = self.region;
+        ((x1 - x0) * (y1 - y0)) as u64
+    }
+
+    /// Whether the chains have taken `samples` mutations per pixel.
+    pub fn is_done(&self, settings: &RenderSettings) -> bool {
+        self.mutations >= settings.samples as u64 * self.pixels()
+    }
+
+    /// Runs the chains for `samples` more mutations per pixel, tracing the
+    /// bootstrap paths first if this is the first pass.
+    pub fn pass(
+        &mut self,
+        camera: &Camera,
+        scene: &Scene,
+        settings: &RenderSettings,
+        integrator: &(dyn Integrator + Sync),
+        samples: usize,
+        bar: &ProgressBar,
+    ) {
+        let total = settings.samples as u64 * self.pixels();
+        let count = (samples as u64 * self.pixels()).min(total - self.mutations);
+        if self.normalization.is_none() {
+            let (normalization, chains) =
+                self.paths(camera, scene, settings, integrator).bootstrap();
+            self.normalization = Some(normalization);
+            self.chains = chains;
+        }
+
+        let mut chains = std::mem::take(&mut self.chains);
+        let paths = self.paths(camera, scene, settings, integrator);
+        let n = chains.len() as u64;
+        let splats: Vec<Vec<Splat>> = chains
+            .par_iter_mut()
+            .enumerate()
+            .map(|(i, chain)| {
+                let mutations = count / n + ((i as u64) < count % n) as u64;
+                let splats = paths.run(chain, mutations);
+                bar.inc(mutations);
+                splats
+            })
+            .collect();
+        self.chains = chains;
+        // Splats are added chain by chain, so the image does not depend on
+        // how the chains were scheduled.
+        for splat in splats.iter().flatten() {
+            let state = &splat.state;
+            self.image[state.pixel] = self.image[state.pixel] + state.radiance * splat.weight;
+            for (film, share) in self.light_paths.iter_mut().zip(&state.shares) {
+                film[state.pixel] = film[state.pixel] + *share * splat.weight;
+            }
+        }
+        self.mutations += count;
+    }
+
+    fn paths<'a>(
+        &'a self,
+        camera: &'a Camera,
+        scene: &'a Scene,
+        settings: &'a RenderSettings,
+        integrator: &'a (dyn Integrator + Sync),
+    ) -> Paths<'a> {
+        Paths {
+            metropolis: self,
+            camera,
+            scene,
+            settings,
+            integrator,
+        }
+    }
+
+    fn scale(&self) -> f32 {
+        match (self.normalization, self.mutations) {
+            (Some(b), mutations) if mutations > 0 => b * self.pixels() as f32 / mutations as f32,
+            _ => 0.0,
+        }
+    }
+
+    /// Linear radiance of every pixel from the mutations so far.
+    pub fn image(&self) -> Vec<Vec3> {
+        let scale = self.scale();
+        self.image.iter().map(|&v| v * scale).collect()
+    }
+
+    /// Radiance of the paths each light path expression selects.
+    pub fn light_path_images(&self) -> Vec<Vec<Vec3>> {
+        let scale = self.scale();
+        self.light_paths
+            .iter()
+            .map(|film| film.iter().map(|&v| v * scale).collect())
+            .collect()
+    }
+}
+
+/// What paths are traced in.
+struct Paths<'a> {
+    metropolis: &'a Metropolis,
+    camera: &'a Camera,
+    scene: &'a Scene,
+    settings: &'a RenderSettings,
+    integrator: &'a (dyn Integrator + Sync),
+}
+
+impl Paths<'_> {
+    /// Traces the path the numbers of `sampler` make: the first two pick a
+    /// point in the rendered region, the next two a point on the lens and the
+    /// rest go to the integrator.
+    fn trace(&self, sampler: &mut MetropolisSampler) -> State {
+        let m = self.metropolis;
+        let (x0, y0, x1, y1) = m.region;
+        let (u0, u1) = sampler.next_2d();
+        let x = x0 as f32 + u0 * (x1 - x0) as f32;
+        let y = y0 as f32 + u1 * (y1 - y0) as f32;
+        let lens = sampler.next_2d();
+        let ray = self
+            .camera
+            .get_ray(x / m.width as f32, 1.0 - y / m.height as f32, lens);
+
+        let mut shares = vec![Vec3::default(); self.settings.light_paths.len()];
+        let mut paths = Vec::new();
+        let fireflies = self.settings.fireflies;
+        let mut record = |events: &[_], value: Vec3| {
+            for (lpe, share) in self.settings.light_paths.iter().zip(shares.iter_mut()) {
+                if lpe.matches(events) {
+                    *share = *share + value;
+                }
+            }
+            if fireflies.abort {
+                paths.push((events.to_vec(), value));
+            }
+        };
+        let mut radiance = ray
+            .map(|r| {
+                self.integrator
+                    .radiance(&r, self.scene, sampler, 0, &mut record)
+            })
+            .unwrap_or_default();
+        let (px, py) = ((x as usize).min(x1 - 1), (y as usize).min(y1 - 1));
+        // Invalid paths are left black, so chains never stay on them.
+        let what = || format!("Metropolis path at pixel ({}, {})", px, py);
+        fireflies.check(&mut radiance, &mut shares, &paths, what);
+        let pixel = py * m.width + px;
+        State {
+            pixel,
+            radiance,
+            shares,
+            luminance: tonemap::luminance_in(radiance, self.settings.luminance).max(0.0),
+        }
+    }
+
+    /// Traces the bootstrap paths and starts every chain from one of them,
+    /// picked in proportion to its luminance. Returns their mean luminance
+    /// and the chains.
+    fn bootstrap(&self) -> (f32, Vec<Chain>) {
+        let m = self.metropolis;
+        let seed = |i: usize| sampler::hash(&[m.seed, i as u64]);
+        let luminances: Vec<f32> = (0..m.settings.bootstrap)
+            .into_par_iter()
+            .map(|i| {
+                self.trace(&mut MetropolisSampler::new(&m.settings, seed(i)))
+                    .luminance
+            })
+            .collect();
+        let sum: f64 = luminances.iter().map(|&l| l as f64).sum();
+        if sum <= 0.0 {
+            return (0.0, Vec::new());
+        }
+        let cdf: Vec<f64> = luminances
+            .iter()
+            .scan(0.0, |total, &l| {
+                *total += l as f64 / sum;
+                Some(*total)
+            })
+            .collect();
+
+        let chains = (0..m.settings.chains)
+            .into_par_iter()
+            .map(|c| {
+                let chain_seed = sampler::hash(&[m.seed, c as u64, m.settings.bootstrap as u64]);
+                let u = sampler::to_unit(chain_seed) as f64;
+                let i = cdf.partition_point(|&p| p < u).min(cdf.len() - 1);
+                // Retracing the picked path restores its numbers; the chain
+                // then mutates them with its own random numbers.
+                let mut sampler = MetropolisSampler::new(&m.settings, seed(i));
+                let current = self.trace(&mut sampler);
+                sampler.rng = StdRng::seed_from_u64(chain_seed);
+                Chain { sampler, current }
+            })
+            .collect();
+        ((sum / luminances.len() as f64) as f32, chains)
+    }
+
+    /// Mutates `chain` `count` times, returning the states to add to the
+    /// image. Both the current and the proposed state are added, weighted by
+    /// the probability of the chain moving to each, which wastes nothing of
+    /// rejected proposals.
+    fn run(&self, chain: &mut Chain, count: u64) -> Vec<Splat> {
+        let mut splats = Vec::new();
+        for _ in 0..count {
+            chain.sampler.start_iteration();
+            let proposed = self.trace(&mut chain.sampler);
+            let current = &chain.current;
+            let accept = match current.luminance > 0.0 {
+                true => (proposed.luminance / current.luminance).min(1.0),
+                false => 1.0,
+            };
+            if current.luminance > 0.0 && accept < 1.0 {
+                splats.push(Splat {
+                    state: current.clone(),
+                    weight: (1.0 - accept) / current.luminance,
+                });
+            }
+            if proposed.luminance > 0.0 {
+                splats.push(Splat {
+                    state: proposed.clone(),
+                    weight: accept / proposed.luminance,
+                });
+            }
+            if chain.sampler.rng.gen::<f32>() < accept {
+                chain.current = proposed;
+                chain.sampler.accept();
+            } else {
+                chain.sampler.reject();
+            }
+        }
+        splats
+    }
+}
+
+/// One number of the path, mutated lazily when it is next asked for.
+#[derive(Debug, Clone, Copy, Default)]
+struct PrimarySample {
+    value: f32,
+    /// Iteration it was last changed in.
+    modified: u64,
+    /// The value and iteration before that change, to go back to when the
+    /// mutation is rejected.
+    backup: f32,
+    backup_modified: u64,
+}
+
+/// The numbers of a path, handed out in order as samples and mutated either
+/// all at once by a large step or each nudged by a small one.
+struct MetropolisSampler {
+    rng: StdRng,
+    samples: Vec<PrimarySample>,
+    /// Next number to hand out.
+    index: usize,
+    iteration: u64,
+    large_step: bool,
+    last_large_step: u64,
+    probability: f32,
+    sigma: f32,
+}
+
+impl MetropolisSampler {
+    fn new(settings: &MetropolisSettings, seed: u64) -> MetropolisSampler {
+        MetropolisSampler {
+            rng: StdRng::seed_from_u64(seed),
+            samples: Vec::new(),
+            index: 0,
+            iteration: 0,
+            // The first path is made of fresh numbers.
+            large_step: true,
+            last_large_step: 0,
+            probability: settings.large_step,
+            sigma: settings.sigma,
+        }
+    }
+
+    fn start_iteration(&mut self) {
+        self.iteration += 1;
+        self.large_step = self.rng.gen::<f32>() < self.probability;
+        self.index = 0;
+    }
+
+    fn accept(&mut self) {
+        if self.large_step {
+            self.last_large_step = self.iteration;
+        }
+    }
+
+    fn reject(&mut self) {
+        for sample in self.samples.iter_mut() {
+            if sample.modified == self.iteration {
+                sample.value = sample.backup;
+                sample.modified = sample.backup_modified;
+            }
+        }
+        self.iteration -= 1;
+    }
+
+    /// Brings number `i` up to the current iteration.
+    fn mutate(&mut self, i: usize) {
+        if i >= self.samples.len() {
+            self.samples.resize(i + 1, PrimarySample::default());
+        }
+        let sample = &mut self.samples[i];
+        // Numbers not asked for since the last accepted large step would have
+        // been replaced by it.
+        if sample.modified < self.last_large_step {
+            sample.value = self.rng.gen();
+            sample.modified = self.last_large_step;
+        }
+        sample.backup = sample.value;
+        sample.backup_modified = sample.modified;
+        if self.large_step {
+            sample.value = self.rng.gen();
+        } else {
+            // All the small steps it missed, added up.
+            let steps = (self.iteration - sample.modified) as f32;
+            let sigma = self.sigma * steps.sqrt();
+            let (u0, u1): (f32, f32) = (self.rng.gen(), self.rng.gen());
+            let normal = (-2.0 * (1.0 - u0).ln()).sqrt() * (2.0 * PI * u1).cos();
+            sample.value = (sample.value + normal * sigma).rem_euclid(1.0);
+            if sample.value >= 1.0 {
+                sample.value = 0.0;
+            }
+        }
+        sample.modified = self.iteration;
+    }
+}
+
+impl Sampler for MetropolisSampler {
+    fn start_sample(&mut self, _x: usize, _y: usize, _index: usize) {
+        self.index = 0;
+    }
+
+    fn next_1d(&mut self) -> f32 {
+        let i = self.index;
+        self.index += 1;
+        if self
+            .samples
+            .get(i)
+            .is_none_or(|s| s.modified < self.iteration)
+        {
+            self.mutate(i);
+        }
+        self.samples[i].value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::colorspace::ColorSpace;
+    use crate::filter::Filter;
+    use crate::firefly::Fireflies;
+    use crate::integrator::{Bounces, IntegratorKind, PathTracer};
+    use crate::mat3::Mat3;
+    use crate::sampler::SamplerKind;
+    use crate::tiles::{self, TileOrder};
+
+    #[test]
+    fn test_rejected_mutations_restore_samples() {
+        let settings = MetropolisSettings {
+            large_step: 0.5,
+            ..MetropolisSettings::default()
+        };
+        let mut sampler = MetropolisSampler::new(&settings, 3);
+        let first: Vec<f32> = (0..6).map(|_| sampler.next_1d()).collect();
+        for _ in 0..20 {
+            sampler.start_iteration();
+            let mutated: Vec<f32> = (0..6).map(|_| sampler.next_1d()).collect();
+            assert_ne!(mutated, first);
+            assert!(mutated.iter().all(|u| (0.0..1.0).contains(u)));
+            sampler.reject();
+        }
+        sampler.start_iteration();
+        sampler.large_step = false;
+        sampler.sigma = 0.0;
+        let unchanged: Vec<f32> = (0..6).map(|_| sampler.next_1d()).collect();
+        assert_eq!(unchanged, first);
+    }
+
+    #[test]
+    fn test_matches_path_tracer() {
+        let scene = Scene::parse(
+            "sky 0.2 0.2 0.2 0.1 0.1 0.2\n\
+             sphere 0 -1000 0 1000 lambertian 0.5 0.5 0.5\n\
+             sphere 0 1 0 1 dielectric 1.5\n\
+             sphere 0 4 0 0.5 light 8 8 8\n",
+            Mat3::IDENTITY,
+            1.0,
+        )
+        .unwrap();
+        let camera = Camera::new(
+            Vec3::new(13.0, 2.0, 3.0),
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            20.0,
+            1.5,
+            0.0,
+            10.0,
+        );
+        let (width, height, samples) = (12, 8, 1024);
+        let settings = RenderSettings {
+            width,
+            height,
+            samples,
+            filter: Filter::default(),
+            sampler: SamplerKind::Independent,
+            integrator: IntegratorKind::Metropolis(MetropolisSettings::default()),
+            bounces: Bounces::default(),
+            fireflies: Fireflies::default(),
+            seed: 5,
+            adaptive: None,
+            tiles: tiles::layout(width, height, 4, TileOrder::Scanline, None, None),
+            aovs: Vec::new(),
+            light_paths: Vec::new(),
+            luminance: ColorSpace::LinearSrgb.luminance_weights(),
+        };
+        let metropolis = MetropolisSettings {
+            bootstrap: 100_000,
+            chains: 64,
+            ..MetropolisSettings::default()
+        };
+        let path_tracer = PathTracer::new(Bounces::default());
+        let mut mlt = Metropolis::new(metropolis, &settings);
+        let bar = ProgressBar::hidden();
+        while !mlt.is_done(&settings) {
+            mlt.pass(&camera, &scene, &settings, &path_tracer, 64, &bar);
+        }
+        let mean = |image: &[Vec3]| {
+            image.iter().fold(Vec3::default(), |sum, &v| sum + v) / image.len() as f32
+        };
+
+        let mut sampler = SamplerKind::Sobol.create(samples, 1);
+        let mut path = Vec::new();
+        for y in 0..height {
+            for x in 0..width {
+                let mut sum = Vec3::default();
+                for index in 0..samples {
+                    sampler.start_sample(x, y, index);
+                    let (jx, jy) = sampler.next_2d();
+                    let (s, t) = (
+                        (x as f32 + jx) / width as f32,
+                        (y as f32 + jy) / height as f32,
+                    );
+                    let r = camera.get_ray(s, 1.0 - t, sampler.next_2d()).unwrap();
+                    sum = sum
+                        + path_tracer.radiance(&r, &scene, sampler.as_mut(), index, &mut |_, _| {});
+                }
+                path.push(sum / samples as f32);
+            }
+        }
+        let (path, mlt) = (mean(&path), mean(&mlt.image()));
+        assert!(
+            (path - mlt).length() < 0.03 * path.length(),
+            "{:?} {:?}",
+            path,
+            mlt
+        );
+    }
+}
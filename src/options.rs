@@ -0,0 +1,433 @@
+use crate::aov::{self, Aov};
+use crate::aperture::ApertureShape;
+use crate::camera::Projection;
+use crate::colorspace::ColorSpace;
+use crate::exposure;
+use crate::filter::Filter;
+use crate::firefly::Fireflies;
+use crate::integrator::{Bounces, IntegratorKind};
+use crate::lpe;
+use crate::mlt::MetropolisSettings;
+use crate::photon::PhotonSettings;
+use crate::sampler::SamplerKind;
+use crate::stereo::{StereoLayout, StereoMode};
+use crate::tiles::{self, Crop, TileOrder};
+use crate::tonemap::ToneMap;
+use std::io;
+use std::time::Duration;
+
+/// Command-line settings for a render.
+///
+/// The first positional argument is the output file name (default `res.ppm`);
+/// everything else is passed as `--name value` pairs.
+pub struct Options {
+    pub output: String,
+    pub projection: Projection,
+    pub stereo: Option<StereoMode>,
+    pub stereo_layout: StereoLayout,
+    pub interocular: f32,
+    /// Zero-parallax distance; defaults to the focus distance.
+    pub convergence: Option<f32>,
+    /// Lens prescription file; replaces the perspective projection with a
+    /// traced lens system. Its millimetres are converted to metres, taking one
+    /// scene unit to be a metre.
+    pub lens: Option<String>,
+    pub film_diagonal: f32,
+    /// Aperture stop diameter in millimetres, narrowing the prescription's stop.
+    pub lens_stop: Option<f32>,
+    pub aperture: ApertureShape,
+    /// Anamorphic squeeze applied to the aperture.
+    pub squeeze: f32,
+    /// Physical exposure is used when any of ISO, shutter or f-number is given.
+    pub iso: Option<f32>,
+    pub shutter: Option<f32>,
+    /// Overrides the aperture diameter of the thin-lens camera; with physical
+    /// exposure it defaults to `exposure::DEFAULT_F_NUMBER`. A lens
+    /// prescription takes its f-number from its stop instead.
+    pub f_number: Option<f32>,
+    /// White balance in Kelvin.
+    pub white_balance: Option<f32>,
+    /// Luminance of the sky in cd/m².
+    pub sky_luminance: f32,
+    pub tonemap: ToneMap,
+    /// Adds triangular noise of up to one 8-bit level before quantising.
+    pub dither: bool,
+    /// Space scene colours are authored in.
+    pub scene_space: ColorSpace,
+    /// Space light transport is computed in.
+    pub working_space: ColorSpace,
+    /// Space of the written image, which is tone mapped in the working space
+    /// first. The scene-linear ACEScg needs `--exr`.
+    pub output_space: ColorSpace,
+    /// Pixel reconstruction filter.
+    pub filter: Filter,
+    /// Where in each pixel, on the lens and at each bounce samples are taken.
+    pub sampler: SamplerKind,
+    /// How light transport is estimated.
+    pub integrator: IntegratorKind,
+    /// How far the path tracers follow each path.
+    pub bounces: Bounces,
+    /// How bright and invalid samples are dealt with.
+    pub fireflies: Fireflies,
+    /// How far ambient occlusion looks for occluders.
+    pub ao_distance: f32,
+    /// Photons shot and gathered by the integrators that use them.
+    pub photons: PhotonSettings,
+    /// How the Metropolis integrator runs its chains.
+    pub metropolis: MetropolisSettings,
+    /// Seed for the scene and every sample; equal seeds give identical images.
+    pub seed: u64,
+    /// Samples per pixel; in adaptive mode the average, with noisy pixels
+    /// taking up to `adaptive::MAX_FACTOR` times as many.
+    pub samples: usize,
+    /// Error threshold that turns on adaptive sampling.
+    pub adaptive: Option<f32>,
+    /// Where to write an image of the samples each pixel took.
+    pub spp_heatmap: Option<String>,
+    /// Samples each pixel takes per progressive pass; see `pass_samples()`.
+    pub pass_samples: Option<usize>,
+    /// Write the image every this many passes.
+    pub snapshot_passes: Option<usize>,
+    /// Write the image whenever this much time has passed since the last one.
+    pub snapshot_interval: Option<Duration>,
+    /// Stop after the pass that exceeds this time, even if samples remain.
+    pub time_limit: Option<Duration>,
+    /// Where to save the render state so it can be resumed.
+    pub checkpoint: Option<String>,
+    /// How often to save the checkpoint; it is also saved at the end.
+    pub checkpoint_interval: Duration,
+    /// Checkpoint to continue from instead of starting from scratch.
+    pub resume: Option<String>,
+    /// Side of the square tiles handed to worker threads, in pixels.
+    pub tile_size: usize,
+    pub tile_order: TileOrder,
+    /// Only render this window of the frame.
+    pub crop: Option<Crop>,
+    /// Only render these tiles, numbered in scanline order.
+    pub tile_list: Option<Vec<usize>>,
+    /// Buffers recorded from the first hit, written next to the output as
+    /// `NAME.AOV.ppm` or into the EXR.
+    pub aovs: Vec<Aov>,
+    /// Light path expressions, as names and expressions, whose radiance is
+    /// written apart like the AOVs.
+    pub lpes: Vec<(String, String)>,
+    /// Also write the radiance of every light group of the scene apart.
+    pub light_groups: bool,
+    /// OpenEXR file to write the unclamped image and the AOVs to as layers.
+    pub exr: Option<String>,
+    /// Denoise the written image, guided by albedo and normal AOVs, which are
+    /// then always recorded.
+    pub denoise: bool,
+    pub denoise_iterations: usize,
+    /// Scene file to render instead of the random spheres; see `Scene::parse`.
+    pub scene: Option<String>,
+    /// Address to serve the HTTP job API on, instead of rendering.
+    pub serve: Option<String>,
+    /// Address to listen on as a worker for a coordinator, instead of
+    /// rendering.
+    pub worker: Option<String>,
+    /// Workers to hand the tiles to, as `host:port` addresses.
+    pub workers: Option<Vec<String>>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            output: String::from("res.ppm"),
+            projection: Projection::Perspective,
+            stereo: None,
+            stereo_layout: StereoLayout::SideBySide,
+            interocular: 0.065,
+            convergence: None,
+            lens: None,
+            film_diagonal: 35.0,
+            lens_stop: None,
+            aperture: ApertureShape::Circle,
+            squeeze: 1.0,
+            iso: None,
+            shutter: None,
+            f_number: None,
+            white_balance: None,
+            sky_luminance: 1.0,
+            tonemap: ToneMap::Clamp,
+            dither: false,
+            scene_space: ColorSpace::LinearSrgb,
+            working_space: ColorSpace::LinearSrgb,
+            output_space: ColorSpace::LinearSrgb,
+            filter: Filter::default(),
+            sampler: SamplerKind::Independent,
+            integrator: IntegratorKind::Path,
+            bounces: Bounces::default(),
+            fireflies: Fireflies::default(),
+            ao_distance: 1.0,
+            photons: PhotonSettings::default(),
+            metropolis: MetropolisSettings::default(),
+            seed: 0,
+            samples: 500,
+            adaptive: None,
+            spp_heatmap: None,
+            pass_samples: None,
+            snapshot_passes: None,
+            snapshot_interval: None,
+            time_limit: None,
+            checkpoint: None,
+            checkpoint_interval: Duration::from_secs(300),
+            resume: None,
+            tile_size: 32,
+            tile_order: TileOrder::Spiral,
+            crop: None,
+            tile_list: None,
+            aovs: Vec::new(),
+            lpes: Vec::new(),
+            light_groups: false,
+            exr: None,
+            denoise: false,
+            denoise_iterations: 3,
+            scene: None,
+            serve: None,
+            worker: None,
+            workers: None,
+        }
+    }
+}
+
+impl Options {
+    pub fn from_args<I: Iterator<Item = String>>(mut args: I) -> io::Result<Options> {
+        let mut options = Options::default();
+        let mut positional = 0;
+
+        while let Some(arg) = args.next() {
+            let Some(name) = arg.strip_prefix("--") else {
+                match positional {
+                    0 => options.output = arg,
+                    _ => return Err(invalid(format!("unexpected argument '{}'", arg))),
+                }
+                positional += 1;
+                continue;
+            };
+
+            let value = args
+                .next()
+                .ok_or_else(|| invalid(format!("missing value for --{}", name)))?;
+
+            match name {
+                "projection" => options.projection = value.parse().map_err(invalid)?,
+                "stereo" => options.stereo = Some(value.parse().map_err(invalid)?),
+                "stereo-layout" => options.stereo_layout = value.parse().map_err(invalid)?,
+                "interocular" => options.interocular = number(name, &value)?,
+                "convergence" => options.convergence = Some(number(name, &value)?),
+                "lens" => options.lens = Some(value),
+                "film-diagonal" => options.film_diagonal = number(name, &value)?,
+                "lens-stop" => options.lens_stop = Some(number(name, &value)?),
+                "aperture" => options.aperture = ApertureShape::from_spec(&value)?,
+                "squeeze" => {
+                    options.squeeze = number(name, &value)?;
+                    if !(options.squeeze > 0.0 && options.squeeze.is_finite()) {
+                        return Err(invalid(format!(
+                            "--squeeze must be positive, not {}",
+                            value
+                        )));
+                    }
+                }
+                "iso" => options.iso = Some(number(name, &value)?),
+                "shutter" => {
+                    options.shutter = Some(
+                        exposure::parse_shutter(&value)
+                            .ok_or_else(|| invalid(format!("invalid shutter time '{}'", value)))?,
+                    )
+                }
+                "f-number" => options.f_number = Some(number(name, &value)?),
+                "white-balance" => options.white_balance = Some(number(name, &value)?),
+                "sky-luminance" => options.sky_luminance = number(name, &value)?,
+                "tonemap" => options.tonemap = value.parse().map_err(invalid)?,
+                "dither" => options.dither = number(name, &value)?,
+                "scene-space" => options.scene_space = value.parse().map_err(invalid)?,
+                "working-space" => options.working_space = value.parse().map_err(invalid)?,
+                "output-space" => options.output_space = value.parse().map_err(invalid)?,
+                "filter" => options.filter = value.parse().map_err(invalid)?,
+                "sampler" => options.sampler = value.parse().map_err(invalid)?,
+                "integrator" => options.integrator = value.parse().map_err(invalid)?,
+                "max-depth" => options.bounces.total = number(name, &value)?,
+                "max-diffuse" => options.bounces.diffuse = number(name, &value)?,
+                "max-specular" => options.bounces.specular = number(name, &value)?,
+                "max-transmission" => options.bounces.transmission = number(name, &value)?,
+                "roulette" => {
+                    options.bounces.roulette = match value.as_str() {
+                        "off" => None,
+                        _ => Some(number(name, &value)?),
+                    }
+                }
+                "clamp-sample" => options.fireflies.sample = Some(number(name, &value)?),
+                "clamp-bounce" => options.fireflies.bounce = Some(number(name, &value)?),
+                "abort-on-nan" => options.fireflies.abort = number(name, &value)?,
+                "ao-distance" => options.ao_distance = number(name, &value)?,
+                "photons" => options.photons.photons = number(name, &value)?,
+                "photon-radius" => options.photons.radius = number(name, &value)?,
+                "photon-alpha" => options.photons.alpha = number(name, &value)?,
+                "mlt-bootstrap" => options.metropolis.bootstrap = number(name, &value)?,
+                "mlt-chains" => options.metropolis.chains = number(name, &value)?,
+                "mlt-large-step" => options.metropolis.large_step = number(name, &value)?,
+                "mlt-sigma" => options.metropolis.sigma = number(name, &value)?,
+                "seed" => options.seed = number(name, &value)?,
+                "samples" => options.samples = number(name, &value)?,
+                "adaptive" => options.adaptive = Some(number(name, &value)?),
+                "spp-heatmap" => options.spp_heatmap = Some(value),
+                "pass-samples" => options.pass_samples = Some(number(name, &value)?),
+                "snapshot-passes" => options.snapshot_passes = Some(number(name, &value)?),
+                "snapshot-interval" => options.snapshot_interval = Some(duration(&value)?),
+                "time-limit" => options.time_limit = Some(duration(&value)?),
+                "checkpoint" => options.checkpoint = Some(value),
+                "checkpoint-interval" => options.checkpoint_interval = duration(&value)?,
+                "resume" => options.resume = Some(value),
+                "tile-size" => options.tile_size = number(name, &value)?,
+                "tile-order" => options.tile_order = value.parse().map_err(invalid)?,
+                "crop" => options.crop = Some(value.parse().map_err(invalid)?),
+                "tiles" => options.tile_list = Some(tiles::parse_list(&value).map_err(invalid)?),
+                "aovs" => options.aovs = aov::parse_list(&value).map_err(invalid)?,
+                "lpes" => options.lpes = lpe::parse_list(&value).map_err(invalid)?,
+                "light-groups" => options.light_groups = number(name, &value)?,
+                "exr" => options.exr = Some(value),
+                "denoise" => options.denoise = number(name, &value)?,
+                "denoise-iterations" => options.denoise_iterations = number(name, &value)?,
+                "scene" => options.scene = Some(value),
+                "serve" => options.serve = Some(value),
+                "worker" => options.worker = Some(value),
+                "workers" => {
+                    options.workers = Some(value.split(',').map(|a| a.trim().to_string()).collect())
+                }
+                _ => return Err(invalid(format!("unknown option --{}", name))),
+            }
+        }
+
+        // The traced lens takes the place of the perspective projection, and
+        // its film is centred on the axis, so it cannot shear the frustum.
+        if options.lens.is_some() {
+            if !matches!(options.projection, Projection::Perspective) {
+                return Err(invalid(String::from(
+                    "--lens replaces the projection; leave out --projection",
+                )));
+            }
+            if options.stereo == Some(StereoMode::OffAxis) {
+                return Err(invalid(String::from(
+                    "--lens cannot shift its film for --stereo off-axis",
+                )));
+            }
+        }
+
+        // Only the panoramas move their eye around the rig; any other
+        // projection would render two identical eyes.
+        if options.stereo == Some(StereoMode::Ods) {
+            let panoramic = matches!(
+                options.projection,
+                Projection::Equirectangular | Projection::Cylindrical { .. }
+            );
+            if !panoramic {
+                return Err(invalid(String::from(
+                    "--stereo ods needs an equirectangular or cylindrical --projection",
+                )));
+            }
+        }
+
+        Ok(options)
+    }
+
+    /// Samples each pixel takes per pass. Unless set, passes are only made
+    /// small when something happens between them: a snapshot, the time limit
+    /// or a checkpoint. Otherwise the whole frame is rendered in one pass.
+    pub fn pass_samples(&self) -> usize {
+        let between_passes = self.snapshot_passes.is_some()
+            || self.snapshot_interval.is_some()
+            || self.time_limit.is_some()
+            || self.checkpoint.is_some();
+        match self.pass_samples {
+            Some(samples) => samples,
+            None if between_passes => 1,
+            None => self.samples,
+        }
+    }
+}
+
+fn number<T: std::str::FromStr>(name: &str, value: &str) -> io::Result<T> {
+    value
+        .parse()
+        .map_err(|_| invalid(format!("invalid value '{}' for --{}", value, name)))
+}
+
+pub fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+/// Parses a duration such as `90`, `90s`, `10m` or `1.5h`; bare numbers are
+/// seconds.
+fn duration(value: &str) -> io::Result<Duration> {
+    let (number, unit) = match value.char_indices().last() {
+        Some((i, 's')) => (&value[..i], 1.0),
+        Some((i, 'm')) => (&value[..i], 60.0),
+        Some((i, 'h')) => (&value[..i], 3600.0),
+        _ => (value, 1.0),
+    };
+    number
+        .parse::<f64>()
+        .ok()
+        .filter(|n| *n >= 0.0)
+        .map(|n| Duration::from_secs_f64(n * unit))
+        .ok_or_else(|| invalid(format!("invalid duration '{}'", value)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_duration() {
+        assert_eq!(duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(duration("10m").unwrap(), Duration::from_secs(600));
+        assert_eq!(duration("1.5h").unwrap(), Duration::from_secs(5400));
+        assert!(duration("soon").is_err());
+        assert!(duration("-1s").is_err());
+    }
+
+    #[test]
+    fn test_ods_needs_panorama() {
+        let parse = |args: &[&str]| Options::from_args(args.iter().map(|a| a.to_string()));
+        assert!(parse(&["--stereo", "ods", "--projection", "equirect"]).is_ok());
+        assert!(parse(&["--projection", "cylindrical:180", "--stereo", "ods"]).is_ok());
+        assert!(parse(&["--stereo", "ods"]).is_err());
+        assert!(parse(&["--stereo", "ods", "--projection", "fisheye"]).is_err());
+        assert!(parse(&["--stereo", "off-axis", "--projection", "fisheye"]).is_ok());
+    }
+
+    #[test]
+    fn test_lens_replaces_perspective_only() {
+        let parse = |args: &[&str]| Options::from_args(args.iter().map(|a| a.to_string()));
+        assert!(parse(&["--lens", "a.dat"]).is_ok());
+        assert!(parse(&["--lens", "a.dat", "--stereo", "parallel"]).is_ok());
+        assert!(parse(&["--lens", "a.dat", "--projection", "fisheye"]).is_err());
+        assert!(parse(&["--projection", "equirect", "--lens", "a.dat"]).is_err());
+        assert!(parse(&["--lens", "a.dat", "--stereo", "off-axis"]).is_err());
+    }
+
+    #[test]
+    fn test_pass_samples() {
+        let parse = |args: &[&str]| Options::from_args(args.iter().map(|a| a.to_string()));
+        assert_eq!(parse(&[]).unwrap().pass_samples(), 500);
+        assert_eq!(parse(&["--time-limit", "10m"]).unwrap().pass_samples(), 1);
+        assert_eq!(
+            parse(&["--snapshot-passes", "4"]).unwrap().pass_samples(),
+            1
+        );
+        let args = ["--snapshot-interval", "30s", "--pass-samples", "8"];
+        assert_eq!(parse(&args).unwrap().pass_samples(), 8);
+    }
+
+    #[test]
+    fn test_squeeze_must_be_positive() {
+        let parse = |value: &str| {
+            let args = ["--squeeze", value].map(String::from);
+            Options::from_args(args.into_iter())
+        };
+        assert_eq!(parse("1.33").unwrap().squeeze, 1.33);
+        assert!(parse("0").is_err());
+        assert!(parse("-2").is_err());
+    }
+}
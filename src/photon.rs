@@ -0,0 +1,703 @@
+use crate::firefly;
+use crate::hittable::{HitRecord, Hittable};
+use crate::integrator::{Bounces, Integrator, MAX_DEPTH};
+use crate::lpe::Event;
+use crate::material::{bsdf, bsdf_pdf, is_delta, scatter, Material, Transport};
+use crate::ray::Ray;
+use crate::sampler::{self, Sampler, SamplerKind};
+use crate::scene::Scene;
+use crate::vec3::Vec3;
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::f32::consts::PI;
+use std::ops::Range;
+use std::sync::{Arc, Mutex, OnceLock};
+
+/// Most photon maps kept at once. Pixels share the few sample indices of a
+/// pass, so passes are split into steps of at most this many samples.
+const MAX_MAPS: usize = 16;
+
+/// How many photons are shot and how they are gathered.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PhotonSettings {
+    /// Photons shot for every sample index.
+    pub photons: usize,
+    /// Radius photons are gathered in by the first sample of every pixel.
+    pub radius: f32,
+    /// Share of the photons each sample keeps as the radius shrinks, between
+    /// zero and one; lower shrinks faster, trading noise for less blur.
+    pub alpha: f32,
+}
+
+impl Default for PhotonSettings {
+    fn default() -> Self {
+        PhotonSettings {
+            photons: 20000,
+            radius: 0.1,
+            alpha: 0.7,
+        }
+    }
+}
+
+impl PhotonSettings {
+    /// The gathering radius of sample `index`. Following Knaus and Zwicker's
+    /// probabilistic progressive photon mapping, every sample gathers from
+    /// its own map with a radius shrinking so that the average of the samples
+    /// converges, its squared radius falling by `(i + alpha) / (i + 1)`.
+    pub fn radius(&self, index: usize) -> f32 {
+        let squared = (1..=index).fold(self.radius * self.radius, |r2, i| {
+            r2 * (i as f32 + self.alpha) / (i as f32 + 1.0)
+        });
+        squared.sqrt()
+    }
+}
+
+/// Progressive photon mapping on its own: camera paths are followed through
+/// glass and metal to the first diffuse surface, where all the light is
+/// estimated from the photons that landed around it.
+///
+/// Photons leave the sky through a disk covering the scene's bounds, so
+/// scenes with a huge ground sphere spread them very thin; those render
+/// better with `CausticPathTracer`.
+pub struct PhotonMapper {
+    maps: PhotonMaps,
+}
+
+impl PhotonMapper {
+    pub fn new(settings: PhotonSettings, seed: u64) -> PhotonMapper {
+        PhotonMapper {
+            maps: PhotonMaps::new(false, settings, seed),
+        }
+    }
+}
+
+/// The path tracer with caustics from a photon map: wherever a path meets a
+/// diffuse surface, the light reaching it from lights and the sky through
+/// only glass and sharp metal is gathered from photons, and the path no
+/// longer counts that light when it finds it itself.
+///
+/// Photons from the sky are aimed at the glass and metal spheres, so none
+/// are wasted on the rest of the scene.
+pub struct CausticPathTracer {
+    maps: PhotonMaps,
+    bounces: Bounces,
+    clamp: Option<f32>,
+}
+
+impl CausticPathTracer {
+    pub fn new(settings: PhotonSettings, seed: u64, bounces: Bounces) -> CausticPathTracer {
+        CausticPathTracer {
+            maps: PhotonMaps::new(true, settings, seed),
+            bounces,
+            clamp: None,
+        }
+    }
+
+    /// The same integrator, scaling the light a path finds itself after
+    /// bouncing down to at most `clamp` in its brightest channel. Light
+    /// gathered from photons is smooth already and left alone.
+    pub fn with_clamp(self, clamp: Option<f32>) -> CausticPathTracer {
+        CausticPathTracer { clamp, ..self }
+    }
+
+    /// `value` found at the end of `path`, clamped if the path bounced.
+    fn clamped(&self, value: Vec3, path: &[Event]) -> Vec3 {
+        match self.clamp {
+            Some(limit) if path.len() > 1 => value * firefly::clamp(value, limit),
+            _ => value,
+        }
+    }
+}
+
+/// Light landing on a diffuse surface.
+struct Photon {
+    p: Vec3,
+    /// Unit direction it came from.
+    from: Vec3,
+    power: Vec3,
+    /// Events of its path after the surface, from the surface to the light.
+    events: Box<[Event]>,
+}
+
+/// Photons in a balanced kd-tree: every range of the list has its median,
+/// which splits the rest along `axes`, in the middle.
+struct PhotonMap {
+    photons: Vec<Photon>,
+    axes: Vec<usize>,
+    radius: f32,
+}
+
+fn coordinate(v: Vec3, axis: usize) -> f32 {
+    match axis {
+        0 => v.x(),
+        1 => v.y(),
+        _ => v.z(),
+    }
+}
+
+impl PhotonMap {
+    fn new(mut photons: Vec<Photon>, radius: f32) -> PhotonMap {
+        let mut axes = vec![0; photons.len()];
+        build(&mut photons, &mut axes);
+        PhotonMap {
+            photons,
+            axes,
+            radius,
+        }
+    }
+
+    /// Calls `f` with every photon within the map's radius of `p`.
+    fn gather(&self, p: Vec3, f: &mut dyn FnMut(&Photon)) {
+        self.search(0, self.photons.len(), p, f);
+    }
+
+    fn search(&self, start: usize, end: usize, p: Vec3, f: &mut dyn FnMut(&Photon)) {
+        if start == end {
+            return;
+        }
+        let mid = (start + end) / 2;
+        let photon = &self.photons[mid];
+        let r2 = self.radius * self.radius;
+        if (photon.p - p).squared_length() <= r2 {
+            f(photon);
+        }
+        let d = coordinate(p, self.axes[mid]) - coordinate(photon.p, self.axes[mid]);
+        let (near, far) = match d < 0.0 {
+            true => ((start, mid), (mid + 1, end)),
+            false => ((mid + 1, end), (start, mid)),
+        };
+        self.search(near.0, near.1, p, f);
+        if d * d <= r2 {
+            self.search(far.0, far.1, p, f);
+        }
+    }
+}
+
+// Puts the median of `photons` along their widest axis in the middle, the
+// ones below it before and the others after, and does the same on both sides.
+fn build(photons: &mut [Photon], axes: &mut [usize]) {
+    if photons.is_empty() {
+        return;
+    }
+    let extent = |axis: usize| {
+        let values = photons.iter().map(|photon| coordinate(photon.p, axis));
+        let (min, max) = values.fold((f32::MAX, f32::MIN), |(min, max), v| {
+            (min.min(v), max.max(v))
+        });
+        max - min
+    };
+    let axis = (0..3)
+        .max_by(|&a, &b| extent(a).total_cmp(&extent(b)))
+        .unwrap();
+    let mid = photons.len() / 2;
+    photons.select_nth_unstable_by(mid, |a, b| {
+        coordinate(a.p, axis).total_cmp(&coordinate(b.p, axis))
+    });
+    axes[mid] = axis;
+    let (below, rest) = photons.split_at_mut(mid);
+    let (axes_below, axes_rest) = axes.split_at_mut(mid);
+    build(below, axes_below);
+    build(&mut rest[1..], &mut axes_rest[1..]);
+}
+
+/// The photon map of every sample index, traced the first time a pixel needs
+/// it. Each depends only on the scene, the seed and the index, like the
+/// samples themselves, so images do not depend on how they are split into
+/// tiles and passes.
+struct PhotonMaps {
+    /// Whether only caustic photons are kept.
+    caustics: bool,
+    settings: PhotonSettings,
+    seed: u64,
+    maps: Mutex<HashMap<usize, Arc<OnceLock<PhotonMap>>>>,
+}
+
+impl PhotonMaps {
+    fn new(caustics: bool, settings: PhotonSettings, seed: u64) -> PhotonMaps {
+        PhotonMaps {
+            caustics,
+            settings,
+            seed,
+            maps: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn cell(&self, index: usize) -> Arc<OnceLock<PhotonMap>> {
+        let mut maps = self.maps.lock().unwrap();
+        if !maps.contains_key(&index) && maps.len() >= MAX_MAPS {
+            let oldest = *maps.keys().min().unwrap();
+            maps.remove(&oldest);
+        }
+        maps.entry(index).or_default().clone()
+    }
+
+    /// Traces the maps of the first `MAX_MAPS` of `indices`, shooting the
+    /// photons of each in parallel. Must not be called from the parallel
+    /// work of a pass: a thread waiting there for the photons could take
+    /// over a pixel that waits for the same map.
+    fn prepare(&self, scene: &Scene, indices: Range<usize>) {
+        for index in indices.take(MAX_MAPS) {
+            self.cell(index)
+                .get_or_init(|| self.trace(scene, index, true));
+        }
+    }
+
+    fn get(&self, scene: &Scene, index: usize) -> Arc<OnceLock<PhotonMap>> {
+        let cell = self.cell(index);
+        // Maps not prepared before the pass are traced on one thread; the
+        // threads needing other indices can trace theirs meanwhile.
+        cell.get_or_init(|| self.trace(scene, index, false));
+        cell
+    }
+
+    /// Shoots the photons of sample `index` from the lights and the sky, on
+    /// every thread of the pool if `parallel`. Each photon has its own
+    /// samples, so the map is the same either way.
+    fn trace(&self, scene: &Scene, index: usize, parallel: bool) -> PhotonMap {
+        let radius = self.settings.radius(index);
+        let (bounds_center, bounds_radius) = scene.bounds;
+        let targets = match self.caustics {
+            true => scene.casters.clone(),
+            false => vec![scene.bounds],
+        };
+        let sky = scene.sky.horizon.squared_length() + scene.sky.zenith.squared_length() > 0.0;
+        let count = scene.lights.len() + (sky && !targets.is_empty()) as usize;
+        if count == 0 || self.settings.photons == 0 {
+            return PhotonMap::new(Vec::new(), radius);
+        }
+        let share = count as f32 / self.settings.photons as f32;
+
+        let seed = sampler::hash(&[self.seed, index as u64]);
+        let new_sampler = || SamplerKind::Independent.create(1, seed);
+        let emit = |sampler: &mut Box<dyn Sampler>, i: usize| {
+            sampler.start_sample(i, 0, 0);
+            let choice = sampler.next_1d();
+            let light = ((choice * count as f32) as usize).min(count - 1);
+            let (u, v) = (sampler.next_2d(), sampler.next_2d());
+            let (r, power, group) = match scene.lights.get(light) {
+                Some(light) => {
+                    let n = sampler::uniform_ball(u, 1.0);
+                    let w = sampler::cosine_direction(n, v);
+                    let area = 4.0 * PI * light.radius * light.radius;
+                    let r = Ray::new(light.center + n * light.radius, w);
+                    (r, light.emit * (PI * area * share), light.group)
+                }
+                None => {
+                    // A direction, then a point on the disk across it that
+                    // covers a target, started from outside the scene.
+                    let d = sampler::uniform_ball(u, 1.0);
+                    let target = sampler.next_1d();
+                    let k = ((target * targets.len() as f32) as usize).min(targets.len() - 1);
+                    let (center, radius) = targets[k];
+                    // The part of a cosine-distributed direction across `d`
+                    // is uniform in the unit disk.
+                    let w = sampler::cosine_direction(d, v);
+                    let q = center + (w - d * Vec3::dot(&w, &d)) * radius;
+                    let back = (center - bounds_center).length() + bounds_radius + radius + 1.0;
+                    // Targets overlap, so every one whose disk the line
+                    // crosses could have sent it.
+                    let area_pdf: f32 = targets
+                        .iter()
+                        .filter(|(c, r)| {
+                            let v = *c - q;
+                            (v - d * Vec3::dot(&v, &d)).squared_length() <= r * r
+                        })
+                        .map(|(_, r)| 1.0 / (PI * r * r))
+                        .sum::<f32>()
+                        / targets.len() as f32;
+                    let pdf = area_pdf / (4.0 * PI);
+                    let power = scene.sky.color(-d) * (share / pdf);
+                    (Ray::new(q - d * back, d), power, scene.sky.group)
+                }
+            };
+            let mut photons = Vec::new();
+            self.shoot(scene, r, power, group, sampler.as_mut(), &mut photons);
+            photons
+        };
+        let photons: Vec<Photon> = match parallel {
+            true => (0..self.settings.photons)
+                .into_par_iter()
+                .map_init(new_sampler, emit)
+                .flatten_iter()
+                .collect(),
+            false => {
+                let mut sampler = new_sampler();
+                (0..self.settings.photons)
+                    .flat_map(|i| emit(&mut sampler, i))
+                    .collect()
+            }
+        };
+        PhotonMap::new(photons, radius)
+    }
+
+    /// Follows a photon from a light, keeping it at diffuse surfaces.
+    fn shoot(
+        &self,
+        scene: &Scene,
+        mut r: Ray,
+        mut power: Vec3,
+        group: usize,
+        sampler: &mut dyn Sampler,
+        photons: &mut Vec<Photon>,
+    ) {
+        let mut trail = vec![Event::Light(group)];
+        // Whether the photon has only passed glass and sharp metal.
+        let mut caustic = false;
+        for depth in 0..=MAX_DEPTH {
+            let Some(rec) = scene.world.hit(&r, 0.001, f32::MAX) else {
+                return;
+            };
+            let from = -Vec3::unit_vector(&r.direction());
+            match rec.material {
+                Material::DiffuseLight { .. } => return,
+                Material::Lambertian { .. } if caustic || !self.caustics => {
+                    photons.push(Photon {
+                        p: rec.p,
+                        from,
+                        power,
+                        events: trail.iter().rev().copied().collect(),
+                    });
+                }
+                _ => {}
+            }
+            if depth == MAX_DEPTH {
+                return;
+            }
+
+            let (u0, u1) = sampler.next_2d();
+            let u = [u0, u1, sampler.next_1d()];
+            let w = match rec.material {
+                Material::Lambertian { .. } => sampler::cosine_direction(rec.normal, (u0, u1)),
+                _ => {
+                    let mut scattered = Ray::new(Vec3::default(), Vec3::default());
+                    let mut attenuation = Vec3::default();
+                    if !scatter(&rec.material, &r, &rec, u, &mut attenuation, &mut scattered) {
+                        return;
+                    }
+                    if is_delta(&rec.material) {
+                        power = power * attenuation;
+                    }
+                    Vec3::unit_vector(&scattered.direction())
+                }
+            };
+            if !is_delta(&rec.material) {
+                let n = rec.normal;
+                let pdf = bsdf_pdf(&rec.material, n, from, w, Transport::Importance);
+                if pdf <= 0.0 {
+                    return;
+                }
+                let f = bsdf(&rec.material, n, w, from);
+                power = power * f * (Vec3::dot(&w, &n).abs() / pdf);
+            }
+
+            caustic = is_delta(&rec.material) && (caustic || trail.len() == 1);
+            if (self.caustics && !caustic) || power.squared_length() == 0.0 {
+                return;
+            }
+            trail.push(Event::between(&rec.material, rec.normal, from, w));
+            r = Ray::new(rec.p, w);
+        }
+    }
+}
+
+/// Radiance leaving the diffuse surface at `rec` along `r` from the photons
+/// around it, each passed to `record` after the events in `path`.
+fn gather(
+    map: &PhotonMap,
+    r: &Ray,
+    rec: &HitRecord,
+    beta: Vec3,
+    path: &mut Vec<Event>,
+    record: &mut dyn FnMut(&[Event], Vec3),
+) -> Vec3 {
+    let to_camera = -Vec3::unit_vector(&r.direction());
+    let scale = 1.0 / (PI * map.radius * map.radius);
+    path.push(Event::Diffuse);
+    let start = path.len();
+    let mut total = Vec3::default();
+    map.gather(rec.p, &mut |photon| {
+        let f = bsdf(&rec.material, rec.normal, to_camera, photon.from);
+        if f.squared_length() == 0.0 {
+            return;
+        }
+        let value = beta * f * photon.power * scale;
+        path.truncate(start);
+        path.extend_from_slice(&photon.events);
+        record(path, value);
+        total = total + value;
+    });
+    path.truncate(start - 1);
+    total
+}
+
+impl Integrator for PhotonMapper {
+    fn prepare(&self, scene: &Scene, indices: Range<usize>) {
+        self.maps.prepare(scene, indices);
+    }
+
+    fn max_pass_samples(&self) -> Option<usize> {
+        Some(MAX_MAPS)
+    }
+
+    fn radiance(
+        &self,
+        r: &Ray,
+        scene: &Scene,
+        sampler: &mut dyn Sampler,
+        index: usize,
+        record: &mut dyn FnMut(&[Event], Vec3),
+    ) -> Vec3 {
+        let map = self.maps.get(scene, index);
+        let map = map.get().unwrap();
+        let mut path = vec![Event::Camera];
+        let (mut r, mut beta) = (*r, Vec3::new(1.0, 1.0, 1.0));
+        for depth in 0..=MAX_DEPTH {
+            let Some(rec) = scene.world.hit(&r, 0.001, f32::MAX) else {
+                path.push(Event::Light(scene.sky.group));
+                let value = beta * scene.sky.color(r.direction());
+                record(&path, value);
+                return value;
+            };
+            match rec.material {
+                Material::DiffuseLight { emit, group } => {
+                    path.push(Event::Light(group));
+                    record(&path, beta * emit);
+                    return beta * emit;
+                }
+                Material::Lambertian { .. } => {
+                    return gather(map, &r, &rec, beta, &mut path, record);
+                }
+                _ => {}
+            }
+
+            let mut scattered = Ray::new(Vec3::default(), Vec3::default());
+            let mut attenuation = Vec3::default();
+            let (u0, u1) = sampler.next_2d();
+            let u = [u0, u1, sampler.next_1d()];
+            if depth == MAX_DEPTH
+                || !scatter(&rec.material, &r, &rec, u, &mut attenuation, &mut scattered)
+            {
+                break;
+            }
+            path.push(Event::scatter(&r, &rec, &scattered));
+            beta = beta * attenuation;
+            r = scattered;
+        }
+        Vec3::default()
+    }
+}
+
+impl Integrator for CausticPathTracer {
+    fn prepare(&self, scene: &Scene, indices: Range<usize>) {
+        self.maps.prepare(scene, indices);
+    }
+
+    fn max_pass_samples(&self) -> Option<usize> {
+        Some(MAX_MAPS)
+    }
+
+    fn radiance(
+        &self,
+        r: &Ray,
+        scene: &Scene,
+        sampler: &mut dyn Sampler,
+        index: usize,
+        record: &mut dyn FnMut(&[Event], Vec3),
+    ) -> Vec3 {
+        let map = self.maps.get(scene, index);
+        let map = map.get().unwrap();
+        let mut path = vec![Event::Camera];
+        let (mut r, mut beta) = (*r, Vec3::new(1.0, 1.0, 1.0));
+        let mut total = Vec3::default();
+        // Whether the path has met a diffuse surface and passed only glass
+        // and sharp metal since, and whether it has passed any; the photons
+        // gathered there already brought the light such a path finds.
+        let (mut diffuse, mut caustic) = (false, false);
+        let mut taken = [0; 3];
+        loop {
+            let Some(rec) = scene.world.hit(&r, 0.001, f32::MAX) else {
+                if !caustic {
+                    let value = self.clamped(beta * scene.sky.color(r.direction()), &path);
+                    path.push(Event::Light(scene.sky.group));
+                    record(&path, value);
+                    total = total + value;
+                }
+                return total;
+            };
+            if let Material::DiffuseLight { emit, group } = rec.material {
+                if !caustic {
+                    let value = self.clamped(beta * emit, &path);
+                    path.push(Event::Light(group));
+                    record(&path, value);
+                    total = total + value;
+                }
+                return total;
+            }
+            if let Material::Lambertian { .. } = rec.material {
+                total = total + gather(map, &r, &rec, beta, &mut path, record);
+            }
+
+            let mut scattered = Ray::new(Vec3::default(), Vec3::default());
+            let mut attenuation = Vec3::default();
+            let (u0, u1) = sampler.next_2d();
+            let u = [u0, u1, sampler.next_1d()];
+            if !scatter(&rec.material, &r, &rec, u, &mut attenuation, &mut scattered) {
+                break;
+            }
+            let event = Event::scatter(&r, &rec, &scattered);
+            if !self.bounces.take(&mut taken, event) {
+                break;
+            }
+            beta = beta * attenuation;
+            match self.bounces.roulette(path.len() - 1, beta, sampler) {
+                Some(survival) => beta = beta / survival,
+                None => break,
+            }
+            path.push(event);
+            diffuse = match rec.material {
+                Material::Lambertian { .. } => true,
+                ref material => diffuse && is_delta(material),
+            };
+            caustic = diffuse && is_delta(&rec.material);
+            r = scattered;
+        }
+        total
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::Camera;
+    use crate::integrator::PathTracer;
+    use crate::mat3::Mat3;
+
+    #[test]
+    fn test_gather_finds_photons_in_radius() {
+        let mut sampler = SamplerKind::Independent.create(1, 7);
+        let points: Vec<Vec3> = (0..2000)
+            .map(|i| {
+                sampler.start_sample(i, 0, 0);
+                sampler::uniform_ball(sampler.next_2d(), sampler.next_1d())
+            })
+            .collect();
+        let photons = points
+            .iter()
+            .map(|&p| Photon {
+                p,
+                from: Vec3::default(),
+                power: Vec3::default(),
+                events: Box::new([]),
+            })
+            .collect();
+        let map = PhotonMap::new(photons, 0.2);
+        for centre in [
+            Vec3::default(),
+            Vec3::new(0.5, -0.3, 0.1),
+            Vec3::new(2.0, 0.0, 0.0),
+        ] {
+            let mut found = 0;
+            map.gather(centre, &mut |_| found += 1);
+            let expected = points
+                .iter()
+                .filter(|p| (**p - centre).length() <= 0.2)
+                .count();
+            assert_eq!(found, expected);
+        }
+
+        let settings = PhotonSettings::default();
+        assert_eq!(settings.radius(0), settings.radius);
+        assert!(settings.radius(100) < settings.radius(10));
+    }
+
+    #[test]
+    fn test_parallel_trace_matches_serial() {
+        let scene = Scene::parse(
+            "sphere 0 -1000 0 1000 lambertian 0.5 0.5 0.5\n\
+             sphere 0 1 0 1 dielectric 1.5\n\
+             sphere 0 6 0 1 light 4 4 4\n",
+            Mat3::IDENTITY,
+            1.0,
+        )
+        .unwrap();
+        let settings = PhotonSettings {
+            photons: 2000,
+            ..PhotonSettings::default()
+        };
+        let maps = PhotonMaps::new(false, settings, 5);
+        let serial = maps.trace(&scene, 3, false);
+        let parallel = maps.trace(&scene, 3, true);
+        assert!(!serial.photons.is_empty());
+        assert_eq!(serial.photons.len(), parallel.photons.len());
+        for (a, b) in serial.photons.iter().zip(&parallel.photons) {
+            assert_eq!((a.p, a.from, a.power), (b.p, b.from, b.power));
+        }
+    }
+
+    #[test]
+    fn test_caustics_match_path_tracer() {
+        // A glass ball focusing a small light onto the floor.
+        let scene = Scene::parse(
+            "sky 0 0 0 0 0 0\n\
+             sphere 0 -1000 0 1000 lambertian 0.5 0.5 0.5\n\
+             sphere 0 1 0 1 dielectric 1.5\n\
+             sphere 0 6 0 1 light 4 4 4\n",
+            Mat3::IDENTITY,
+            1.0,
+        )
+        .unwrap();
+        let camera = Camera::new(
+            Vec3::new(0.0, 6.0, 3.0),
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            40.0,
+            1.0,
+            0.0,
+            10.0,
+        );
+        let settings = PhotonSettings {
+            photons: 4000,
+            radius: 0.1,
+            alpha: 0.7,
+        };
+        let (size, samples) = (8, 256);
+        let mean = |integrator: &dyn Integrator| {
+            let mut sampler = SamplerKind::Sobol.create(samples, 1);
+            let mut sum = Vec3::default();
+            // Sample by sample, as passes do, so each map is traced once.
+            for index in 0..samples {
+                integrator.prepare(&scene, index..index + 1);
+                for y in 0..size {
+                    for x in 0..size {
+                        sampler.start_sample(x, y, index);
+                        let (jx, jy) = sampler.next_2d();
+                        let (s, t) = ((x as f32 + jx) / size as f32, (y as f32 + jy) / size as f32);
+                        let r = camera.get_ray(s, t, sampler.next_2d()).unwrap();
+                        sum = sum
+                            + integrator.radiance(
+                                &r,
+                                &scene,
+                                sampler.as_mut(),
+                                index,
+                                &mut |_, _| {},
+                            );
+                    }
+                }
+            }
+            sum / (size * size * samples) as f32
+        };
+        let path = mean(&PathTracer::new(Bounces::default()));
+        for integrator in [
+            &CausticPathTracer::new(settings, 1, Bounces::default()) as &dyn Integrator,
+            &PhotonMapper::new(settings, 1),
+        ] {
+            let estimate = mean(integrator);
+            assert!(
+                (path - estimate).length() < 0.03 * path.length(),
+                "{:?} {:?}",
+                path,
+                estimate
+            );
+        }
+    }
+}
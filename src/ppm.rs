@@ -0,0 +1,151 @@
+use crate::vec3::Vec3;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// A decoded netpbm image with channels scaled to `[0, 1]`, rows top to bottom.
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<Vec3>,
+}
+
+/// Reads a P2/P3 (ASCII) or P5/P6 (binary, 8-bit) netpbm image.
+pub fn read<P: AsRef<Path>>(path: P) -> io::Result<Image> {
+    decode(&fs::read(path)?)
+}
+
+/// Writes 8-bit pixels, rows top to bottom, as an ASCII (P3) pixmap. The
+/// colour space is recorded in a header comment.
+pub fn write<P: AsRef<Path>>(
+    path: P,
+    width: usize,
+    height: usize,
+    pixels: &[[u8; 3]],
+    color_space: &str,
+) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+
+    writeln!(file, "P3\n# colorspace: {}", color_space)?;
+    writeln!(file, "{} {}\n255", width, height)?;
+    for [r, g, b] in pixels {
+        writeln!(file, "{} {} {}", r, g, b)?;
+    }
+
+    file.flush()
+}
+
+pub fn decode(bytes: &[u8]) -> io::Result<Image> {
+    let mut pos = 0;
+    let magic = token(bytes, &mut pos)?;
+    let width: usize = number(bytes, &mut pos)?;
+    let height: usize = number(bytes, &mut pos)?;
+    let max_value: u32 = number(bytes, &mut pos)?;
+    let scale = 1.0 / max_value.max(1) as f32;
+
+    let channels = match magic.as_str() {
+        "P2" | "P5" => 1,
+        "P3" | "P6" => 3,
+        _ => return Err(bad(format!("unsupported netpbm format '{}'", magic))),
+    };
+
+    // Every value takes at least a byte of the file, so a header claiming
+    // more than fits is corrupt.
+    let count = width
+        .checked_mul(height)
+        .and_then(|pixels| pixels.checked_mul(channels))
+        .filter(|&count| count <= bytes.len())
+        .ok_or_else(|| bad(String::from("netpbm size does not fit in the file")))?;
+    let values: Vec<f32> = match magic.as_str() {
+        "P2" | "P3" => (0..count)
+            .map(|_| number::<u32>(bytes, &mut pos).map(|v| v as f32 * scale))
+            .collect::<io::Result<_>>()?,
+        _ => {
+            if max_value > 255 {
+                return Err(bad(String::from("16-bit binary netpbm is not supported")));
+            }
+            // A single whitespace byte separates the header from the raster.
+            let raster = (pos + 1)
+                .checked_add(count)
+                .and_then(|end| bytes.get(pos + 1..end))
+                .ok_or_else(|| bad(String::from("truncated netpbm raster")))?;
+            raster.iter().map(|&v| v as f32 * scale).collect()
+        }
+    };
+
+    let pixels = values
+        .chunks(channels)
+        .map(|c| match c {
+            [v] => Vec3::new(*v, *v, *v),
+            _ => Vec3::new(c[0], c[1], c[2]),
+        })
+        .collect();
+
+    Ok(Image {
+        width,
+        height,
+        pixels,
+    })
+}
+
+fn token(bytes: &[u8], pos: &mut usize) -> io::Result<String> {
+    loop {
+        match bytes.get(*pos) {
+            Some(b'#') => {
+                while bytes.get(*pos).is_some_and(|&b| b != b'\n') {
+                    *pos += 1;
+                }
+            }
+            Some(b) if b.is_ascii_whitespace() => *pos += 1,
+            Some(_) => break,
+            None => return Err(bad(String::from("unexpected end of netpbm data"))),
+        }
+    }
+
+    let start = *pos;
+    while bytes.get(*pos).is_some_and(|b| !b.is_ascii_whitespace()) {
+        *pos += 1;
+    }
+    Ok(String::from_utf8_lossy(&bytes[start..*pos]).into_owned())
+}
+
+fn number<T: std::str::FromStr>(bytes: &[u8], pos: &mut usize) -> io::Result<T> {
+    let token = token(bytes, pos)?;
+    token
+        .parse()
+        .map_err(|_| bad(format!("invalid number '{}' in netpbm data", token)))
+}
+
+fn bad(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_ascii() {
+        let image = decode(b"P3\n# comment\n2 1\n255\n255 0 0 0 0 255\n").unwrap();
+        assert_eq!((image.width, image.height), (2, 1));
+        assert_eq!(image.pixels[0], Vec3::new(1.0, 0.0, 0.0));
+        assert_eq!(image.pixels[1], Vec3::new(0.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn test_decode_binary_gray() {
+        let mut data = b"P5 2 2 255\n".to_vec();
+        data.extend_from_slice(&[0, 255, 255, 0]);
+        let image = decode(&data).unwrap();
+        assert_eq!(image.pixels[1], Vec3::new(1.0, 1.0, 1.0));
+        assert_eq!(image.pixels[3], Vec3::new(0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn test_oversized_headers_are_rejected() {
+        let huge = format!("P6 {} {} 255\n", usize::MAX / 2, 3);
+        assert!(decode(huge.as_bytes()).is_err());
+        assert!(decode(b"P6 1000 1000 255\n\0\0\0").is_err());
+        assert!(decode(b"P3 1000000 1000000 255\n0 0 0").is_err());
+    }
+}
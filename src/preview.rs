@@ -0,0 +1,171 @@
+use crate::adaptive;
+use crate::aov::Aov;
+use crate::hittable::Hittable;
+use crate::hittable_list;
+use crate::integrator::{Bounces, Integrator, PathTracer};
+use crate::lpe::Event;
+use crate::ray::Ray;
+use crate::sampler::{cosine_direction, Sampler};
+use crate::scene::Scene;
+use crate::vec3::Vec3;
+
+/// Bounces a path takes to come out white in the bounce heatmap.
+const HEAT_BOUNCES: usize = 16;
+
+/// Intersection tests a path costs to come out white in the cost heatmap.
+const HEAT_TESTS: usize = 4096;
+
+/// Quick shading modes for setting up a scene. The first three look only at
+/// what camera rays hit first; the heatmaps show how the path tracer fares.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Preview {
+    /// Shading normals, moved from `[-1, 1]` into `[0, 1]`.
+    Normals,
+    /// Surface colours without any lighting.
+    Albedo,
+    /// The share of the hemisphere above the surface, weighted by cosine,
+    /// left open by objects closer than this distance.
+    Occlusion(f32),
+    /// Bounces each path takes, as a heatmap.
+    Bounces,
+    /// Ray-object intersection tests each path makes, counted as they
+    /// happen, as a heatmap.
+    Cost,
+}
+
+/// Renders a `Preview` in place of the light arriving at the camera. Rays
+/// that miss the scene are black.
+pub struct PreviewIntegrator {
+    preview: Preview,
+    path: PathTracer,
+}
+
+impl PreviewIntegrator {
+    /// The heatmaps follow paths as far as `bounces` lets them.
+    pub fn new(preview: Preview, bounces: Bounces) -> PreviewIntegrator {
+        PreviewIntegrator {
+            preview,
+            path: PathTracer::new(bounces),
+        }
+    }
+
+    /// Bounces the path tracer's path for `r` takes. It traces one ray
+    /// more than that, to the light, the sky or the surface it stops at.
+    fn bounces(&self, r: &Ray, scene: &Scene, sampler: &mut dyn Sampler, index: usize) -> usize {
+        let mut bounces = 0;
+        self.path
+            .radiance(r, scene, sampler, index, &mut |path, _| {
+                bounces = path
+                    .iter()
+                    .filter(|e| !matches!(e, Event::Camera | Event::Light(_)))
+                    .count();
+            });
+        bounces
+    }
+}
+
+impl Integrator for PreviewIntegrator {
+    fn radiance(
+        &self,
+        r: &Ray,
+        scene: &Scene,
+        sampler: &mut dyn Sampler,
+        index: usize,
+        _record: &mut dyn FnMut(&[Event], Vec3),
+    ) -> Vec3 {
+        let hit = || scene.world.hit(r, 0.001, f32::MAX);
+        let heat = |count, max| adaptive::heatmap(&[count], max)[0];
+        match self.preview {
+            Preview::Normals => hit().map_or(Vec3::default(), |rec| {
+                Aov::Normal.display(&[Aov::Normal.value(r, &rec)])[0]
+            }),
+            Preview::Albedo => hit().map_or(Vec3::default(), |rec| Aov::Albedo.value(r, &rec)),
+            Preview::Occlusion(distance) => hit().map_or(Vec3::default(), |rec| {
+                // Look out from the side the camera sees.
+                let n = if Vec3::dot(&rec.normal, &r.direction()) > 0.0 {
+                    -rec.normal
+                } else {
+                    rec.normal
+                };
+                let ray = Ray::new(rec.p, cosine_direction(n, sampler.next_2d()));
+                match scene.world.hit(&ray, 0.001, distance) {
+                    Some(_) => Vec3::default(),
+                    None => Vec3::new(1.0, 1.0, 1.0),
+                }
+            }),
+            Preview::Bounces => heat(self.bounces(r, scene, sampler, index), HEAT_BOUNCES),
+            Preview::Cost => {
+                let before = hittable_list::tests();
+                self.bounces(r, scene, sampler, index);
+                heat((hittable_list::tests() - before) as usize, HEAT_TESTS)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mat3::Mat3;
+    use crate::sampler::SamplerKind;
+
+    #[test]
+    fn test_previews() {
+        let scene = Scene::parse(
+            "sphere 0 -1000 0 1000 lambertian 0.8 0.6 0.2\n\
+             sphere 0 1 0 1 lambertian 0.5 0.5 0.5\n",
+            Mat3::IDENTITY,
+            1.0,
+        )
+        .unwrap();
+        let mut sampler = SamplerKind::Independent.create(1, 1);
+        let mut mean = |preview, origin: Vec3, direction: Vec3| {
+            let integrator = PreviewIntegrator::new(preview, Bounces::default());
+            let r = Ray::new(origin, direction);
+            let samples = 1000;
+            let mut sum = Vec3::default();
+            for index in 0..samples {
+                sampler.start_sample(0, 0, index);
+                sum =
+                    sum + integrator.radiance(&r, &scene, sampler.as_mut(), index, &mut |_, _| {});
+            }
+            sum / samples as f32
+        };
+        let down = Vec3::new(0.0, -1.0, 0.0);
+        let far = Vec3::new(10.0, 1.0, 0.0);
+        let close = |a: Vec3, b: Vec3, tolerance| (a - b).length() < tolerance;
+        // The ground is a huge sphere, so its normal leans a little.
+        let normal = mean(Preview::Normals, far, down);
+        assert!(close(normal, Vec3::new(0.5, 1.0, 0.5), 0.01));
+        assert!(close(
+            mean(Preview::Albedo, far, down),
+            Vec3::new(0.8, 0.6, 0.2),
+            1e-4
+        ));
+        assert!(close(
+            mean(Preview::Occlusion(1.0), far, down),
+            Vec3::new(1.0, 1.0, 1.0),
+            1e-4
+        ));
+
+        // Next to where the sphere rests the ground is half hidden, unless
+        // occluders are looked for only very close by.
+        let near = Vec3::new(1.2, 1.0, 0.0);
+        let occlusion = mean(Preview::Occlusion(10.0), near, down).x();
+        assert!(occlusion > 0.1 && occlusion < 0.9, "{}", occlusion);
+        assert_eq!(mean(Preview::Occlusion(0.001), near, down).x(), 1.0);
+
+        // A ray that misses costs one test per object.
+        let up = Vec3::new(0.0, 1.0, 0.0);
+        assert!(close(
+            mean(Preview::Bounces, far, up),
+            Vec3::default(),
+            1e-4
+        ));
+        assert!(close(
+            mean(Preview::Cost, far, up),
+            adaptive::heatmap(&[2], HEAT_TESTS)[0],
+            1e-4
+        ));
+    }
+}
@@ -0,0 +1,880 @@
+use crate::adaptive::{self, PixelStats};
+use crate::aov::Aov;
+use crate::camera::Camera;
+use crate::checkpoint;
+use crate::film::Film;
+use crate::filter::Filter;
+use crate::firefly::{Fireflies, InvalidSamples};
+use crate::hittable::Hittable;
+use crate::integrator::{Bounces, Integrator, IntegratorKind};
+use crate::lpe::Lpe;
+use crate::mlt::Metropolis;
+use crate::sampler::SamplerKind;
+use crate::scene::Scene;
+use crate::tiles::Tile;
+use crate::tonemap;
+use crate::vec3::Vec3;
+use indicatif::ProgressBar;
+use rayon::prelude::*;
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Everything a frame is rendered from: the scene, one camera per image and
+/// how they are sampled.
+pub struct Job {
+    pub scene: Scene,
+    pub cameras: Vec<Camera>,
+    pub settings: RenderSettings,
+}
+
+/// How an image is sampled and reconstructed.
+pub struct RenderSettings {
+    pub width: usize,
+    pub height: usize,
+    pub samples: usize,
+    pub filter: Filter,
+    pub sampler: SamplerKind,
+    pub integrator: IntegratorKind,
+    /// How far the path tracers follow each path.
+    pub bounces: Bounces,
+    /// How bright and invalid samples are dealt with.
+    pub fireflies: Fireflies,
+    pub seed: u64,
+    /// Error threshold for adaptive sampling; `samples` is then the average
+    /// budget, which noisy pixels may exceed by what converged pixels save.
+    pub adaptive: Option<f32>,
+    /// The tiles to render, in the order they are handed out to threads.
+    pub tiles: Vec<Tile>,
+    /// Buffers to record from the first hit of every camera ray.
+    pub aovs: Vec<Aov>,
+    /// Buffers to gather the radiance of the paths each expression selects
+    /// in.
+    pub light_paths: Vec<Lpe>,
+    /// Luminance weights of the working space, by which samples are judged.
+    pub luminance: Vec3,
+}
+
+/// An image refined pass by pass: the filtered samples so far, one film per
+/// AOV and light path expression and the statistics of every pixel, in
+/// top-to-bottom row order.
+pub struct Render {
+    film: Film,
+    aovs: Vec<Film>,
+    light_paths: Vec<Film>,
+    stats: Vec<PixelStats>,
+    integrator: Box<dyn Integrator + Send + Sync>,
+    /// The chains and splats of a Metropolis render, which replace the
+    /// per-pixel samples.
+    metropolis: Option<Metropolis>,
+    /// Samples that were NaN or infinite.
+    invalid: InvalidSamples,
+}
+
+impl Render {
+    pub fn new(settings: &RenderSettings) -> Render {
+        Render {
+            film: Film::new(settings.width, settings.height, settings.filter),
+            // AOVs are not filtered: averaging positions or normals across
+            // neighbouring pixels would blur edges that denoisers rely on.
+            aovs: settings
+                .aovs
+                .iter()
+                .map(|_| Film::new(settings.width, settings.height, Filter::default()))
+                .collect(),
+            // Light paths are filtered like the image, so those that split it
+            // add up to it exactly.
+            light_paths: settings
+                .light_paths
+                .iter()
+                .map(|_| Film::new(settings.width, settings.height, settings.filter))
+                .collect(),
+            stats: vec![PixelStats::default(); settings.width * settings.height],
+            integrator: settings.integrator.create(
+                settings.seed,
+                settings.bounces,
+                settings.fireflies.bounce,
+            ),
+            metropolis: match settings.integrator {
+                IntegratorKind::Metropolis(metropolis) => {
+                    Some(Metropolis::new(metropolis, settings))
+                }
+                _ => None,
+            },
+            invalid: InvalidSamples::default(),
+        }
+    }
+
+    /// Whether every pixel of the rendered tiles has taken all the samples it
+    /// needs, and none is left to hand the saved budget to.
+    pub fn is_done(&self, settings: &RenderSettings) -> bool {
+        if let Some(metropolis) = &self.metropolis {
+            return metropolis.is_done(settings);
+        }
+        self.all_finished(settings) && self.grants(settings).is_empty()
+    }
+
+    fn all_finished(&self, settings: &RenderSettings) -> bool {
+        settings.tiles.iter().all(|tile| {
+            tile.pixels()
+                .all(|(x, y)| finished(&self.stats[y * settings.width + x], settings))
+        })
+    }
+
+    /// Once every pixel has finished, gives the samples the converged pixels
+    /// saved to those still noisy, so they are taken in the next passes. Only
+    /// does anything in adaptive mode.
+    pub fn redistribute(&mut self, settings: &RenderSettings) {
+        if self.metropolis.is_some() || !self.all_finished(settings) {
+            return;
+        }
+        for (pixel, samples) in self.grants(settings) {
+            self.stats[pixel].grant(samples);
+        }
+    }
+
+    // Samples to grant, by pixel index, under `adaptive::allocate`. The
+    // budget is the samples per pixel over every rendered pixel.
+    fn grants(&self, settings: &RenderSettings) -> Vec<(usize, usize)> {
+        let Some(threshold) = settings.adaptive else {
+            return Vec::new();
+        };
+        let pixels: Vec<usize> = settings
+            .tiles
+            .iter()
+            .flat_map(|tile| tile.pixels().map(|(x, y)| y * settings.width + x))
+            .collect();
+        let stats: Vec<PixelStats> = pixels.iter().map(|&p| self.stats[p]).collect();
+        let taken: usize = stats.iter().map(PixelStats::count).sum();
+        let budget = (pixels.len() * settings.samples).saturating_sub(taken);
+        adaptive::allocate(&stats, budget, threshold, settings.samples)
+            .into_iter()
+            .map(|(i, samples)| (pixels[i], samples))
+            .collect()
+    }
+
+    /// Takes up to `samples` more samples in every pixel that still needs them,
+    /// stopping early once `cancel` is set.
+    ///
+    /// Threads take tiles in the order of `settings.tiles`. Every sample
+    /// depends only on its pixel, index and the seed, and finished tiles are
+    /// merged by their index, so the image is the same however the tiles are
+    /// ordered and scheduled and however the samples are split into passes.
+    pub fn pass(
+        &mut self,
+        camera: &Camera,
+        scene: &Scene,
+        settings: &RenderSettings,
+        samples: usize,
+        bar: &ProgressBar,
+        cancel: &AtomicBool,
+    ) {
+        // The samples are taken in steps the integrator can prepare for.
+        // Metropolis chains cannot stop partway, so they step a sample per
+        // pixel at a time to notice `cancel`.
+        let step = match &self.metropolis {
+            Some(_) => 1,
+            None => self.max_pass_samples().unwrap_or(samples),
+        };
+        let mut left = samples;
+        while left > 0 && !cancel.load(Ordering::Relaxed) {
+            let samples = left.min(step.max(1));
+            left -= samples;
+            if let Some(metropolis) = &mut self.metropolis {
+                let integrator = self.integrator.as_ref();
+                metropolis.pass(camera, scene, settings, integrator, samples, bar);
+                continue;
+            }
+            self.redistribute(settings);
+            self.prepare(scene, settings, settings.tiles.iter(), samples);
+            let mut done: Vec<TileResult> = settings
+                .tiles
+                .iter()
+                .par_bridge()
+                .map(|tile| {
+                    let result = self.render_tile(camera, scene, settings, tile, samples, cancel);
+                    bar.inc(result.taken);
+                    result
+                })
+                .collect();
+
+            done.sort_by_key(|result| result.tile.index);
+            for result in done {
+                self.merge(result);
+            }
+        }
+    }
+
+    /// Most samples per pixel one pass of the integrator may take; see
+    /// `Integrator::max_pass_samples`.
+    pub fn max_pass_samples(&self) -> Option<usize> {
+        self.integrator.max_pass_samples()
+    }
+
+    /// Lets the integrator ready what the next `samples` samples of the
+    /// pixels of `tiles` share, such as photon maps, before they are rendered
+    /// in parallel.
+    pub fn prepare<'a>(
+        &self,
+        scene: &Scene,
+        settings: &RenderSettings,
+        tiles: impl Iterator<Item = &'a Tile>,
+        samples: usize,
+    ) {
+        let width = settings.width;
+        let next = tiles
+            .flat_map(|tile| tile.pixels())
+            .map(|(x, y)| &self.stats[y * width + x])
+            .filter(|stats| !finished(stats, settings))
+            .map(PixelStats::count)
+            .min();
+        if let Some(first) = next {
+            self.integrator.prepare(scene, first..first + samples);
+        }
+    }
+
+    /// Takes up to `samples` more samples in every pixel of `tile` that still
+    /// needs them, without changing the image yet. Once `cancel` is set the
+    /// remaining pixels are left as they are.
+    pub fn render_tile(
+        &self,
+        camera: &Camera,
+        scene: &Scene,
+        settings: &RenderSettings,
+        tile: &Tile,
+        samples: usize,
+        cancel: &AtomicBool,
+    ) -> TileResult {
+        let (width, height) = (settings.width, settings.height);
+        let mut film = self.film.tile(tile.x0, tile.y0, tile.x1, tile.y1);
+        let mut aovs: Vec<Film> = self
+            .aovs
+            .iter()
+            .map(|aov| aov.tile(tile.x0, tile.y0, tile.x1, tile.y1))
+            .collect();
+        let mut light_paths: Vec<Film> = self
+            .light_paths
+            .iter()
+            .map(|film| film.tile(tile.x0, tile.y0, tile.x1, tile.y1))
+            .collect();
+        let mut shares = vec![Vec3::default(); light_paths.len()];
+        let mut paths = Vec::new();
+        let mut invalid = InvalidSamples::default();
+        let mut sampler = settings.sampler.create(settings.samples, settings.seed);
+        let mut tile_stats = Vec::with_capacity(tile.area());
+        let mut taken = 0;
+
+        for (i, row) in tile.pixels() {
+            let mut stats = self.stats[row * width + i];
+            for _ in 0..samples {
+                if finished(&stats, settings) || cancel.load(Ordering::Relaxed) {
+                    break;
+                }
+
+                let index = stats.count();
+                sampler.start_sample(i, row, index);
+                let (jx, jy) = sampler.next_2d();
+                let (x, y) = (i as f32 + jx, row as f32 + jy);
+                let lens = sampler.next_2d();
+
+                // Image rows run top to bottom, camera coordinates bottom to top.
+                let ray = camera.get_ray(x / width as f32, 1.0 - y / height as f32, lens);
+                shares.fill(Vec3::default());
+                paths.clear();
+                let mut record = |events: &[_], value: Vec3| {
+                    for (lpe, share) in settings.light_paths.iter().zip(shares.iter_mut()) {
+                        if lpe.matches(events) {
+                            *share = *share + value;
+                        }
+                    }
+                    // Paths are only kept to be shown when aborting.
+                    if settings.fireflies.abort {
+                        paths.push((events.to_vec(), value));
+                    }
+                };
+                let mut radiance = ray
+                    .map(|r| {
+                        let sampler = sampler.as_mut();
+                        self.integrator
+                            .radiance(&r, scene, sampler, index, &mut record)
+                    })
+                    .unwrap_or_default();
+                let what = || format!("sample {} of pixel ({}, {})", index, i, row);
+                if !settings
+                    .fireflies
+                    .check(&mut radiance, &mut shares, &paths, what)
+                {
+                    invalid.add(i, row);
+                }
+                film.add_sample(x, y, radiance);
+                // Every film takes every sample, zero where no path matches,
+                // so its weights stay those of the image.
+                for (share, lpe_film) in shares.iter().zip(light_paths.iter_mut()) {
+                    lpe_film.add_sample(x, y, *share);
+                }
+
+                if !aovs.is_empty() {
+                    let hit = ray.and_then(|r| Some((r, scene.world.hit(&r, 0.001, f32::MAX)?)));
+                    for (aov, aov_film) in settings.aovs.iter().zip(aovs.iter_mut()) {
+                        if aov.is_id() && stats.count() > 0 {
+                            continue;
+                        }
+                        let value = hit
+                            .as_ref()
+                            .map_or(Vec3::default(), |(r, rec)| aov.value(r, rec));
+                        aov_film.add_sample(x, y, value);
+                    }
+                }
+                stats.add(tonemap::luminance_in(radiance, settings.luminance));
+                taken += 1;
+            }
+            tile_stats.push(stats);
+        }
+
+        TileResult {
+            tile: *tile,
+            film,
+            aovs,
+            light_paths,
+            stats: tile_stats,
+            taken,
+            invalid,
+        }
+    }
+
+    /// Adds the samples of a rendered tile to the image.
+    pub fn merge(&mut self, result: TileResult) {
+        self.film.merge(&result.film);
+        for (aov, tile) in self.aovs.iter_mut().zip(&result.aovs) {
+            aov.merge(tile);
+        }
+        for (film, tile) in self.light_paths.iter_mut().zip(&result.light_paths) {
+            film.merge(tile);
+        }
+        let width = self.film.width();
+        for ((x, y), stats) in result.tile.pixels().zip(result.stats) {
+            self.stats[y * width + x] = stats;
+        }
+        self.invalid.merge(&result.invalid);
+    }
+
+    /// Writes the statistics of the pixels of `tile`, which decide where their
+    /// next samples start.
+    pub fn save_tile_stats(&self, tile: &Tile, out: &mut impl Write) -> io::Result<()> {
+        let width = self.film.width();
+        tile.pixels()
+            .try_for_each(|(x, y)| self.stats[y * width + x].save(out))
+    }
+
+    /// Reads statistics written by `save_tile_stats` into the pixels of `tile`.
+    pub fn load_tile_stats(&mut self, tile: &Tile, input: &mut impl Read) -> io::Result<()> {
+        let width = self.film.width();
+        for (x, y) in tile.pixels() {
+            self.stats[y * width + x] = PixelStats::load(input)?;
+        }
+        Ok(())
+    }
+
+    /// Reads a tile result written by `TileResult::save` for `tile`.
+    pub fn load_tile(&self, tile: &Tile, input: &mut impl Read) -> io::Result<TileResult> {
+        let mut film = self.film.tile(tile.x0, tile.y0, tile.x1, tile.y1);
+        film.load(input)?;
+        let mut load_films = |films: &[Film]| {
+            films
+                .iter()
+                .map(|film| {
+                    let mut film = film.tile(tile.x0, tile.y0, tile.x1, tile.y1);
+                    film.load(input)?;
+                    Ok(film)
+                })
+                .collect::<io::Result<Vec<Film>>>()
+        };
+        let aovs = load_films(&self.aovs)?;
+        let light_paths = load_films(&self.light_paths)?;
+        let stats = (0..tile.area())
+            .map(|_| PixelStats::load(input))
+            .collect::<io::Result<_>>()?;
+        let taken = checkpoint::read_u64(input)?;
+        let invalid = InvalidSamples::load(input)?;
+        Ok(TileResult {
+            tile: *tile,
+            film,
+            aovs,
+            light_paths,
+            stats,
+            taken,
+            invalid,
+        })
+    }
+
+    /// Writes the samples and statistics of every pixel.
+    pub fn save(&self, out: &mut impl Write) -> io::Result<()> {
+        self.film.save(out)?;
+        self.aovs.iter().try_for_each(|aov| aov.save(out))?;
+        self.light_paths
+            .iter()
+            .try_for_each(|film| film.save(out))?;
+        self.stats.iter().try_for_each(|stats| stats.save(out))
+    }
+
+    /// Replaces the state with one written by `save` for the same image size.
+    pub fn load(&mut self, input: &mut impl Read) -> io::Result<()> {
+        self.film.load(input)?;
+        for film in self.aovs.iter_mut().chain(self.light_paths.iter_mut()) {
+            film.load(input)?;
+        }
+        for stats in self.stats.iter_mut() {
+            *stats = PixelStats::load(input)?;
+        }
+        Ok(())
+    }
+
+    /// Samples that were NaN or infinite and left black. Samples taken
+    /// before a checkpoint are not counted.
+    pub fn invalid_samples(&self) -> &InvalidSamples {
+        &self.invalid
+    }
+
+    /// Linear radiance of every pixel from the samples taken so far.
+    pub fn image(&self) -> Vec<Vec3> {
+        match &self.metropolis {
+            Some(metropolis) => metropolis.image(),
+            None => self.film.resolve(),
+        }
+    }
+
+    /// Values of every AOV of the settings, in the same order.
+    pub fn aov_images(&self) -> Vec<Vec<Vec3>> {
+        self.aovs.iter().map(Film::resolve).collect()
+    }
+
+    /// Radiance of the paths each light path expression of the settings
+    /// selects, in the same order.
+    pub fn light_path_images(&self) -> Vec<Vec<Vec3>> {
+        match &self.metropolis {
+            Some(metropolis) => metropolis.light_path_images(),
+            None => self.light_paths.iter().map(Film::resolve).collect(),
+        }
+    }
+
+    /// Variance of the mean luminance of every pixel, an estimate of how
+    /// noisy it still is.
+    pub fn variances(&self) -> Vec<f32> {
+        self.stats
+            .iter()
+            .map(|stats| stats.variance() / stats.count().max(1) as f32)
+            .collect()
+    }
+
+    /// Samples taken in every pixel so far.
+    pub fn counts(&self) -> Vec<usize> {
+        self.stats.iter().map(PixelStats::count).collect()
+    }
+}
+
+/// New samples of one tile: their filtered contributions, which can reach
+/// slightly past the tile, and the updated statistics of its pixels.
+pub struct TileResult {
+    tile: Tile,
+    film: Film,
+    aovs: Vec<Film>,
+    light_paths: Vec<Film>,
+    stats: Vec<PixelStats>,
+    taken: u64,
+    invalid: InvalidSamples,
+}
+
+impl TileResult {
+    pub fn tile(&self) -> &Tile {
+        &self.tile
+    }
+
+    /// Number of samples taken.
+    pub fn taken(&self) -> u64 {
+        self.taken
+    }
+
+    pub fn save(&self, out: &mut impl Write) -> io::Result<()> {
+        self.film.save(out)?;
+        self.aovs.iter().try_for_each(|aov| aov.save(out))?;
+        self.light_paths
+            .iter()
+            .try_for_each(|film| film.save(out))?;
+        self.stats.iter().try_for_each(|stats| stats.save(out))?;
+        checkpoint::write_u64(out, self.taken)?;
+        self.invalid.save(out)
+    }
+}
+
+fn finished(stats: &PixelStats, settings: &RenderSettings) -> bool {
+    match settings.adaptive {
+        Some(threshold) => stats.converged(threshold, settings.samples),
+        None => stats.count() >= settings.samples,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::colorspace::ColorSpace;
+    use crate::lpe;
+    use crate::mat3::Mat3;
+    use crate::photon::PhotonSettings;
+    use crate::tiles::{self, TileOrder};
+    use rayon::ThreadPoolBuilder;
+
+    fn settings(samples: usize, adaptive: Option<f32>) -> RenderSettings {
+        RenderSettings {
+            width: 8,
+            height: 6,
+            samples,
+            filter: "gaussian".parse().unwrap(),
+            sampler: SamplerKind::Sobol,
+            integrator: IntegratorKind::Path,
+            bounces: Bounces::default(),
+            fireflies: Fireflies::default(),
+            seed: 42,
+            adaptive,
+            tiles: tiles::layout(8, 6, 4, TileOrder::Spiral, None, None),
+            aovs: Vec::new(),
+            light_paths: Vec::new(),
+            luminance: ColorSpace::LinearSrgb.luminance_weights(),
+        }
+    }
+
+    fn cover_camera() -> Camera {
+        Camera::new(
+            Vec3::new(13.0, 2.0, 3.0),
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            20.0,
+            8.0 / 6.0,
+            0.1,
+            10.0,
+        )
+    }
+
+    #[test]
+    fn test_render_is_independent_of_thread_count() {
+        let settings = settings(4, None);
+        let camera = cover_camera();
+
+        let [one, four] = [1, 4].map(|threads| {
+            let scene = Scene::random(Mat3::IDENTITY, 1.0, 7);
+            let pool = ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap();
+            let mut render = Render::new(&settings);
+            pool.install(|| {
+                render.pass(
+                    &camera,
+                    &scene,
+                    &settings,
+                    4,
+                    &ProgressBar::hidden(),
+                    &AtomicBool::new(false),
+                )
+            });
+            render.image()
+        });
+        assert_eq!(one, four);
+    }
+
+    #[test]
+    fn test_passes_add_up_to_full_render() {
+        let settings = settings(8, None);
+        let (camera, scene) = (cover_camera(), Scene::random(Mat3::IDENTITY, 1.0, 7));
+        let bar = ProgressBar::hidden();
+
+        let mut whole = Render::new(&settings);
+        whole.pass(&camera, &scene, &settings, 8, &bar, &AtomicBool::new(false));
+        let mut progressive = Render::new(&settings);
+        while !progressive.is_done(&settings) {
+            progressive.pass(&camera, &scene, &settings, 3, &bar, &AtomicBool::new(false));
+        }
+
+        assert_eq!(progressive.counts(), vec![8; 48]);
+        for (a, b) in whole.image().iter().zip(progressive.image()) {
+            assert!((*a - b).length() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_photon_render_in_one_pass() {
+        // The default pass takes every sample at once; the photon maps must
+        // still be traced once per index rather than once per pixel.
+        let photons = PhotonSettings {
+            photons: 500,
+            ..PhotonSettings::default()
+        };
+        let settings = RenderSettings {
+            integrator: IntegratorKind::Photon(photons),
+            ..settings(32, None)
+        };
+        let (camera, scene) = (cover_camera(), Scene::random(Mat3::IDENTITY, 1.0, 7));
+        let bar = ProgressBar::hidden();
+
+        let mut whole = Render::new(&settings);
+        assert_eq!(whole.max_pass_samples(), Some(16));
+        whole.pass(
+            &camera,
+            &scene,
+            &settings,
+            settings.samples,
+            &bar,
+            &AtomicBool::new(false),
+        );
+        let mut progressive = Render::new(&settings);
+        while !progressive.is_done(&settings) {
+            progressive.pass(
+                &camera,
+                &scene,
+                &settings,
+                16,
+                &bar,
+                &AtomicBool::new(false),
+            );
+        }
+
+        assert_eq!(whole.counts(), vec![32; 48]);
+        assert_eq!(whole.image(), progressive.image());
+    }
+
+    #[test]
+    fn test_cancelled_pass_stops() {
+        let settings = settings(64, None);
+        let (camera, scene) = (cover_camera(), Scene::random(Mat3::IDENTITY, 1.0, 7));
+
+        let mut render = Render::new(&settings);
+        let cancel = AtomicBool::new(true);
+        render.pass(
+            &camera,
+            &scene,
+            &settings,
+            64,
+            &ProgressBar::hidden(),
+            &cancel,
+        );
+        assert_eq!(render.counts(), vec![0; 48]);
+    }
+
+    #[test]
+    fn test_adaptive_sampling_stops_on_smooth_sky() {
+        let settings = RenderSettings {
+            width: 4,
+            height: 4,
+            filter: Filter::default(),
+            tiles: tiles::layout(4, 4, 4, TileOrder::Scanline, None, None),
+            ..settings(256, Some(0.01))
+        };
+        // Looking straight up, every pixel sees only the sky gradient.
+        let camera = Camera::new(
+            Vec3::new(0.0, 2.0, 0.0),
+            Vec3::new(0.0, 3.0, 0.0),
+            Vec3::new(1.0, 0.0, 0.0),
+            20.0,
+            1.0,
+            0.0,
+            10.0,
+        );
+        let scene = Scene::random(Mat3::IDENTITY, 1.0, 7);
+
+        let mut render = Render::new(&settings);
+        render.pass(
+            &camera,
+            &scene,
+            &settings,
+            256,
+            &ProgressBar::hidden(),
+            &AtomicBool::new(false),
+        );
+        assert!(render.is_done(&settings));
+        assert!(
+            render.counts().iter().all(|&c| c == adaptive::MIN_SAMPLES),
+            "{:?}",
+            render.counts()
+        );
+    }
+
+    #[test]
+    fn test_adaptive_sampling_moves_budget_to_noisy_pixels() {
+        let settings = RenderSettings {
+            filter: Filter::default(),
+            ..settings(32, Some(0.02))
+        };
+        let (camera, scene) = (cover_camera(), Scene::random(Mat3::IDENTITY, 1.0, 7));
+
+        let mut render = Render::new(&settings);
+        while !render.is_done(&settings) {
+            render.pass(
+                &camera,
+                &scene,
+                &settings,
+                32,
+                &ProgressBar::hidden(),
+                &AtomicBool::new(false),
+            );
+        }
+        let counts = render.counts();
+        assert!(counts.iter().sum::<usize>() <= 32 * counts.len());
+        assert!(counts.iter().any(|&c| c < 32), "{:?}", counts);
+        assert!(counts.iter().any(|&c| c > 32), "{:?}", counts);
+        assert!(counts.iter().all(|&c| c <= adaptive::MAX_FACTOR * 32));
+    }
+
+    #[test]
+    fn test_aovs_record_first_hit() {
+        let settings = RenderSettings {
+            width: 4,
+            height: 4,
+            tiles: tiles::layout(4, 4, 4, TileOrder::Scanline, None, None),
+            aovs: vec![Aov::Normal, Aov::Albedo, Aov::ObjectId],
+            ..settings(3, None)
+        };
+        // Looking straight down at the ground, the first object of the scene.
+        let camera = Camera::new(
+            Vec3::new(0.0, 50.0, 0.0),
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(1.0, 0.0, 0.0),
+            1.0,
+            1.0,
+            0.0,
+            50.0,
+        );
+        let scene = Scene::parse(
+            "sphere 0 -1000 0 1000 lambertian 0.5 0.5 0.5",
+            Mat3::IDENTITY,
+            1.0,
+        )
+        .unwrap();
+
+        let mut render = Render::new(&settings);
+        while !render.is_done(&settings) {
+            render.pass(
+                &camera,
+                &scene,
+                &settings,
+                2,
+                &ProgressBar::hidden(),
+                &AtomicBool::new(false),
+            );
+        }
+        let aovs = render.aov_images();
+        assert!(aovs[0]
+            .iter()
+            .all(|n| (*n - Vec3::new(0.0, 1.0, 0.0)).length() < 1e-3));
+        assert!(aovs[1]
+            .iter()
+            .all(|a| (*a - Vec3::new(0.5, 0.5, 0.5)).length() < 1e-6));
+        assert!(aovs[2].iter().all(|id| *id == Vec3::new(1.0, 1.0, 1.0)));
+    }
+
+    #[test]
+    fn test_light_paths_add_up_to_image() {
+        let scene = Scene::parse(
+            "sky 0.5 0.5 0.5 0.2 0.3 0.6\n\
+             sphere 0 -1000 0 1000 lambertian 0.5 0.5 0.5\n\
+             sphere 0 1 0 1 dielectric 1.5\n\
+             sphere 4 1 0 1 metal 0.7 0.6 0.5 0.1\n\
+             sphere -4 1 0 1 light 4 3 2 key\n",
+            Mat3::IDENTITY,
+            1.0,
+        )
+        .unwrap();
+        let lpe = |(name, text): &(&str, &str)| Lpe::new(name, text, &scene.light_groups);
+        let groups = [("key", "C.*L'key'"), ("sky", "C.*L'sky'")];
+        for integrator in [IntegratorKind::Path, IntegratorKind::Bidirectional] {
+            let settings = RenderSettings {
+                integrator,
+                light_paths: lpe::COMPONENTS
+                    .iter()
+                    .chain(&groups)
+                    .map(|entry| lpe(entry).unwrap())
+                    .collect(),
+                ..settings(8, None)
+            };
+
+            let mut render = Render::new(&settings);
+            render.pass(
+                &cover_camera(),
+                &scene,
+                &settings,
+                8,
+                &ProgressBar::hidden(),
+                &AtomicBool::new(false),
+            );
+            let image = render.image();
+            let buffers = render.light_path_images();
+            let (components, groups) = buffers.split_at(lpe::COMPONENTS.len());
+            for parts in [components, groups] {
+                for (p, pixel) in image.iter().enumerate() {
+                    let sum = parts
+                        .iter()
+                        .fold(Vec3::default(), |sum, part| sum + part[p]);
+                    assert!((sum - *pixel).length() <= 1e-4 * pixel.length().max(1.0));
+                }
+            }
+            // Every component takes part somewhere.
+            assert!(buffers.iter().all(|b| b.iter().any(|p| p.length() > 0.0)));
+        }
+    }
+
+    #[test]
+    fn test_crop_only_samples_inside() {
+        let crop = "2,1,5,4".parse().unwrap();
+        let settings = RenderSettings {
+            filter: Filter::default(),
+            tiles: tiles::layout(8, 6, 2, TileOrder::Hilbert, Some(crop), None),
+            ..settings(2, None)
+        };
+        let (camera, scene) = (cover_camera(), Scene::random(Mat3::IDENTITY, 1.0, 7));
+
+        let mut render = Render::new(&settings);
+        render.pass(
+            &camera,
+            &scene,
+            &settings,
+            2,
+            &ProgressBar::hidden(),
+            &AtomicBool::new(false),
+        );
+        assert!(render.is_done(&settings));
+        for (i, count) in render.counts().into_iter().enumerate() {
+            let (x, y) = (i % 8, i / 8);
+            let inside = (2..5).contains(&x) && (1..4).contains(&y);
+            assert_eq!(count, if inside { 2 } else { 0 });
+        }
+    }
+
+    #[test]
+    fn test_invalid_samples_are_counted_and_left_black() {
+        let settings = settings(4, None);
+        let scene = Scene::parse(
+            "sky 0 0 0 0 0 0\nsphere 0 0 0 2 light inf 1 1\n",
+            Mat3::IDENTITY,
+            1.0,
+        )
+        .unwrap();
+        let mut render = Render::new(&settings);
+        let tile = &settings.tiles[0];
+        let result = render.render_tile(
+            &cover_camera(),
+            &scene,
+            &settings,
+            tile,
+            4,
+            &AtomicBool::new(false),
+        );
+
+        let mut saved = Vec::new();
+        result.save(&mut saved).unwrap();
+        let loaded = render.load_tile(tile, &mut saved.as_slice()).unwrap();
+        assert_eq!(loaded.invalid, result.invalid);
+        render.merge(result);
+
+        let invalid = render.invalid_samples();
+        // Every sample of the pixel by the centre sees the light.
+        assert!(invalid.count() >= 4);
+        assert!(render.image().iter().all(|v| v.x().is_finite()));
+    }
+}
@@ -0,0 +1,554 @@
+use crate::bluenoise;
+use crate::vec3::Vec3;
+use std::f32::consts::PI;
+use std::str::FromStr;
+
+/// Largest `f32` below one, so samples stay in `[0, 1)`.
+const ONE_MINUS_EPSILON: f32 = 1.0 - f32::EPSILON / 2.0;
+
+/// Source of the numbers driving each camera path. A path asks for them one
+/// dimension at a time in a fixed order: the position inside the pixel, the
+/// point on the lens, then three numbers for every bounce and one more for
+/// every bounce that plays Russian roulette. Samplers use that order to
+/// spread each dimension well over the samples of a pixel.
+pub trait Sampler {
+    /// Starts sample `index` of pixel `(x, y)` from its first dimension.
+    fn start_sample(&mut self, x: usize, y: usize, index: usize);
+
+    fn next_1d(&mut self) -> f32;
+
+    fn next_2d(&mut self) -> (f32, f32) {
+        (self.next_1d(), self.next_1d())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SamplerKind {
+    /// Uncorrelated uniform random numbers.
+    Independent,
+    /// Jittered strata, shuffled independently in each dimension.
+    Stratified,
+    /// Owen-scrambled Halton sequence.
+    Halton,
+    /// Owen-scrambled Sobol sequence, padded in blocks of four dimensions.
+    Sobol,
+    /// One Sobol sequence shared by all pixels, shifted per pixel by a
+    /// blue-noise texture so that the remaining error looks like blue noise.
+    BlueNoise,
+}
+
+impl FromStr for SamplerKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "independent" | "random" => Ok(SamplerKind::Independent),
+            "stratified" => Ok(SamplerKind::Stratified),
+            "halton" => Ok(SamplerKind::Halton),
+            "sobol" => Ok(SamplerKind::Sobol),
+            "blue-noise" | "bluenoise" => Ok(SamplerKind::BlueNoise),
+            _ => Err(format!("unknown sampler '{}'", s)),
+        }
+    }
+}
+
+impl SamplerKind {
+    /// A sampler taking `samples_per_pixel` samples in every pixel. Samplers
+    /// created with the same seed produce the same numbers.
+    pub fn create(self, samples_per_pixel: usize, seed: u64) -> Box<dyn Sampler> {
+        let state = State {
+            seed,
+            ..State::default()
+        };
+        match self {
+            SamplerKind::Independent => Box::new(Independent(state)),
+            SamplerKind::Stratified => Box::new(Stratified::new(samples_per_pixel, state)),
+            SamplerKind::Halton => Box::new(Halton(state)),
+            SamplerKind::Sobol => Box::new(Sobol(state)),
+            SamplerKind::BlueNoise => Box::new(BlueNoise(state)),
+        }
+    }
+}
+
+/// Where a sampler is: pixel, sample index and the next dimension.
+#[derive(Debug, Default, Clone, Copy)]
+struct State {
+    seed: u64,
+    x: usize,
+    y: usize,
+    index: usize,
+    dimension: usize,
+}
+
+impl State {
+    fn start(&mut self, x: usize, y: usize, index: usize) {
+        self.x = x;
+        self.y = y;
+        self.index = index;
+        self.dimension = 0;
+    }
+
+    /// Hash of the pixel, the current dimension and the seed.
+    fn pixel_hash(&self) -> u64 {
+        hash(&[
+            self.x as u64,
+            self.y as u64,
+            self.dimension as u64,
+            self.seed,
+        ])
+    }
+
+    /// A uniform number unique to this pixel, sample and dimension.
+    fn uniform(&self) -> f32 {
+        to_unit(hash(&[
+            self.x as u64,
+            self.y as u64,
+            self.index as u64,
+            self.dimension as u64,
+            self.seed,
+        ]))
+    }
+}
+
+struct Independent(State);
+
+impl Sampler for Independent {
+    fn start_sample(&mut self, x: usize, y: usize, index: usize) {
+        self.0.start(x, y, index);
+    }
+
+    fn next_1d(&mut self) -> f32 {
+        let u = self.0.uniform();
+        self.0.dimension += 1;
+        u
+    }
+}
+
+struct Stratified {
+    state: State,
+    samples: usize,
+    // Columns and rows of the grid used for two-dimensional samples.
+    x_strata: usize,
+    y_strata: usize,
+}
+
+impl Stratified {
+    fn new(samples: usize, state: State) -> Stratified {
+        let samples = samples.max(1);
+        // The most square grid with exactly one sample per cell.
+        let x_strata = (1..=samples)
+            .take_while(|x| x * x <= samples)
+            .filter(|&x| samples.is_multiple_of(x))
+            .last()
+            .unwrap_or(1);
+        Stratified {
+            state,
+            samples,
+            x_strata,
+            y_strata: samples / x_strata,
+        }
+    }
+
+    fn stratum(&self) -> usize {
+        let hash = self.state.pixel_hash() as u32;
+        permutation_element(
+            (self.state.index % self.samples) as u32,
+            self.samples as u32,
+            hash,
+        ) as usize
+    }
+}
+
+impl Sampler for Stratified {
+    fn start_sample(&mut self, x: usize, y: usize, index: usize) {
+        self.state.start(x, y, index);
+    }
+
+    fn next_1d(&mut self) -> f32 {
+        let u = (self.stratum() as f32 + self.state.uniform()) / self.samples as f32;
+        self.state.dimension += 1;
+        u.min(ONE_MINUS_EPSILON)
+    }
+
+    fn next_2d(&mut self) -> (f32, f32) {
+        let stratum = self.stratum();
+        let (sx, sy) = (stratum % self.x_strata, stratum / self.x_strata);
+        let jx = self.state.uniform();
+        self.state.dimension += 1;
+        let jy = self.state.uniform();
+        self.state.dimension += 1;
+        (
+            ((sx as f32 + jx) / self.x_strata as f32).min(ONE_MINUS_EPSILON),
+            ((sy as f32 + jy) / self.y_strata as f32).min(ONE_MINUS_EPSILON),
+        )
+    }
+}
+
+const PRIMES: [u32; 64] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131, 137, 139, 149, 151, 157, 163, 167, 173, 179, 181, 191, 193,
+    197, 199, 211, 223, 227, 229, 233, 239, 241, 251, 257, 263, 269, 271, 277, 281, 283, 293, 307,
+    311,
+];
+
+struct Halton(State);
+
+impl Sampler for Halton {
+    fn start_sample(&mut self, x: usize, y: usize, index: usize) {
+        self.0.start(x, y, index);
+    }
+
+    fn next_1d(&mut self) -> f32 {
+        // Dimensions past the prime table fall back to independent numbers.
+        let u = match PRIMES.get(self.0.dimension) {
+            Some(&base) => {
+                owen_scrambled_radical_inverse(base, self.0.index as u64, self.0.pixel_hash())
+            }
+            None => self.0.uniform(),
+        };
+        self.0.dimension += 1;
+        u
+    }
+}
+
+struct Sobol(State);
+
+impl Sampler for Sobol {
+    fn start_sample(&mut self, x: usize, y: usize, index: usize) {
+        self.0.start(x, y, index);
+    }
+
+    fn next_1d(&mut self) -> f32 {
+        let seed = hash(&[self.0.x as u64, self.0.y as u64, self.0.seed]);
+        let u = padded_sobol(self.0.index as u32, self.0.dimension, seed);
+        self.0.dimension += 1;
+        u
+    }
+}
+
+struct BlueNoise(State);
+
+impl Sampler for BlueNoise {
+    fn start_sample(&mut self, x: usize, y: usize, index: usize) {
+        self.0.start(x, y, index);
+    }
+
+    fn next_1d(&mut self) -> f32 {
+        let state = &self.0;
+        let u = padded_sobol(state.index as u32, state.dimension, state.seed);
+
+        // Each dimension reads the texture at its own offset so that the
+        // shifts of different dimensions are not correlated.
+        let offset = hash(&[state.dimension as u64, state.seed]);
+        let shift = bluenoise::value(
+            state.x + offset as usize % bluenoise::SIZE,
+            state.y + (offset >> 32) as usize % bluenoise::SIZE,
+        );
+        self.0.dimension += 1;
+        (u + shift).fract().min(ONE_MINUS_EPSILON)
+    }
+}
+
+/// Mixes the bits of `v` so that nearby inputs give unrelated outputs.
+pub fn mix_bits(mut v: u64) -> u64 {
+    v ^= v >> 31;
+    v = v.wrapping_mul(0x7fb5_d329_728e_a185);
+    v ^= v >> 27;
+    v = v.wrapping_mul(0x81da_def4_bc2d_d44d);
+    v ^= v >> 33;
+    v
+}
+
+/// Hashes a sequence of values into one.
+pub fn hash(values: &[u64]) -> u64 {
+    values.iter().fold(0x9e37_79b9_7f4a_7c15, |h, &v| {
+        mix_bits(h ^ mix_bits(v.wrapping_add(0x9e37_79b9_7f4a_7c15)))
+    })
+}
+
+/// Turns hash bits into a uniform number in `[0, 1)`.
+pub fn to_unit(bits: u64) -> f32 {
+    (bits >> 40) as f32 / (1u64 << 24) as f32
+}
+
+/// Element `i` of a pseudo-random permutation of `0..len` chosen by `seed`,
+/// after Kensler's "Correlated Multi-Jittered Sampling".
+fn permutation_element(mut i: u32, len: u32, seed: u32) -> u32 {
+    let mut w = len - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+
+    // Permute within the next power of two and walk the cycle until the
+    // result lands inside `0..len`.
+    loop {
+        i ^= seed;
+        i = i.wrapping_mul(0xe170_893d);
+        i ^= seed >> 16;
+        i ^= (i & w) >> 4;
+        i ^= seed >> 8;
+        i = i.wrapping_mul(0x0929_eb3f);
+        i ^= seed >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | seed >> 27);
+        i = i.wrapping_mul(0x6935_fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dc_b303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e50_1cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860_a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < len {
+            break;
+        }
+    }
+    (i.wrapping_add(seed)) % len
+}
+
+/// The radical inverse of `a` in `base` with every digit permuted by a
+/// permutation depending on the digits before it, which keeps the sequence's
+/// stratification while decorrelating differently seeded copies.
+fn owen_scrambled_radical_inverse(base: u32, mut a: u64, seed: u64) -> f32 {
+    let inv_base = 1.0 / base as f32;
+    let mut inv_base_m = 1.0f32;
+    let mut reversed_digits = 0u64;
+
+    while 1.0 - inv_base_m < 1.0 {
+        let next = a / base as u64;
+        let digit = (a - next * base as u64) as u32;
+        let digit_hash = mix_bits(seed ^ reversed_digits) as u32;
+        let digit = permutation_element(digit, base, digit_hash);
+        reversed_digits = reversed_digits * base as u64 + digit as u64;
+        inv_base_m *= inv_base;
+        a = next;
+    }
+    (inv_base_m * reversed_digits as f32).min(ONE_MINUS_EPSILON)
+}
+
+/// Generator matrices of the first four Sobol dimensions, as 32 direction
+/// numbers each, from Joe and Kuo's primitive polynomials.
+const SOBOL_MATRICES: [[u32; 32]; 4] = sobol_matrices();
+
+const fn sobol_matrices() -> [[u32; 32]; 4] {
+    // Degree, polynomial coefficients and initial direction numbers.
+    const POLYNOMIALS: [(usize, u32, [u32; 3]); 3] =
+        [(1, 0, [1, 0, 0]), (2, 1, [1, 3, 0]), (3, 1, [1, 3, 1])];
+
+    let mut matrices = [[0; 32]; 4];
+    let mut k = 0;
+    while k < 32 {
+        // The first dimension is the van der Corput sequence.
+        matrices[0][k] = 1 << (31 - k);
+        k += 1;
+    }
+
+    let mut d = 0;
+    while d < POLYNOMIALS.len() {
+        let (degree, coefficients, initial) = POLYNOMIALS[d];
+        let mut m = [0u32; 32];
+        let mut k = 0;
+        while k < 32 {
+            m[k] = if k < degree {
+                initial[k]
+            } else {
+                let mut v = m[k - degree] ^ (m[k - degree] << degree);
+                let mut j = 1;
+                while j < degree {
+                    if (coefficients >> (degree - 1 - j)) & 1 == 1 {
+                        v ^= m[k - j] << j;
+                    }
+                    j += 1;
+                }
+                v
+            };
+            matrices[d + 1][k] = m[k] << (31 - k);
+            k += 1;
+        }
+        d += 1;
+    }
+    matrices
+}
+
+fn sobol(index: u32, dimension: usize) -> u32 {
+    let mut v = 0;
+    let mut bits = index;
+    let mut k = 0;
+    while bits != 0 {
+        if bits & 1 == 1 {
+            v ^= SOBOL_MATRICES[dimension][k];
+        }
+        bits >>= 1;
+        k += 1;
+    }
+    v
+}
+
+// Laine and Karras' hash approximating an Owen scramble of reversed bits, with
+// the constants from Burley's "Practical Hash-based Owen Scrambling".
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    let mut x = x.reverse_bits().wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50_b47c);
+    x ^= x.wrapping_mul(0xb82f_1e52);
+    x ^= x.wrapping_mul(0xc7af_e638);
+    x ^= x.wrapping_mul(0x8d22_f6e6);
+    x.reverse_bits()
+}
+
+/// Dimension `dimension` of an Owen-scrambled Sobol sequence. Dimensions come
+/// in independently scrambled and shuffled blocks of four, following Burley,
+/// so only the first four Sobol dimensions are ever needed.
+fn padded_sobol(index: u32, dimension: usize, seed: u64) -> f32 {
+    let block_seed = hash(&[(dimension / 4) as u64, seed]);
+    let shuffled = nested_uniform_scramble(index, block_seed as u32);
+    let v = sobol(shuffled, dimension % 4);
+    let v = nested_uniform_scramble(v, (block_seed >> 32) as u32 ^ (dimension % 4) as u32);
+    (v as f32 / (1u64 << 32) as f32).min(ONE_MINUS_EPSILON)
+}
+
+/// Maps the unit square onto the unit disk, keeping strata compact (Shirley
+/// and Chiu's concentric mapping).
+pub fn concentric_disk(u: (f32, f32)) -> Vec3 {
+    let (x, y) = (2.0 * u.0 - 1.0, 2.0 * u.1 - 1.0);
+    if x == 0.0 && y == 0.0 {
+        return Vec3::default();
+    }
+
+    let (r, theta) = if x.abs() > y.abs() {
+        (x, PI / 4.0 * (y / x))
+    } else {
+        (y, PI / 2.0 - PI / 4.0 * (x / y))
+    };
+    Vec3::new(r * theta.cos(), r * theta.sin(), 0.0)
+}
+
+/// A uniformly distributed point inside the unit ball: `u` picks the direction
+/// and `w` the distance from the centre.
+pub fn uniform_ball(u: (f32, f32), w: f32) -> Vec3 {
+    let z = 1.0 - 2.0 * u.0;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * u.1;
+    w.cbrt() * Vec3::new(r * phi.cos(), r * phi.sin(), z)
+}
+
+/// A direction around `n` with density `cos θ / π`.
+pub fn cosine_direction(n: Vec3, u: (f32, f32)) -> Vec3 {
+    let d = concentric_disk(u);
+    let z = (1.0 - d.x() * d.x() - d.y() * d.y()).max(0.0).sqrt();
+    let a = if n.x().abs() > 0.9 {
+        Vec3::new(0.0, 1.0, 0.0)
+    } else {
+        Vec3::new(1.0, 0.0, 0.0)
+    };
+    let t = Vec3::unit_vector(&Vec3::cross(&a, &n));
+    let b = Vec3::cross(&n, &t);
+    t * d.x() + b * d.y() + n * z
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KINDS: [SamplerKind; 5] = [
+        SamplerKind::Independent,
+        SamplerKind::Stratified,
+        SamplerKind::Halton,
+        SamplerKind::Sobol,
+        SamplerKind::BlueNoise,
+    ];
+
+    // Counts how many of `n` points fall into each cell of a `cols` x `rows` grid.
+    fn occupancy(points: &[(f32, f32)], cols: usize, rows: usize) -> Vec<usize> {
+        let mut cells = vec![0; cols * rows];
+        for &(x, y) in points {
+            cells[(y * rows as f32) as usize * cols + (x * cols as f32) as usize] += 1;
+        }
+        cells
+    }
+
+    #[test]
+    fn test_permutation_element() {
+        for len in [1, 2, 5, 16, 17, 100] {
+            let mut seen: Vec<u32> = (0..len)
+                .map(|i| permutation_element(i, len, 12345))
+                .collect();
+            seen.sort_unstable();
+            assert_eq!(seen, (0..len).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn test_sobol_sequence() {
+        let first: Vec<u32> = (0..4).map(|i| sobol(i, 1)).collect();
+        assert_eq!(first, [0, 0x8000_0000, 0xc000_0000, 0x4000_0000]);
+    }
+
+    #[test]
+    fn test_low_discrepancy_samplers_stratify() {
+        // Sixteen samples of a (0, 2)-sequence hit every elementary interval.
+        for kind in [SamplerKind::Stratified, SamplerKind::Sobol] {
+            let mut sampler = kind.create(16, 7);
+            let mut pixel = Vec::new();
+            let mut bounce = Vec::new();
+            for i in 0..16 {
+                sampler.start_sample(3, 5, i);
+                pixel.push(sampler.next_2d());
+                sampler.next_2d();
+                sampler.next_1d();
+                bounce.push(sampler.next_1d());
+            }
+            assert!(
+                occupancy(&pixel, 4, 4).iter().all(|&c| c == 1),
+                "{:?}",
+                kind
+            );
+            let strata = occupancy(&bounce.iter().map(|&u| (u, 0.0)).collect::<Vec<_>>(), 16, 1);
+            assert!(strata.iter().all(|&c| c == 1), "{:?}", kind);
+        }
+
+        let mut halton = SamplerKind::Halton.create(0, 7);
+        let points: Vec<(f32, f32)> = (0..6)
+            .map(|i| {
+                halton.start_sample(0, 0, i);
+                halton.next_2d()
+            })
+            .collect();
+        // Six samples fill a 2 x 3 grid in bases two and three.
+        assert!(occupancy(&points, 2, 3).iter().all(|&c| c == 1));
+    }
+
+    #[test]
+    fn test_samplers_are_seeded_and_in_range() {
+        for kind in KINDS {
+            let (mut a, mut b) = (kind.create(8, 1), kind.create(8, 1));
+            let mut other = kind.create(8, 2);
+            let mut differs = false;
+            for i in 0..8 {
+                a.start_sample(10, 20, i);
+                b.start_sample(10, 20, i);
+                other.start_sample(10, 20, i);
+                for _ in 0..100 {
+                    let u = a.next_1d();
+                    assert!((0.0..1.0).contains(&u), "{:?}", kind);
+                    assert_eq!(u, b.next_1d());
+                    differs |= u != other.next_1d();
+                }
+            }
+            assert!(differs, "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn test_warps() {
+        assert_eq!(concentric_disk((0.5, 0.5)), Vec3::default());
+        assert!((concentric_disk((1.0, 0.5)) - Vec3::new(1.0, 0.0, 0.0)).length() < 1e-6);
+        for i in 0..100 {
+            let u = (i as f32 / 100.0, (i * 37 % 100) as f32 / 100.0);
+            assert!(concentric_disk(u).length() <= 1.0 + 1e-6);
+            assert!(uniform_ball(u, 1.0 - u.0).length() <= 1.0 + 1e-6);
+        }
+    }
+}
@@ -0,0 +1,337 @@
+use crate::hittable::Hittable;
+use crate::hittable_list::HittableList;
+use crate::mat3::Mat3;
+use crate::material::Material;
+use crate::sphere::Sphere;
+use crate::vec3::Vec3;
+use rand::prelude::*;
+
+/// The gradient environment surrounding the scene, which is also a light.
+pub struct Sky {
+    pub horizon: Vec3,
+    pub zenith: Vec3,
+    /// Index of the sky's light group in the scene.
+    pub group: usize,
+}
+
+impl Sky {
+    pub fn color(&self, direction: Vec3) -> Vec3 {
+        let unit_direction = Vec3::unit_vector(&direction);
+        let t = 0.5 * (unit_direction.y() + 1.0);
+
+        self.horizon * (1.0 - t) + self.zenith * t
+    }
+}
+
+/// An emitting sphere, kept apart from the world so that points on it can be
+/// sampled.
+pub struct SphereLight {
+    pub center: Vec3,
+    pub radius: f32,
+    pub emit: Vec3,
+    pub group: usize,
+    /// Index of the sphere in the world's list.
+    pub object: usize,
+}
+
+pub struct Scene {
+    pub world: HittableList,
+    pub sky: Sky,
+    pub lights: Vec<SphereLight>,
+    /// Centres and radii of the glass and metal spheres, which focus light
+    /// into caustics.
+    pub casters: Vec<(Vec3, f32)>,
+    /// Centre and radius of a sphere around everything in the scene.
+    pub bounds: (Vec3, f32),
+    /// Names of the groups lights are gathered in, so their contributions can
+    /// be written apart.
+    pub light_groups: Vec<String>,
+}
+
+impl Scene {
+    /// Gathers `spheres` into a world, keeping the lights and the spheres
+    /// that cast caustics apart.
+    pub fn new(spheres: Vec<Sphere>, sky: Sky, light_groups: Vec<String>) -> Scene {
+        let mut lights = Vec::new();
+        let mut casters = Vec::new();
+        let (mut min, mut max) = ([f32::MAX; 3], [f32::MIN; 3]);
+        for (object, sphere) in spheres.iter().enumerate() {
+            let (center, radius) = (sphere.center(), sphere.radius());
+            match *sphere.material() {
+                Material::DiffuseLight { emit, group } => lights.push(SphereLight {
+                    center,
+                    radius,
+                    emit,
+                    group,
+                    object,
+                }),
+                Material::Metal { .. } | Material::Dielectric { .. } => {
+                    casters.push((center, radius))
+                }
+                Material::Lambertian { .. } => {}
+            }
+            for (axis, c) in [center.x(), center.y(), center.z()].into_iter().enumerate() {
+                min[axis] = min[axis].min(c - radius);
+                max[axis] = max[axis].max(c + radius);
+            }
+        }
+        let bounds = match spheres.is_empty() {
+            true => (Vec3::default(), 0.0),
+            false => {
+                let center = Vec3::new(min[0] + max[0], min[1] + max[1], min[2] + max[2]) / 2.0;
+                let radius = spheres
+                    .iter()
+                    .map(|s| (s.center() - center).length() + s.radius())
+                    .fold(0.0, f32::max);
+                (center, radius)
+            }
+        };
+
+        let list = spheres
+            .into_iter()
+            .map(|s| Box::new(s) as Box<dyn Hittable + Send + Sync>)
+            .collect();
+        Scene {
+            world: HittableList::new(list),
+            sky,
+            lights,
+            casters,
+            bounds,
+            light_groups,
+        }
+    }
+
+    /// The field of random spheres from the cover of "Ray Tracing in One Weekend".
+    ///
+    /// Colours are authored in one space and converted with `colors` into the
+    /// working space; `sky_luminance` scales the sky, the only light, in the
+    /// light group `sky`. The same `seed` always places the same spheres.
+    pub fn random(colors: Mat3, sky_luminance: f32, seed: u64) -> Scene {
+        let mut list = Vec::new();
+
+        let mut rng = StdRng::seed_from_u64(seed);
+
+        list.push(Sphere::sphere(
+            Vec3::new(0.0, -1000.0, -1.0),
+            1000.0,
+            Material::Lambertian {
+                albedo: colors.transform(Vec3::new(0.5, 0.5, 0.5)),
+            },
+        ));
+
+        for a in -11..11 {
+            for b in -11..11 {
+                let choose_mat = rng.gen::<f32>();
+                let center = Vec3::new(
+                    a as f32 + 0.9 * rng.gen::<f32>(),
+                    0.2,
+                    b as f32 + 0.9 * rng.gen::<f32>(),
+                );
+                if (center - Vec3::new(4.0, 0.2, 0.0)).length() > 0.9 {
+                    if choose_mat < 0.8 {
+                        // diffuse
+                        list.push(Sphere::sphere(
+                            center,
+                            0.2,
+                            Material::Lambertian {
+                                albedo: colors.transform(Vec3::new(
+                                    rng.gen::<f32>() * rng.gen::<f32>(),
+                                    rng.gen::<f32>() * rng.gen::<f32>(),
+                                    rng.gen::<f32>() * rng.gen::<f32>(),
+                                )),
+                            },
+                        ));
+                    } else if choose_mat < 0.95 {
+                        //metal
+                        list.push(Sphere::sphere(
+                            center,
+                            0.2,
+                            Material::Metal {
+                                albedo: colors.transform(Vec3::new(
+                                    0.5 * (1.0 + rng.gen::<f32>()),
+                                    0.5 * (1.0 + rng.gen::<f32>()),
+                                    0.5 * (1.0 + rng.gen::<f32>()),
+                                )),
+                                fuzz: (0.5 * rng.gen::<f32>()),
+                            },
+                        ));
+                    } else {
+                        //glass
+                        list.push(Sphere::sphere(
+                            center,
+                            0.2,
+                            Material::Dielectric { ref_idx: 1.5 },
+                        ));
+                    }
+                }
+            }
+        }
+
+        list.push(Sphere::sphere(
+            Vec3::new(0.0, 1.0, 0.0),
+            1.0,
+            Material::Dielectric { ref_idx: 1.5 },
+        ));
+
+        list.push(Sphere::sphere(
+            Vec3::new(-4.0, 1.0, 0.0),
+            1.0,
+            Material::Lambertian {
+                albedo: colors.transform(Vec3::new(0.4, 0.2, 0.1)),
+            },
+        ));
+
+        list.push(Sphere::sphere(
+            Vec3::new(4.0, 1.0, 0.0),
+            1.0,
+            Material::Metal {
+                albedo: colors.transform(Vec3::new(0.7, 0.6, 0.5)),
+                fuzz: 0.0,
+            },
+        ));
+
+        let sky = Sky {
+            horizon: colors.transform(Vec3::new(1.0, 1.0, 1.0)) * sky_luminance,
+            zenith: colors.transform(Vec3::new(0.5, 0.7, 1.0)) * sky_luminance,
+            group: 0,
+        };
+        Scene::new(list, sky, vec![String::from("sky")])
+    }
+
+    /// Reads a scene from text with one object per line:
+    ///
+    /// ```text
+    /// # comment
+    /// sky R G B R G B [GROUP]            horizon and zenith colours
+    /// sphere X Y Z RADIUS lambertian R G B
+    /// sphere X Y Z RADIUS metal R G B FUZZ
+    /// sphere X Y Z RADIUS dielectric IOR
+    /// sphere X Y Z RADIUS light R G B [GROUP]
+    /// ```
+    ///
+    /// Colours are converted and the sky scaled as in `random`. Without a `sky`
+    /// line the scene gets the usual white-to-blue gradient. Lights belong to
+    /// the named light group, by default `sky` for the sky and `light` for
+    /// the others.
+    pub fn parse(text: &str, colors: Mat3, sky_luminance: f32) -> Result<Scene, String> {
+        let mut list = Vec::new();
+        let mut sky = (Vec3::new(1.0, 1.0, 1.0), Vec3::new(0.5, 0.7, 1.0), "sky");
+        let mut light_groups: Vec<String> = Vec::new();
+        let mut group = |name: &str| match light_groups.iter().position(|g| g == name) {
+            Some(i) => i,
+            None => {
+                light_groups.push(name.to_string());
+                light_groups.len() - 1
+            }
+        };
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = || format!("line {}: invalid scene entry '{}'", number + 1, line);
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let values = |words: &[&str]| -> Result<Vec<f32>, String> {
+                words
+                    .iter()
+                    .map(|w| w.parse().map_err(|_| invalid()))
+                    .collect()
+            };
+
+            match words[..] {
+                ["sky", ..] => {
+                    let (colours, name) = split_group(&words[1..], 6, "sky");
+                    match values(colours)?[..] {
+                        [r0, g0, b0, r1, g1, b1] => {
+                            sky = (Vec3::new(r0, g0, b0), Vec3::new(r1, g1, b1), name);
+                        }
+                        _ => return Err(invalid()),
+                    }
+                }
+                ["sphere", x, y, z, radius, kind, ref rest @ ..] => {
+                    let [x, y, z, radius] = values(&[x, y, z, radius])?[..] else {
+                        unreachable!()
+                    };
+                    let (rest, name) = match kind {
+                        "light" => split_group(rest, 3, "light"),
+                        _ => (rest, ""),
+                    };
+                    let material = match (kind, &values(rest)?[..]) {
+                        ("lambertian", &[r, g, b]) => Material::Lambertian {
+                            albedo: colors.transform(Vec3::new(r, g, b)),
+                        },
+                        ("metal", &[r, g, b, fuzz]) => Material::Metal {
+                            albedo: colors.transform(Vec3::new(r, g, b)),
+                            fuzz,
+                        },
+                        ("dielectric", &[ref_idx]) => Material::Dielectric { ref_idx },
+                        ("light", &[r, g, b]) => Material::DiffuseLight {
+                            emit: colors.transform(Vec3::new(r, g, b)),
+                            group: group(name),
+                        },
+                        _ => return Err(invalid()),
+                    };
+                    list.push(Sphere::sphere(Vec3::new(x, y, z), radius, material));
+                }
+                _ => return Err(invalid()),
+            }
+        }
+
+        let sky = Sky {
+            horizon: colors.transform(sky.0) * sky_luminance,
+            zenith: colors.transform(sky.1) * sky_luminance,
+            group: group(sky.2),
+        };
+        Ok(Scene::new(list, sky, light_groups))
+    }
+}
+
+// Splits an optional light group name off the end of `words`, which without
+// it hold `count` numbers.
+fn split_group<'a, 'b>(
+    words: &'b [&'a str],
+    count: usize,
+    default: &'a str,
+) -> (&'b [&'a str], &'a str) {
+    match words.split_last() {
+        Some((name, numbers)) if words.len() == count + 1 => (numbers, name),
+        _ => (words, default),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ray::Ray;
+
+    #[test]
+    fn test_parse() {
+        let text = "# a lone ball\nsky 0 0 0 1 1 1\nsphere 0 0 -2 0.5 metal 0.8 0.8 0.8 0.1\n";
+        let scene = Scene::parse(text, Mat3::IDENTITY, 2.0).unwrap();
+        assert_eq!(scene.sky.zenith, Vec3::new(2.0, 2.0, 2.0));
+        let ray = Ray::new(Vec3::default(), Vec3::new(0.0, 0.0, -1.0));
+        assert!(scene.world.hit(&ray, 0.001, f32::MAX).is_some());
+
+        assert!(Scene::parse("sphere 0 0 0 1 glass", Mat3::IDENTITY, 1.0).is_err());
+        assert!(Scene::parse("sphere 0 0 0 1 dielectric", Mat3::IDENTITY, 1.0).is_err());
+        assert!(Scene::parse("cube 1", Mat3::IDENTITY, 1.0).is_err());
+    }
+
+    #[test]
+    fn test_light_groups() {
+        let text = "sphere 0 5 0 1 light 4 4 4 key\nsphere 0 0 0 1 light 1 1 1\n\
+                    sphere 5 5 0 1 light 2 2 2 key\nsky 0 0 0 0 0 0 env\n";
+        let scene = Scene::parse(text, Mat3::IDENTITY, 1.0).unwrap();
+        assert_eq!(scene.light_groups, ["key", "light", "env"]);
+        assert_eq!(scene.sky.group, 2);
+        assert_eq!(scene.lights.len(), 3);
+        assert_eq!((scene.lights[2].object, scene.lights[2].group), (2, 0));
+        let ray = Ray::new(Vec3::new(5.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0));
+        let rec = scene.world.hit(&ray, 0.001, f32::MAX).unwrap();
+        assert!(matches!(
+            rec.material,
+            Material::DiffuseLight { group: 0, .. }
+        ));
+    }
+}
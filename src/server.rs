@@ -0,0 +1,600 @@
+use crate::mat3::Mat3;
+use crate::options::Options;
+use crate::scene::Scene;
+use indicatif::ProgressBar;
+use std::any::Any;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Largest request body accepted, in bytes.
+const MAX_BODY: usize = 16 << 20;
+
+/// How long a connection may sit idle while a request is read or a response
+/// written.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Options a client may not set: they read or write files of their choosing,
+/// turn the render into another server or make it panic on purpose. Mask
+/// apertures are refused apart, as the other aperture shapes are harmless.
+const RESERVED: [&str; 10] = [
+    "scene",
+    "lens",
+    "exr",
+    "spp-heatmap",
+    "checkpoint",
+    "resume",
+    "serve",
+    "worker",
+    "workers",
+    "abort-on-nan",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+enum State {
+    Queued,
+    Running,
+    Done,
+    Cancelled,
+    Failed(String),
+}
+
+impl State {
+    fn name(&self) -> &'static str {
+        match self {
+            State::Queued => "queued",
+            State::Running => "running",
+            State::Done => "done",
+            State::Cancelled => "cancelled",
+            State::Failed(_) => "failed",
+        }
+    }
+}
+
+/// A submitted render: its command line, where its image goes and how far it
+/// has got.
+struct Job {
+    id: usize,
+    args: Vec<String>,
+    output: PathBuf,
+    state: Mutex<State>,
+    bar: ProgressBar,
+    cancel: AtomicBool,
+    /// How long the render took, once it has stopped.
+    took: Mutex<Option<Duration>>,
+}
+
+struct Jobs {
+    dir: PathBuf,
+    list: Mutex<Vec<Arc<Job>>>,
+    queued: Condvar,
+}
+
+/// Serves the job API on `listener` until the process is killed. Scenes and
+/// images are kept in `dir`.
+///
+/// - `POST /jobs?samples=64&sampler=sobol` queues a render. Query parameters
+///   are the command-line options without their dashes, and a non-empty body
+///   is the scene, in the format of `Scene::parse`. Answers `{"id": N}`.
+/// - `GET /jobs` and `GET /jobs/N` report state and progress as JSON.
+/// - `DELETE /jobs/N` cancels a queued or running job.
+/// - `GET /jobs/N/image` downloads the image of a finished job.
+///
+/// Jobs run one at a time, in the order they were submitted, through `run`,
+/// which gets the job's command line, a bar to count progress on and a flag
+/// asking it to stop. It returns whether the render ran to the end.
+pub fn serve<F>(listener: TcpListener, dir: &Path, run: F) -> io::Result<()>
+where
+    F: Fn(&[String], &ProgressBar, &AtomicBool) -> io::Result<bool> + Sync,
+{
+    fs::create_dir_all(dir)?;
+    let jobs = Jobs {
+        dir: dir.to_path_buf(),
+        list: Mutex::new(Vec::new()),
+        queued: Condvar::new(),
+    };
+    let jobs = &jobs;
+
+    thread::scope(|scope| {
+        scope.spawn(|| loop {
+            let job = jobs.next();
+            job.bar.reset_elapsed();
+            // A panicking render fails its job rather than taking the runner,
+            // and every job queued after it, down with it.
+            let result =
+                panic::catch_unwind(AssertUnwindSafe(|| run(&job.args, &job.bar, &job.cancel)))
+                    .unwrap_or_else(|payload| Err(io::Error::other(panic_message(&*payload))));
+            *job.took.lock().unwrap() = Some(job.bar.elapsed());
+            *job.state.lock().unwrap() = match result {
+                Ok(true) => State::Done,
+                Ok(false) => State::Cancelled,
+                Err(e) => State::Failed(e.to_string()),
+            };
+        });
+
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    scope.spawn(move || {
+                        if let Err(e) = handle(stream, jobs) {
+                            eprintln!("server: {}", e);
+                        }
+                    });
+                }
+                Err(e) => eprintln!("server: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+impl Jobs {
+    // Waits for the oldest queued job and marks it running.
+    fn next(&self) -> Arc<Job> {
+        let mut list = self.list.lock().unwrap();
+        loop {
+            for job in list.iter() {
+                let mut state = job.state.lock().unwrap();
+                if *state == State::Queued {
+                    *state = State::Running;
+                    return job.clone();
+                }
+            }
+            list = self.queued.wait(list).unwrap();
+        }
+    }
+
+    fn get(&self, id: &str) -> Option<Arc<Job>> {
+        let id: usize = id.parse().ok()?;
+        let list = self.list.lock().unwrap();
+        list.iter().find(|job| job.id == id).cloned()
+    }
+
+    fn submit(&self, query: &[(String, String)], body: &[u8]) -> Response {
+        let mut list = self.list.lock().unwrap();
+        let id = list.len() + 1;
+        let output = self.dir.join(format!("job-{}.ppm", id));
+        let mut args = vec![output.to_string_lossy().into_owned()];
+
+        if !body.is_empty() {
+            let Ok(text) = std::str::from_utf8(body) else {
+                return Response::error(400, "scene is not UTF-8");
+            };
+            if let Err(e) = Scene::parse(text, Mat3::IDENTITY, 1.0) {
+                return Response::error(400, &e);
+            }
+            let path = self.dir.join(format!("job-{}.scene", id));
+            if let Err(e) = fs::write(&path, text) {
+                return Response::error(500, &e.to_string());
+            }
+            args.push("--scene".to_string());
+            args.push(path.to_string_lossy().into_owned());
+        }
+
+        for (name, value) in query {
+            if RESERVED.contains(&name.as_str()) {
+                return Response::error(400, &format!("option '{}' is not allowed", name));
+            }
+            if name == "aperture" && value.starts_with("mask:") {
+                return Response::error(400, "mask apertures are not allowed");
+            }
+            args.push(format!("--{}", name));
+            args.push(value.clone());
+        }
+        if let Err(e) = Options::from_args(args.iter().cloned()) {
+            return Response::error(400, &e.to_string());
+        }
+
+        list.push(Arc::new(Job {
+            id,
+            args,
+            output,
+            state: Mutex::new(State::Queued),
+            bar: ProgressBar::hidden(),
+            cancel: AtomicBool::new(false),
+            took: Mutex::new(None),
+        }));
+        self.queued.notify_one();
+        Response::json(201, format!("{{\"id\": {}}}", id))
+    }
+}
+
+impl Job {
+    fn status(&self) -> String {
+        let state = self.state.lock().unwrap().clone();
+        let (done, total) = (self.bar.position(), self.bar.length());
+        let progress = match state {
+            State::Done => 1.0,
+            _ => done as f64 / total.max(1) as f64,
+        };
+        let elapsed = match (&state, *self.took.lock().unwrap()) {
+            (State::Queued, _) => Duration::ZERO,
+            (_, Some(took)) => took,
+            _ => self.bar.elapsed(),
+        };
+        let error = match &state {
+            State::Failed(message) => json_string(message),
+            _ => "null".to_string(),
+        };
+        format!(
+            "{{\"id\": {}, \"state\": \"{}\", \"samples\": {}, \"total_samples\": {}, \
+             \"progress\": {:.4}, \"elapsed\": {:.1}, \"error\": {}}}",
+            self.id,
+            state.name(),
+            done,
+            total,
+            progress,
+            elapsed.as_secs_f64(),
+            error
+        )
+    }
+
+    fn cancel(&self) -> Response {
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            State::Queued => {
+                *state = State::Cancelled;
+                *self.took.lock().unwrap() = Some(Duration::ZERO);
+            }
+            State::Running => self.cancel.store(true, Ordering::Relaxed),
+            _ => return Response::error(409, "job has already finished"),
+        }
+        drop(state);
+        Response::json(200, self.status())
+    }
+
+    fn image(&self) -> Response {
+        if *self.state.lock().unwrap() != State::Done {
+            return Response::error(409, "job has not finished");
+        }
+        match fs::read(&self.output) {
+            Ok(body) => Response {
+                status: 200,
+                content_type: "image/x-portable-pixmap",
+                body,
+            },
+            Err(e) => Response::error(500, &e.to_string()),
+        }
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn json(status: u16, body: String) -> Response {
+        Response {
+            status,
+            content_type: "application/json",
+            body: body.into_bytes(),
+        }
+    }
+
+    fn error(status: u16, message: &str) -> Response {
+        Response::json(status, format!("{{\"error\": {}}}", json_string(message)))
+    }
+
+    fn write(&self, out: &mut impl Write) -> io::Result<()> {
+        let reason = match self.status {
+            200 => "OK",
+            201 => "Created",
+            400 => "Bad Request",
+            404 => "Not Found",
+            409 => "Conflict",
+            413 => "Payload Too Large",
+            _ => "Internal Server Error",
+        };
+        write!(
+            out,
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.status,
+            reason,
+            self.content_type,
+            self.body.len()
+        )?;
+        out.write_all(&self.body)?;
+        out.flush()
+    }
+}
+
+fn handle(mut stream: TcpStream, jobs: &Jobs) -> io::Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let response = match read_request(&mut BufReader::new(&stream))? {
+        Ok(request) => route(&request, jobs),
+        Err(response) => response,
+    };
+    response.write(&mut stream)
+}
+
+// What a render panicked with, for the job's error.
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    let message = payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown cause");
+    format!("render panicked: {}", message)
+}
+
+fn route(request: &Request, jobs: &Jobs) -> Response {
+    let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+    match (request.method.as_str(), &segments[..]) {
+        ("POST", ["jobs"]) => jobs.submit(&request.query, &request.body),
+        ("GET", ["jobs"]) => {
+            let list = jobs.list.lock().unwrap();
+            let statuses: Vec<String> = list.iter().map(|job| job.status()).collect();
+            Response::json(200, format!("[{}]", statuses.join(", ")))
+        }
+        (method, ["jobs", id, rest @ ..]) => {
+            let Some(job) = jobs.get(id) else {
+                return Response::error(404, "no such job");
+            };
+            match (method, rest) {
+                ("GET", []) => Response::json(200, job.status()),
+                ("DELETE", []) => job.cancel(),
+                ("GET", ["image"]) => job.image(),
+                _ => Response::error(404, "no such endpoint"),
+            }
+        }
+        _ => Response::error(404, "no such endpoint"),
+    }
+}
+
+// Reads one HTTP/1.1 request; a malformed one gives the response to send back.
+fn read_request(input: &mut impl BufRead) -> io::Result<Result<Request, Response>> {
+    let mut line = String::new();
+    input.read_line(&mut line)?;
+    let mut words = line.split_whitespace();
+    let (Some(method), Some(target)) = (words.next(), words.next()) else {
+        return Ok(Err(Response::error(400, "malformed request line")));
+    };
+    let (method, target) = (method.to_string(), target.to_string());
+    let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+
+    let mut length = 0;
+    loop {
+        line.clear();
+        if input.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                match value.trim().parse() {
+                    Ok(n) => length = n,
+                    Err(_) => return Ok(Err(Response::error(400, "invalid Content-Length"))),
+                }
+            }
+        }
+    }
+    if length > MAX_BODY {
+        return Ok(Err(Response::error(413, "request body is too large")));
+    }
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+
+    let query = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (decode(name), decode(value))
+        })
+        .collect();
+
+    Ok(Ok(Request {
+        method,
+        path: decode(path),
+        query,
+        body,
+    }))
+}
+
+// Undoes URL percent-encoding, with `+` standing for a space.
+fn decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' => match s
+                .get(i + 1..i + 3)
+                .and_then(|h| u8::from_str_radix(h, 16).ok())
+            {
+                Some(byte) => {
+                    out.push(byte);
+                    i += 2;
+                }
+                None => out.push(b'%'),
+            },
+            byte => out.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rayon::ThreadPoolBuilder;
+    use std::io::Read;
+    use std::time::Instant;
+
+    // Stands in for a render: one sample every millisecond, then an image.
+    fn fake_render(args: &[String], bar: &ProgressBar, cancel: &AtomicBool) -> io::Result<bool> {
+        let options = Options::from_args(args.iter().cloned())?;
+        if options.seed == 13 {
+            panic!("unlucky seed");
+        }
+        bar.set_length(options.samples as u64);
+        for _ in 0..options.samples {
+            if cancel.load(Ordering::Relaxed) {
+                return Ok(false);
+            }
+            bar.inc(1);
+            thread::sleep(Duration::from_millis(1));
+        }
+        fs::write(&options.output, format!("P3 {}", options.samples))?;
+        Ok(true)
+    }
+
+    fn start_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let dir = std::env::temp_dir().join(format!("server-test-{}", address.replace(':', "-")));
+        thread::spawn(move || serve(listener, &dir, fake_render));
+        address
+    }
+
+    fn request(address: &str, method: &str, target: &str, body: &str) -> (u16, String) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nHost: test\r\nContent-Length: {}\r\n\r\n{}",
+            method,
+            target,
+            body.len(),
+            body
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let status = response[9..12].parse().unwrap();
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        (status, body.to_string())
+    }
+
+    fn wait_for(address: &str, id: usize, state: &str) -> String {
+        let start = Instant::now();
+        loop {
+            let (_, status) = request(address, "GET", &format!("/jobs/{}", id), "");
+            if status.contains(&format!("\"state\": \"{}\"", state)) {
+                return status;
+            }
+            assert!(start.elapsed() < Duration::from_secs(10), "{}", status);
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn test_jobs() {
+        let address = start_server();
+
+        let scene = "sphere 0 0 -1 0.5 lambertian 0.5 0.5 0.5\n";
+        let (status, body) = request(&address, "POST", "/jobs?samples=20&sampler=sobol", scene);
+        assert_eq!((status, body.as_str()), (201, "{\"id\": 1}"));
+        let status = wait_for(&address, 1, "done");
+        assert!(status.contains("\"samples\": 20, \"total_samples\": 20"));
+        assert_eq!(
+            request(&address, "GET", "/jobs/1/image", ""),
+            (200, "P3 20".to_string())
+        );
+
+        // Long enough to still be running when cancelled.
+        let (status, _) = request(&address, "POST", "/jobs?samples=100000", "");
+        assert_eq!(status, 201);
+        wait_for(&address, 2, "running");
+        assert_eq!(request(&address, "DELETE", "/jobs/2", "").0, 200);
+        wait_for(&address, 2, "cancelled");
+        assert_eq!(request(&address, "GET", "/jobs/2/image", "").0, 409);
+        assert_eq!(request(&address, "DELETE", "/jobs/2", "").0, 409);
+
+        let (_, list) = request(&address, "GET", "/jobs", "");
+        assert!(list.starts_with("[{\"id\": 1") && list.contains("{\"id\": 2"));
+        assert_eq!(request(&address, "GET", "/jobs/3", "").0, 404);
+    }
+
+    #[test]
+    fn test_cancel_real_render() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let dir = std::env::temp_dir().join(format!("server-test-{}", address.replace(':', "-")));
+        thread::spawn(move || {
+            let pool = ThreadPoolBuilder::new().num_threads(2).build().unwrap();
+            serve(listener, &dir, |args, bar, cancel| {
+                let options = Options::from_args(args.iter().cloned())?;
+                crate::render(&options, args, &pool, bar, cancel)
+            })
+        });
+
+        // A single pass, far longer than the test waits.
+        let (status, _) = request(&address, "POST", "/jobs?samples=1000000&crop=0,0,16,16", "");
+        assert_eq!(status, 201);
+        wait_for(&address, 1, "running");
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(request(&address, "DELETE", "/jobs/1", "").0, 200);
+        wait_for(&address, 1, "cancelled");
+        assert_eq!(request(&address, "GET", "/jobs/1/image", "").0, 409);
+    }
+
+    #[test]
+    fn test_panicking_job() {
+        let address = start_server();
+        assert_eq!(request(&address, "POST", "/jobs?seed=13", "").0, 201);
+        assert_eq!(request(&address, "POST", "/jobs?samples=5", "").0, 201);
+        let status = wait_for(&address, 1, "failed");
+        assert!(
+            status.contains("render panicked: unlucky seed"),
+            "{}",
+            status
+        );
+        wait_for(&address, 2, "done");
+    }
+
+    #[test]
+    fn test_rejected_submissions() {
+        let address = start_server();
+        for (target, body) in [
+            ("/jobs?samples=many", ""),
+            ("/jobs?bogus=1", ""),
+            ("/jobs?checkpoint=%2Ftmp%2Fx", ""),
+            ("/jobs?lens=%2Fetc%2Fpasswd", ""),
+            ("/jobs?abort-on-nan=true", ""),
+            ("/jobs?aperture=mask%3A%2Fetc%2Fpasswd", ""),
+            ("/jobs", "sphere 0 0 0"),
+        ] {
+            let (status, body) = request(&address, "POST", target, body);
+            assert_eq!(status, 400, "{}", target);
+            assert!(body.starts_with("{\"error\": "));
+        }
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(decode("a%2Fb+c%zz"), "a/b c%zz");
+        assert_eq!(json_string("say \"hi\"\n"), "\"say \\\"hi\\\"\\n\"");
+    }
+}
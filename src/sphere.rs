@@ -10,6 +10,7 @@ pub struct Sphere {
 }
 
 impl Sphere {
+    #[allow(clippy::self_named_constructors)]
     pub fn sphere(center: Vec3, radius: f32, material: Material) -> Sphere {
         Sphere {
             center,
@@ -17,6 +18,18 @@ impl Sphere {
             material,
         }
     }
+
+    pub fn center(&self) -> Vec3 {
+        self.center
+    }
+
+    pub fn radius(&self) -> f32 {
+        self.radius
+    }
+
+    pub fn material(&self) -> &Material {
+        &self.material
+    }
 }
 
 impl Hittable for Sphere {
@@ -36,6 +49,7 @@ impl Hittable for Sphere {
                     p: r.point_at_parameter(temp),
                     normal: (r.point_at_parameter(temp) - self.center) / self.radius,
                     material: self.material,
+                    object: 0,
                 });
             }
             temp = (-b + discriminant.sqrt()) / a;
@@ -49,6 +63,7 @@ impl Hittable for Sphere {
                     p: r.point_at_parameter(temp),
                     normal: (r.point_at_parameter(temp) - self.center) / self.radius,
                     material: self.material,
+                    object: 0,
                 });
             }
         }
@@ -0,0 +1,109 @@
+use crate::vec3::Vec3;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Eye {
+    Left,
+    Right,
+}
+
+/// How the two eye cameras of a stereo rig are derived from the centre camera.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StereoMode {
+    /// Eyes shifted sideways with parallel optical axes.
+    Parallel,
+    /// Eyes shifted sideways and rotated to converge on the zero-parallax plane.
+    ToedIn,
+    /// Parallel axes with sheared frustums meeting at the zero-parallax plane.
+    OffAxis,
+    /// Omni-directional stereo for equirectangular and cylindrical panoramas.
+    Ods,
+}
+
+impl FromStr for StereoMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "parallel" => Ok(StereoMode::Parallel),
+            "toed-in" => Ok(StereoMode::ToedIn),
+            "off-axis" => Ok(StereoMode::OffAxis),
+            "ods" => Ok(StereoMode::Ods),
+            _ => Err(format!("unknown stereo mode '{}'", s)),
+        }
+    }
+}
+
+/// How the left and right eye images are packed into the output frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StereoLayout {
+    /// Left eye on the left half, right eye on the right half.
+    SideBySide,
+    /// Left eye on the top half, right eye on the bottom half.
+    TopBottom,
+}
+
+impl FromStr for StereoLayout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "side-by-side" | "sbs" => Ok(StereoLayout::SideBySide),
+            "top-bottom" | "tb" => Ok(StereoLayout::TopBottom),
+            _ => Err(format!("unknown stereo layout '{}'", s)),
+        }
+    }
+}
+
+/// Packs two row-major `width` x `height` eye images into one frame and
+/// returns it with its dimensions.
+pub fn compose(
+    layout: StereoLayout,
+    left: &[Vec3],
+    right: &[Vec3],
+    width: usize,
+    height: usize,
+) -> (Vec<Vec3>, usize, usize) {
+    match layout {
+        StereoLayout::SideBySide => {
+            let mut frame = Vec::with_capacity(2 * width * height);
+            for (l, r) in left.chunks(width).zip(right.chunks(width)) {
+                frame.extend_from_slice(l);
+                frame.extend_from_slice(r);
+            }
+            (frame, 2 * width, height)
+        }
+        StereoLayout::TopBottom => {
+            let mut frame = Vec::with_capacity(2 * width * height);
+            frame.extend_from_slice(left);
+            frame.extend_from_slice(right);
+            (frame, width, 2 * height)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compose_side_by_side() {
+        let l = vec![Vec3::new(1.0, 0.0, 0.0); 4];
+        let r = vec![Vec3::new(0.0, 1.0, 0.0); 4];
+        let (frame, w, h) = compose(StereoLayout::SideBySide, &l, &r, 2, 2);
+        assert_eq!((w, h), (4, 2));
+        assert_eq!(frame[1], l[0]);
+        assert_eq!(frame[2], r[0]);
+        assert_eq!(frame[4], l[0]);
+    }
+
+    #[test]
+    fn test_compose_top_bottom() {
+        let l = vec![Vec3::new(1.0, 0.0, 0.0); 4];
+        let r = vec![Vec3::new(0.0, 1.0, 0.0); 4];
+        let (frame, w, h) = compose(StereoLayout::TopBottom, &l, &r, 2, 2);
+        assert_eq!((w, h), (2, 4));
+        assert_eq!(frame[3], l[0]);
+        assert_eq!(frame[4], r[0]);
+    }
+}
@@ -0,0 +1,218 @@
+use std::str::FromStr;
+
+/// A rectangle of pixels `[x0, x1) x [y0, y1)`. `index` numbers the tiles of
+/// the full frame in scanline order and stays the same whatever order they are
+/// rendered in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tile {
+    pub index: usize,
+    pub x0: usize,
+    pub y0: usize,
+    pub x1: usize,
+    pub y1: usize,
+}
+
+impl Tile {
+    /// The pixels of the tile in scanline order.
+    pub fn pixels(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        (self.y0..self.y1).flat_map(move |y| (self.x0..self.x1).map(move |x| (x, y)))
+    }
+
+    pub fn area(&self) -> usize {
+        (self.x1 - self.x0) * (self.y1 - self.y0)
+    }
+}
+
+/// Order in which tiles are handed to the worker threads.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TileOrder {
+    /// Left to right, top to bottom.
+    Scanline,
+    /// Outwards from the centre of the frame, ring by ring.
+    Spiral,
+    /// Along a Hilbert curve, keeping consecutive tiles next to each other.
+    Hilbert,
+}
+
+impl FromStr for TileOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "scanline" => Ok(TileOrder::Scanline),
+            "spiral" => Ok(TileOrder::Spiral),
+            "hilbert" => Ok(TileOrder::Hilbert),
+            _ => Err(format!("unknown tile order '{}'", s)),
+        }
+    }
+}
+
+/// A crop window in pixels, parsed from `X0,Y0,X1,Y1` with exclusive ends.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Crop {
+    pub x0: usize,
+    pub y0: usize,
+    pub x1: usize,
+    pub y1: usize,
+}
+
+impl FromStr for Crop {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values: Vec<usize> = s
+            .split(',')
+            .map(|v| v.trim().parse())
+            .collect::<Result<_, _>>()
+            .map_err(|_| format!("invalid crop window '{}'", s))?;
+        match values[..] {
+            [x0, y0, x1, y1] if x0 < x1 && y0 < y1 => Ok(Crop { x0, y0, x1, y1 }),
+            _ => Err(format!("invalid crop window '{}'", s)),
+        }
+    }
+}
+
+/// Parses a list of tile indices such as `0-7,12,15`.
+pub fn parse_list(s: &str) -> Result<Vec<usize>, String> {
+    let invalid = || format!("invalid tile list '{}'", s);
+    let mut list = Vec::new();
+    for part in s.split(',') {
+        let (first, last) = part.split_once('-').unwrap_or((part, part));
+        let first: usize = first.trim().parse().map_err(|_| invalid())?;
+        let last: usize = last.trim().parse().map_err(|_| invalid())?;
+        if first > last {
+            return Err(invalid());
+        }
+        list.extend(first..=last);
+    }
+    Ok(list)
+}
+
+/// Splits a `width` x `height` frame into tiles of `size` pixels, keeps those
+/// in `list` (all if `None`), clips them to `crop` and sorts them by `order`.
+pub fn layout(
+    width: usize,
+    height: usize,
+    size: usize,
+    order: TileOrder,
+    crop: Option<Crop>,
+    list: Option<&[usize]>,
+) -> Vec<Tile> {
+    let size = size.max(1);
+    let columns = width.div_ceil(size);
+    let rows = height.div_ceil(size);
+    let crop = crop.unwrap_or(Crop {
+        x0: 0,
+        y0: 0,
+        x1: width,
+        y1: height,
+    });
+
+    let mut tiles: Vec<(usize, usize, Tile)> = (0..rows * columns)
+        .filter(|index| list.is_none_or(|list| list.contains(index)))
+        .map(|index| {
+            let (column, row) = (index % columns, index / columns);
+            let tile = Tile {
+                index,
+                x0: (column * size).max(crop.x0),
+                y0: (row * size).max(crop.y0),
+                x1: ((column + 1) * size).min(width).min(crop.x1),
+                y1: ((row + 1) * size).min(height).min(crop.y1),
+            };
+            (column, row, tile)
+        })
+        .filter(|(_, _, tile)| tile.x0 < tile.x1 && tile.y0 < tile.y1)
+        .collect();
+
+    match order {
+        TileOrder::Scanline => {}
+        TileOrder::Spiral => {
+            // Ring around the centre tile, then angle within the ring.
+            let (cx, cy) = ((columns as f32 - 1.0) / 2.0, (rows as f32 - 1.0) / 2.0);
+            let key = |&(column, row, _): &(usize, usize, Tile)| {
+                let (dx, dy) = (column as f32 - cx, row as f32 - cy);
+                (dx.abs().max(dy.abs()), dy.atan2(dx))
+            };
+            tiles.sort_by(|a, b| key(a).partial_cmp(&key(b)).unwrap());
+        }
+        TileOrder::Hilbert => {
+            let side = columns.max(rows).next_power_of_two();
+            tiles.sort_by_key(|&(column, row, _)| hilbert_index(side, column, row));
+        }
+    }
+
+    tiles.into_iter().map(|(_, _, tile)| tile).collect()
+}
+
+/// Distance along the Hilbert curve filling a `side` x `side` grid, where
+/// `side` is a power of two.
+fn hilbert_index(side: usize, mut x: usize, mut y: usize) -> usize {
+    let mut d = 0;
+    let mut s = side / 2;
+    while s > 0 {
+        let rx = usize::from(x & s != 0);
+        let ry = usize::from(y & s != 0);
+        d += s * s * ((3 * rx) ^ ry);
+
+        // Rotate the quadrant so the curve inside it starts at its origin.
+        if ry == 0 {
+            if rx == 1 {
+                x = side - 1 - x;
+                y = side - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        s /= 2;
+    }
+    d
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tiles_cover_frame_once() {
+        for order in [TileOrder::Scanline, TileOrder::Spiral, TileOrder::Hilbert] {
+            let tiles = layout(70, 45, 16, order, None, None);
+            assert_eq!(tiles.len(), 5 * 3);
+            let mut covered = vec![0; 70 * 45];
+            for tile in &tiles {
+                for (x, y) in tile.pixels() {
+                    covered[y * 70 + x] += 1;
+                }
+            }
+            assert!(covered.iter().all(|&c| c == 1), "{:?}", order);
+        }
+    }
+
+    #[test]
+    fn test_orders() {
+        let spiral = layout(48, 48, 16, TileOrder::Spiral, None, None);
+        assert_eq!(spiral[0].index, 4);
+
+        // Consecutive tiles along the Hilbert curve share an edge.
+        let hilbert = layout(64, 64, 16, TileOrder::Hilbert, None, None);
+        for pair in hilbert.windows(2) {
+            let (a, b) = (pair[0], pair[1]);
+            let distance = a.x0.abs_diff(b.x0) + a.y0.abs_diff(b.y0);
+            assert_eq!(distance, 16, "{:?} {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn test_crop_and_list() {
+        let crop: Crop = "10,5,40,20".parse().unwrap();
+        let tiles = layout(64, 64, 16, TileOrder::Scanline, Some(crop), None);
+        assert_eq!(tiles.iter().map(Tile::area).sum::<usize>(), 30 * 15);
+        assert!("10,5,4,20".parse::<Crop>().is_err());
+
+        let list = parse_list("0-2,7").unwrap();
+        assert_eq!(list, [0, 1, 2, 7]);
+        let tiles = layout(64, 64, 16, TileOrder::Hilbert, None, Some(&list));
+        let mut indices: Vec<usize> = tiles.iter().map(|t| t.index).collect();
+        indices.sort_unstable();
+        assert_eq!(indices, list);
+        assert!(parse_list("3-1").is_err());
+    }
+}
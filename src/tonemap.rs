@@ -0,0 +1,297 @@
+use crate::colorspace::ColorSpace;
+use crate::mat3::Mat3;
+use crate::vec3::Vec3;
+use std::str::FromStr;
+
+/// Operator compressing linear scene-referred radiance into the `[0, 1]`
+/// display range.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ToneMap {
+    /// No compression; values above 1 clip.
+    Clamp,
+    /// L / (1 + L) on luminance.
+    Reinhard,
+    /// Reinhard with a luminance `white` that maps exactly to 1.
+    ExtendedReinhard { white: f32 },
+    /// Stephen Hill's fit of the ACES reference and output transforms.
+    Aces,
+    /// Troy Sobotka's AgX base look.
+    AgX,
+    /// John Hable's Uncharted 2 filmic curve.
+    Hable,
+}
+
+impl FromStr for ToneMap {
+    type Err = String;
+
+    /// Parses an operator name; `reinhard-extended:WHITE` takes the white point.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, param) = s.split_once(':').unwrap_or((s, ""));
+        match name {
+            "clamp" | "none" => Ok(ToneMap::Clamp),
+            "reinhard" => Ok(ToneMap::Reinhard),
+            "reinhard-extended" => {
+                let white = if param.is_empty() {
+                    4.0
+                } else {
+                    param
+                        .parse()
+                        .map_err(|_| format!("invalid white point '{}'", param))?
+                };
+                Ok(ToneMap::ExtendedReinhard { white })
+            }
+            "aces" => Ok(ToneMap::Aces),
+            "agx" => Ok(ToneMap::AgX),
+            "hable" | "filmic" => Ok(ToneMap::Hable),
+            _ => Err(format!("unknown tone mapping operator '{}'", name)),
+        }
+    }
+}
+
+impl ToneMap {
+    /// Maps linear radiance to linear display values in `[0, 1]`, in a space
+    /// whose luminance has the given `weights`.
+    pub fn apply(&self, c: Vec3, weights: Vec3) -> Vec3 {
+        let c = Vec3::new(c.r().max(0.0), c.g().max(0.0), c.b().max(0.0));
+
+        let mapped = match *self {
+            ToneMap::Clamp => c,
+            ToneMap::Reinhard => scale_luminance(c, weights, |l| l / (1.0 + l)),
+            ToneMap::ExtendedReinhard { white } => {
+                scale_luminance(c, weights, |l| l * (1.0 + l / (white * white)) / (1.0 + l))
+            }
+            ToneMap::Aces => aces(c),
+            ToneMap::AgX => agx(c),
+            ToneMap::Hable => {
+                let white = hable_partial(11.2);
+                per_channel(c, |x| hable_partial(2.0 * x) / white)
+            }
+        };
+
+        per_channel(mapped, |x| x.clamp(0.0, 1.0))
+    }
+}
+
+fn per_channel(c: Vec3, f: impl Fn(f32) -> f32) -> Vec3 {
+    Vec3::new(f(c.r()), f(c.g()), f(c.b()))
+}
+
+/// Luminance of linear Rec.709 RGB.
+pub fn luminance(c: Vec3) -> f32 {
+    0.2126 * c.r() + 0.7152 * c.g() + 0.0722 * c.b()
+}
+
+/// Luminance of linear RGB in a space whose primaries have the given
+/// `weights`; see `ColorSpace::luminance_weights`.
+pub fn luminance_in(c: Vec3, weights: Vec3) -> f32 {
+    Vec3::dot(&c, &weights)
+}
+
+// Applies a curve to luminance and scales the colour to match, keeping hue.
+fn scale_luminance(c: Vec3, weights: Vec3, curve: impl Fn(f32) -> f32) -> Vec3 {
+    let l = luminance_in(c, weights);
+    if l <= 0.0 {
+        return c;
+    }
+    c * (curve(l) / l)
+}
+
+fn hable_partial(x: f32) -> f32 {
+    const A: f32 = 0.15;
+    const B: f32 = 0.50;
+    const C: f32 = 0.10;
+    const D: f32 = 0.20;
+    const E: f32 = 0.02;
+    const F: f32 = 0.30;
+    ((x * (A * x + C * B) + D * E) / (x * (A * x + B) + D * F)) - E / F
+}
+
+fn aces(c: Vec3) -> Vec3 {
+    const INPUT: Mat3 = Mat3::new([
+        [0.59719, 0.35458, 0.04823],
+        [0.07600, 0.90834, 0.01566],
+        [0.02840, 0.13383, 0.83777],
+    ]);
+    const OUTPUT: Mat3 = Mat3::new([
+        [1.60475, -0.53108, -0.07367],
+        [-0.10208, 1.10813, -0.00605],
+        [-0.00327, -0.07276, 1.07602],
+    ]);
+
+    let v = INPUT.transform(c);
+    let v = per_channel(v, |x| {
+        (x * (x + 0.0245786) - 0.000090537) / (x * (0.983729 * x + 0.432951) + 0.238081)
+    });
+    OUTPUT.transform(v)
+}
+
+fn agx(c: Vec3) -> Vec3 {
+    const INSET: Mat3 = Mat3::new([
+        [0.84247905, 0.0784336, 0.07922374],
+        [0.042328242, 0.87846863, 0.07916613],
+        [0.042375654, 0.0784336, 0.879143],
+    ]);
+    const OUTSET: Mat3 = Mat3::new([
+        [1.196879, -0.09802088, -0.09902974],
+        [-0.052896854, 1.1519032, -0.098961174],
+        [-0.052971635, -0.09804345, 1.1510737],
+    ]);
+    const MIN_EV: f32 = -12.47393;
+    const MAX_EV: f32 = 4.026069;
+
+    let v = per_channel(INSET.transform(c), |x| {
+        let x = (x.max(1e-10).log2().clamp(MIN_EV, MAX_EV) - MIN_EV) / (MAX_EV - MIN_EV);
+        let x2 = x * x;
+        let x4 = x2 * x2;
+        15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x
+            - 0.00232
+    });
+
+    // The base look is display encoded with a 2.2 power; undo it so the result
+    // goes through the same sRGB encoding as every other operator.
+    per_channel(OUTSET.transform(v), |x| x.max(0.0).powf(2.2))
+}
+
+/// Quantises a display-encoded value to 8 bits. `noise` in `[-1, 1]` is added
+/// in steps of one level before rounding: the full triangular range needed
+/// to keep the error from depending on the signal.
+pub fn quantize(x: f32, noise: f32) -> u8 {
+    (x * 255.0 + 0.5 + noise).clamp(0.0, 255.0) as u8
+}
+
+/// Triangular dither noise in `[-1, 1]` for a pixel channel, derived from a hash
+/// so the same image always dithers the same way.
+pub fn dither(pixel: usize, channel: usize) -> f32 {
+    let uniform = |n: u64| {
+        let mut h = n.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        h ^= h >> 31;
+        h = h.wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        h ^= h >> 29;
+        (h >> 40) as f32 / (1u64 << 24) as f32
+    };
+    let n = (pixel as u64) * 3 + channel as u64;
+    uniform(2 * n) + uniform(2 * n + 1) - 1.0
+}
+
+/// Runs the display pipeline on exposed linear pixels in the `working` space:
+/// tone mapping there, conversion to the `output` space, its transfer
+/// function and optionally dithered 8-bit quantisation.
+pub fn to_display(
+    pixels: &[Vec3],
+    operator: ToneMap,
+    working: ColorSpace,
+    output: ColorSpace,
+    dithering: bool,
+) -> Vec<[u8; 3]> {
+    let weights = working.luminance_weights();
+    let to_output = working.conversion_to(output);
+    pixels
+        .iter()
+        .enumerate()
+        .map(|(i, &p)| {
+            let mapped = to_output.transform(operator.apply(p, weights));
+            let channels = [mapped.r(), mapped.g(), mapped.b()];
+            let mut out = [0u8; 3];
+            for (c, value) in channels.into_iter().enumerate() {
+                let noise = if dithering { dither(i, c) } else { 0.0 };
+                out[c] = quantize(output.encode(value.clamp(0.0, 1.0)), noise);
+            }
+            out
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPERATORS: [ToneMap; 6] = [
+        ToneMap::Clamp,
+        ToneMap::Reinhard,
+        ToneMap::ExtendedReinhard { white: 4.0 },
+        ToneMap::Aces,
+        ToneMap::AgX,
+        ToneMap::Hable,
+    ];
+
+    fn rec709() -> Vec3 {
+        ColorSpace::LinearSrgb.luminance_weights()
+    }
+
+    #[test]
+    fn test_operators_stay_in_range() {
+        for operator in OPERATORS {
+            for value in [0.0, 0.01, 0.18, 1.0, 10.0, 1000.0] {
+                let c = operator.apply(Vec3::new(value, value * 0.5, value * 0.1), rec709());
+                for x in [c.r(), c.g(), c.b()] {
+                    assert!((0.0..=1.0).contains(&x), "{:?} {} {}", operator, value, x);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_operators_are_monotonic() {
+        for operator in OPERATORS {
+            let mut previous = -1.0;
+            for i in 0..200 {
+                let v = operator.apply(Vec3::new(1.0, 1.0, 1.0) * (i as f32 * 0.05), rec709());
+                assert!(v.g() >= previous - 1e-6, "{:?} at {}", operator, i);
+                previous = v.g();
+            }
+        }
+    }
+
+    #[test]
+    fn test_extended_reinhard_white_point() {
+        let white =
+            ToneMap::ExtendedReinhard { white: 4.0 }.apply(Vec3::new(4.0, 4.0, 4.0), rec709());
+        assert!((white.g() - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_reinhard_uses_the_working_space_luminance() {
+        // The same triple has a different luminance under other primaries.
+        let green = Vec3::new(0.0, 1.0, 0.0);
+        let rec2020 = ColorSpace::Rec2020.luminance_weights();
+        let expected = |l: f32| 1.0 / (1.0 + l);
+        let srgb = ToneMap::Reinhard.apply(green, rec709());
+        assert!((srgb.g() - expected(rec709().y())).abs() < 1e-5);
+        let wide = ToneMap::Reinhard.apply(green, rec2020);
+        assert!((wide.g() - expected(rec2020.y())).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_display_converts_after_tone_mapping() {
+        let white = [Vec3::new(1.0, 1.0, 1.0)];
+        for output in [
+            ColorSpace::LinearSrgb,
+            ColorSpace::Rec2020,
+            ColorSpace::DisplayP3,
+        ] {
+            let display = to_display(
+                &white,
+                ToneMap::Clamp,
+                ColorSpace::LinearSrgb,
+                output,
+                false,
+            );
+            assert_eq!(display, vec![[255, 255, 255]]);
+        }
+    }
+
+    #[test]
+    fn test_quantize() {
+        assert_eq!(quantize(1.0, 0.0), 255);
+        assert_eq!(quantize(0.0, -1.0), 0);
+        assert_eq!(quantize(0.0, 0.75), 1);
+        assert_eq!(quantize(1.0, -0.75), 254);
+    }
+
+    #[test]
+    fn test_dither_range() {
+        let mean: f32 = (0..3000).map(|i| dither(i / 3, i % 3)).sum::<f32>() / 3000.0;
+        assert!(mean.abs() < 0.05);
+        assert!((0..3000).all(|i| dither(i, 0).abs() <= 1.0));
+    }
+}